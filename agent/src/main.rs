@@ -1,9 +1,14 @@
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::process::Command;
 use std::fs::File;
-use std::io::copy;
-use log::{info, error, warn};
+use std::io::{IsTerminal, Write};
+use futures_util::StreamExt;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use log::{debug, info, error, warn};
+use rand::Rng;
 use config::Config;
 #[cfg(target_os = "windows")]
 use winreg::enums::*;
@@ -15,33 +20,750 @@ use std::os::unix::fs::PermissionsExt;
 
 #[derive(Serialize, Deserialize, Debug)]
 struct AgentConfig {
+    /// May be left out when `backend_urls` is set.
+    #[serde(default)]
     backend_url: String,
+    /// Backends tried in order when the active one is unreachable; the one that answers stays in use
+    /// for heartbeats, acks and downloads from a backend. Defaults to just `backend_url`.
+    #[serde(default)]
+    backend_urls: Vec<String>,
     heartbeat_interval: u64,
     auth_token: String,
+    /// Refuse installs, uninstalls and package tasks (ack "failed") when the agent runs without
+    /// administrator/root rights, instead of letting the installer fail in confusing ways.
+    #[serde(default)]
+    require_elevation: bool,
+    /// Words ignored when matching software names to installed products, on top of the built-in
+    /// ones ("setup", "installer", "x64", ...), e.g. a vendor's branding that isn't in the DisplayName.
+    #[serde(default)]
+    extra_stop_words: Vec<String>,
+    /// Test environments only: ack .exe tasks on Linux as successful without running them.
+    #[serde(default)]
+    simulate_exe_on_linux: bool,
+    /// Also search the Uninstall keys of Windows users who aren't logged on, by briefly loading their
+    /// NTUSER.DAT. A user logging on while their hive is loaded gets a temporary profile.
+    #[serde(default)]
+    load_user_hives: bool,
+    /// Accept a plain http:// `backend_url` (lab setups). The token is then sent in cleartext.
+    #[serde(default)]
+    allow_insecure_backend: bool,
+    /// What to do when an installer reports that a reboot is required: "never", "notify" or "auto".
+    #[serde(default = "default_reboot_policy")]
+    reboot_policy: String,
+    /// Default maximum runtime of an installer in seconds (overridable per task).
+    #[serde(default = "default_task_timeout_secs")]
+    task_timeout_secs: u64,
+    /// Directory for persistent agent state (pending acks, etc.)
+    #[serde(default = "default_state_dir")]
+    state_dir: String,
+    /// Maximum number of bytes of installer stdout/stderr (each) kept for the ack.
+    #[serde(default = "default_output_tail_bytes")]
+    output_tail_bytes: usize,
+    /// Keep the staging directory of failed tasks for inspection instead of deleting it.
+    #[serde(default)]
+    keep_temp_on_failure: bool,
+    /// Working directory for installers. Defaults to the task's staging directory.
+    #[serde(default)]
+    work_dir: Option<String>,
+    /// Whether "reboot" tasks are honored on this machine.
+    #[serde(default = "default_true")]
+    allow_reboot: bool,
+    /// Negotiate gzip/brotli/deflate compressed responses with the backend.
+    #[serde(default = "default_true")]
+    enable_compression: bool,
+    /// Gzip request bodies: "off" (default), "auto" (once a heartbeat response lists "gzip" in
+    /// `request_encodings`) or "gzip" (always, for backends known to accept it).
+    #[serde(default = "default_request_compression")]
+    request_compression: String,
+    /// Name of the service/unit the agent itself runs as, protected from "service" tasks.
+    /// Detected automatically for systemd units.
+    #[serde(default)]
+    agent_service_name: Option<String>,
+    /// Whether "script" tasks may run at all. Off by default since they execute arbitrary code.
+    #[serde(default)]
+    allow_scripts: bool,
+    /// Ack every task except agent updates as "agent_outdated" while the backend's
+    /// `min_protocol_version` is above `PROTOCOL_VERSION`.
+    #[serde(default)]
+    refuse_tasks_when_outdated: bool,
+    /// If non-empty, only executables whose file name matches one of these entries
+    /// (case-insensitive, ".exe" optional) may be run, e.g. ["msiexec", "winget", "setup.exe"].
+    #[serde(default)]
+    allowed_executables: Vec<String>,
+    /// Abort downloads larger than this many bytes.
+    #[serde(default)]
+    max_download_bytes: Option<u64>,
+    /// If non-empty, downloads are only allowed from these hosts (case-insensitive, "*.example.com" matches subdomains).
+    #[serde(default)]
+    allowed_download_hosts: Vec<String>,
+    /// Also allow plain http:// downloads. Only https:// is accepted otherwise.
+    #[serde(default)]
+    allow_insecure_downloads: bool,
+    /// Volume installers write to (e.g. "C:\" or "/opt"), checked for `required_disk_mb` in addition to the temp volume.
+    #[serde(default)]
+    install_volume: Option<String>,
+    /// Have msiexec write a verbose log (/l*v) to the staging directory, quoted in failure acks.
+    #[serde(default = "default_true")]
+    msi_verbose_log: bool,
+    /// HTTP headers sent with every download (e.g. an artifact store token). Task headers take precedence.
+    #[serde(default)]
+    default_download_headers: HashMap<String, String>,
+    /// Cap on this agent's download speed in kilobits per second. Unlimited if unset.
+    #[serde(default)]
+    max_download_kbps: Option<u64>,
+    /// How long completed task ids are remembered to avoid running a re-sent task twice.
+    #[serde(default = "default_completed_task_ttl_secs")]
+    completed_task_ttl_secs: u64,
+    /// How long a result is reported in `recent_results` while the backend doesn't acknowledge it.
+    /// Capped by `completed_task_ttl_secs`.
+    #[serde(default = "default_recent_results_max_age_secs")]
+    recent_results_max_age_secs: u64,
+    /// Random offset (+/- seconds) applied to every heartbeat sleep.
+    #[serde(default)]
+    heartbeat_jitter_secs: u64,
+    /// Also wait for tasks on a long-poll request to `{backend_url}/tasks/wait`, so they start within
+    /// seconds instead of at the next heartbeat. Heartbeats continue, and deliver tasks on their own
+    /// if the backend doesn't offer the endpoint.
+    #[serde(default)]
+    long_poll: bool,
+    /// How long the backend may hold a long-poll request open before answering without tasks.
+    #[serde(default = "default_long_poll_timeout_secs")]
+    long_poll_timeout_secs: u64,
+    /// Wait a random time (up to one heartbeat interval) before the first heartbeat.
+    #[serde(default)]
+    random_startup_delay: bool,
+    /// Sign heartbeat, ack, progress and long-poll requests with HMAC-SHA256 (X-Timestamp /
+    /// X-Signature headers, see `signed_json`). Requires `signing_secret`.
+    #[serde(default)]
+    sign_requests: bool,
+    /// Client certificate for mutual TLS with the backend: PEM (with the key in `client_key_path` or
+    /// in the same file) or PKCS#12 (.p12/.pfx, with `client_cert_password`). Reloaded when it changes.
+    #[serde(default)]
+    client_cert_path: Option<String>,
+    #[serde(default)]
+    client_key_path: Option<String>,
+    #[serde(default)]
+    client_cert_password: Option<String>,
+    /// Key for `sign_requests` until the backend issues the machine its own signing key (kept in
+    /// `state_dir`, see `signed_json`).
+    #[serde(default)]
+    signing_secret: Option<String>,
+    /// How many tasks may run at the same time. msiexec tasks always run one after another.
+    #[serde(default = "default_max_concurrent_tasks")]
+    max_concurrent_tasks: usize,
+    /// Consecutive failed heartbeats before the interval starts doubling (0 disables backoff).
+    #[serde(default = "default_backoff_after_failures")]
+    backoff_after_failures: u32,
+    /// Upper bound for the backed-off heartbeat interval in seconds.
+    #[serde(default = "default_max_backoff_secs")]
+    max_backoff_secs: u64,
+    /// Treat every task as `dry_run` (also enabled by the `--dry-run` command line flag).
+    #[serde(default)]
+    dry_run: bool,
+    /// Serve Prometheus metrics on http://<metrics_bind>:<port>/metrics. Disabled if unset.
+    #[serde(default)]
+    metrics_port: Option<u16>,
+    /// Address the metrics endpoint listens on. Local only by default; "0.0.0.0" for a remote scraper.
+    #[serde(default = "default_metrics_bind")]
+    metrics_bind: String,
+    /// Window from older configs for tasks with `respect_maintenance_window`, which are acked
+    /// "waiting_for_window" outside it. Ignored when `maintenance_windows` is set.
+    #[serde(default)]
+    maintenance_window: Option<MaintenanceWindow>,
+    /// Where the reported identity and system details come from: the OS, or a substitute set by
+    /// tests and embedders. Not read from the config file.
+    #[serde(skip, default = "default_system_info_provider")]
+    system_info: Arc<dyn SystemInfoProvider>,
+    /// Default of `require_no_pending_reboot` for installs and rollbacks that don't set it. Tasks
+    /// held because of it are acked "deferred_reboot_pending".
+    #[serde(default)]
+    defer_on_pending_reboot: bool,
+    /// When tasks may run. Outside all of them tasks are acked "deferred" and kept locally until
+    /// the next one opens. Always, if empty.
+    #[serde(default)]
+    maintenance_windows: Vec<MaintenanceWindow>,
+    /// Only tasks with `respect_maintenance_window` wait for `maintenance_windows`, the others run
+    /// right away.
+    #[serde(default)]
+    maintenance_windows_flagged_only: bool,
+    /// Where installers are downloaded and run from. Must allow executing files (no noexec mount).
+    /// Defaults to the system temp directory (TMPDIR / TEMP).
+    #[serde(default)]
+    temp_dir: Option<String>,
+    /// Directory (or share) with pre-staged installers for `local_path` / file:// tasks.
+    /// Local tasks are refused if unset, and may not reference files outside of it.
+    #[serde(default)]
+    bundle_dir: Option<String>,
+    /// Whether "package_manager" tasks may use Flatpak (system-wide, from Flathub) and Snap.
+    #[serde(default = "default_true")]
+    allow_flatpak_snap: bool,
+    /// Keep verified downloads here, keyed by SHA-256, and reuse them instead of downloading
+    /// again. Only tasks with a `sha256` use the cache. Disabled if unset.
+    #[serde(default)]
+    cache_dir: Option<String>,
+    /// Size limit of `cache_dir`; the least recently used files are removed beyond it.
+    #[serde(default = "default_cache_max_mb")]
+    cache_max_mb: u64,
+    /// Send the installed software with the heartbeat. Can be turned off where that's too revealing.
+    #[serde(default = "default_true")]
+    inventory_enabled: bool,
+    /// How often the inventory is sent (it goes out with the first heartbeat, then at most this often).
+    #[serde(default = "default_inventory_interval_secs")]
+    inventory_interval_secs: u64,
+    /// Check the inventory every heartbeat but send the full list only when it changed (and every
+    /// `inventory_interval_secs`); other heartbeats carry just its `inventory_hash`.
+    #[serde(default)]
+    inventory_delta: bool,
+    /// Include the CPU model and every volume's size and free space in the hardware report. Core
+    /// count and memory are always sent.
+    #[serde(default = "default_true")]
+    hardware_details: bool,
+    /// User-Agent sent with every request. Defaults to "ZE-SilentSync/<version> (<os>; <arch>)".
+    #[serde(default)]
+    user_agent: Option<String>,
+    /// Also write the log to this file (in addition to the console), rotated by size.
+    #[serde(default)]
+    log_file: Option<String>,
+    /// Size at which `log_file` is rotated (0 disables rotation).
+    #[serde(default = "default_log_max_size_mb")]
+    log_max_size_mb: u64,
+    /// Rotated log files kept next to `log_file` (agent.log.1 is the newest).
+    #[serde(default = "default_log_keep_files")]
+    log_keep_files: usize,
 }
 
+/// Local-time window for disruptive tasks, e.g. `days = ["mon", "tue", "wed", "thu", "fri"]`,
+/// `times = ["22:00-05:00"]`. A range past midnight belongs to the day it starts on.
+#[derive(Serialize, Deserialize, Debug)]
+struct MaintenanceWindow {
+    /// Days the window opens on. Every day, if empty.
+    #[serde(default)]
+    days: Vec<chrono::Weekday>,
+    /// "HH:MM-HH:MM" ranges in the machine's local time.
+    times: Vec<String>,
+}
+
+impl MaintenanceWindow {
+    fn contains(&self, now: chrono::DateTime<chrono::Local>) -> bool {
+        use chrono::Datelike;
+
+        let on_day = |day: chrono::Weekday| self.days.is_empty() || self.days.contains(&day);
+        let today = now.weekday();
+        let time = now.time();
+        self.times.iter().filter_map(|range| parse_time_range(range)).any(|(start, end)| {
+            if start <= end {
+                on_day(today) && start <= time && time < end
+            } else {
+                // Overnight: the evening part today, or the morning part of yesterday's window
+                (on_day(today) && time >= start) || (on_day(today.pred()) && time < end)
+            }
+        })
+    }
+
+    /// When the window next opens after `now`, within the coming week.
+    fn next_opening(&self, now: chrono::DateTime<chrono::Local>) -> Option<chrono::DateTime<chrono::Local>> {
+        use chrono::{Datelike, TimeZone};
+
+        let starts: Vec<chrono::NaiveTime> = self.times.iter().filter_map(|range| parse_time_range(range)).map(|(start, _)| start).collect();
+        (0..=7).filter_map(|offset| now.date_naive().checked_add_days(chrono::Days::new(offset)))
+            .filter(|date| self.days.is_empty() || self.days.contains(&date.weekday()))
+            .flat_map(|date| starts.iter().filter_map(move |start| chrono::Local.from_local_datetime(&date.and_time(*start)).earliest()))
+            .filter(|opening| *opening > now)
+            .min()
+    }
+}
+
+/// Parse "22:00-05:00" into its start and end time.
+fn parse_time_range(range: &str) -> Option<(chrono::NaiveTime, chrono::NaiveTime)> {
+    let (start, end) = range.split_once('-')?;
+    let parse = |time: &str| chrono::NaiveTime::parse_from_str(time.trim(), "%H:%M").ok();
+    Some((parse(start)?, parse(end)?))
+}
+
+fn default_metrics_bind() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_max_concurrent_tasks() -> usize {
+    1
+}
+
+fn default_backoff_after_failures() -> u32 {
+    3
+}
+
+fn default_max_backoff_secs() -> u64 {
+    900
+}
+
+fn default_long_poll_timeout_secs() -> u64 {
+    60
+}
+
+fn default_reboot_policy() -> String {
+    "notify".to_string()
+}
+
+fn default_task_timeout_secs() -> u64 {
+    3600
+}
+
+fn default_request_compression() -> String {
+    "off".to_string()
+}
+
+fn default_completed_task_ttl_secs() -> u64 {
+    24 * 3600
+}
+
+fn default_recent_results_max_age_secs() -> u64 {
+    6 * 3600
+}
+
+fn default_state_dir() -> String {
+    "state".to_string()
+}
+
+fn default_output_tail_bytes() -> usize {
+    4096
+}
+
+fn default_cache_max_mb() -> u64 {
+    2048
+}
+
+fn default_inventory_interval_secs() -> u64 {
+    3600
+}
+
+fn default_log_max_size_mb() -> u64 {
+    10
+}
+
+fn default_log_keep_files() -> usize {
+    5
+}
+
+/// msiexec exit codes meaning "success, but a reboot is required".
+/// 3010 = ERROR_SUCCESS_REBOOT_REQUIRED, 1641 = ERROR_SUCCESS_REBOOT_INITIATED
+/// Only meaningful for Windows Installer; arbitrary EXEs may use these codes for anything.
+const REBOOT_REQUIRED_EXIT_CODES: [i32; 2] = [3010, 1641];
+/// ERROR_PATCH_TARGET_NOT_FOUND: none of the products the patch applies to is installed.
+const MSI_PATCH_NOT_APPLICABLE_EXIT_CODE: i32 = 1642;
+/// Upper bound for the msiexec log excerpt attached to failure acks.
+const MSI_LOG_EXCERPT_BYTES: usize = 4096;
+/// How much of an unparseable heartbeat response is logged.
+const HEARTBEAT_BODY_EXCERPT_BYTES: usize = 512;
+
 #[derive(Serialize, Deserialize, Debug)]
 struct SystemInfo {
     hostname: String,
+    /// Stable identity MAC, see `primary_mac_address`
     mac_address: String,
+    /// Every interface MAC (physical and virtual), sorted
+    mac_addresses: Vec<String>,
+    /// Network adapters other than loopback, sorted by name
+    #[serde(default)]
+    interfaces: Vec<NetworkInterface>,
     os_info: String,
+    /// The OS is waiting for a reboot (see `pending_reboot`)
+    pending_reboot: bool,
+    /// Source addresses of the default routes (see `primary_ip_address`)
+    #[serde(default)]
+    ipv4_address: Option<String>,
+    #[serde(default)]
+    ipv6_address: Option<String>,
+    /// Active Directory domain (Windows) or DNS domain (elsewhere)
+    #[serde(default)]
+    domain: Option<String>,
+    /// Workgroup of a Windows machine that isn't domain-joined
+    #[serde(default)]
+    workgroup: Option<String>,
+    /// Most recent task results the backend hasn't acknowledged yet, in case their acks were lost
+    #[serde(default, alias = "last_results")]
+    recent_results: Vec<TaskResultSummary>,
+    /// Installed software, only sent every `inventory_interval_secs`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    software_inventory: Option<Vec<InstalledSoftware>>,
+    /// SHA-256 of the inventory, sent alone when it hasn't changed since the last full list (`inventory_delta`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    inventory_hash: Option<String>,
+    /// Build of the agent sending the heartbeat
+    #[serde(default)]
+    agent_info: AgentInfo,
+    /// CPU, memory and disks, refreshed every `HARDWARE_REFRESH_INTERVAL`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hardware: Option<HardwareInfo>,
+    /// `PROTOCOL_VERSION` of the agent
+    #[serde(default)]
+    protocol_version: u32,
+    /// What this agent can do, see `agent_capabilities`
+    #[serde(default)]
+    capabilities: Vec<String>,
+}
+
+/// Version of the heartbeat/task protocol. Raised when the agent gains something the backend must
+/// not rely on older agents having.
+const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct HardwareInfo {
+    #[serde(default)]
+    cpu_model: Option<String>,
+    #[serde(default)]
+    cpu_physical_cores: Option<usize>,
+    cpu_logical_cores: usize,
+    total_memory_bytes: u64,
+    /// Fixed and removable volumes, without pseudo file systems
+    #[serde(default)]
+    disks: Vec<DiskInfo>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct DiskInfo {
+    mount_point: String,
+    file_system: String,
+    total_bytes: u64,
+    available_bytes: u64,
+    removable: bool,
+}
+
+/// How often the hardware report is collected again; it goes out with every heartbeat.
+const HARDWARE_REFRESH_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Version and build metadata of this agent binary (see build.rs).
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct AgentInfo {
+    agent_version: String,
+    /// Short commit hash, "unknown" outside a git checkout
+    git_commit: String,
+    /// RFC 3339
+    build_timestamp: String,
+    /// Target triple, e.g. "x86_64-pc-windows-msvc"
+    target: String,
+}
+
+impl AgentInfo {
+    fn current() -> Self {
+        let build_timestamp = env!("AGENT_BUILD_TIMESTAMP").parse::<i64>().ok()
+            .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+            .map(|built| built.to_rfc3339())
+            .unwrap_or_default();
+        AgentInfo {
+            agent_version: env!("CARGO_PKG_VERSION").to_string(),
+            git_commit: env!("AGENT_GIT_COMMIT").to_string(),
+            build_timestamp,
+            target: env!("AGENT_TARGET").to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for AgentInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (commit {}, built {}, {})", self.agent_version, self.git_commit, self.build_timestamp, self.target)
+    }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct NetworkInterface {
+    name: String,
+    mac_address: String,
+    #[serde(default)]
+    ipv4_addresses: Vec<String>,
+    #[serde(default)]
+    ipv6_addresses: Vec<String>,
+    /// Operationally up (Linux) or has an address (elsewhere)
+    is_up: bool,
+    /// Looks like a VM, container, bridge or VPN adapter (see `looks_virtual`)
+    is_virtual: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct InstalledSoftware {
+    name: String,
+    #[serde(default)]
+    version: Option<String>,
+    #[serde(default)]
+    publisher: Option<String>,
+    /// EstimatedSize from the Uninstall key, in KB (Windows only)
+    #[serde(default)]
+    size_kb: Option<u32>,
+}
+
+/// Upper bound for the inventory entries in one heartbeat.
+const INVENTORY_LIMIT: usize = 2000;
+
 #[derive(Serialize, Deserialize, Debug)]
+struct TaskResultSummary {
+    task_id: i32,
+    status: String,
+    /// Unix timestamp (seconds)
+    finished_at: u64,
+}
+
+/// How many unconfirmed results a heartbeat carries at most.
+const RECENT_RESULTS_LIMIT: usize = 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct Task {
     id: i32,
     #[serde(rename = "type")]
     task_type: String,
     software_name: String,
+    /// May be empty or absent for "uninstall" tasks, which then go by the installed product.
+    #[serde(default)]
     download_url: String,
     silent_args: String,
+    /// Ids of tasks (in the same heartbeat or completed earlier) that must have succeeded first.
+    #[serde(default)]
+    depends_on: Vec<i32>,
+    /// Download and verify, but only report the command that would run (ack status "dry_run").
+    #[serde(default)]
+    dry_run: bool,
+    /// Earliest time to run the task (RFC 3339). Future tasks are acked "scheduled" and kept
+    /// locally until due; past or missing times run immediately.
+    #[serde(default)]
+    run_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Wait for the agent's `maintenance_windows` even with `maintenance_windows_flagged_only`, or
+    /// for its older `maintenance_window` (acked "waiting_for_window" until then).
+    #[serde(default)]
+    respect_maintenance_window: bool,
+    /// Don't run while the machine has a reboot pending (e.g. from Windows Update); until then
+    /// the task is acked "pending_reboot" and kept locally. Defaults to the agent's
+    /// `defer_on_pending_reboot` for installs and rollbacks, off for everything else.
+    #[serde(default)]
+    require_no_pending_reboot: Option<bool>,
+    /// Install/remove MSIX/Appx packages for the current user (Add-AppxPackage) instead of
+    /// provisioning them for all users. Usually combined with `run_as_logged_on_user`.
+    #[serde(default)]
+    per_user: bool,
+    /// Pre-staged file to use instead of downloading `download_url`, relative to `bundle_dir`.
+    /// A file:// `download_url` works the same way.
+    #[serde(default)]
+    local_path: Option<String>,
+    /// Execution order within one heartbeat (lowest first, ties by id). Tasks without a priority
+    /// run after all prioritized ones, so backends that don't send it keep working.
+    #[serde(default, alias = "order")]
+    priority: Option<i32>,
+    #[serde(default)]
+    timeout_secs: Option<u64>,
+    /// Delay before a "reboot" task restarts the machine (falls back to `silent_args`).
+    #[serde(default)]
+    reboot_delay_secs: Option<u64>,
+    /// Target path for "file_deploy" tasks (must be absolute).
+    #[serde(default)]
+    destination_path: Option<String>,
+    /// What "file_deploy" does if the destination exists: "fail" (default), "replace" or "backup".
+    #[serde(default)]
+    overwrite: Option<String>,
+    /// Create missing parent directories of `destination_path`.
+    #[serde(default)]
+    create_dirs: bool,
+    /// Octal file mode applied after deploying, e.g. "0644" (Unix only).
+    #[serde(default)]
+    file_mode: Option<String>,
+    /// Owner applied after deploying as "user" or "user:group" (Unix only).
+    #[serde(default)]
+    file_owner: Option<String>,
+    /// Inline operations for "registry" tasks. If absent, `download_url` must point at a JSON file with the same content.
+    #[serde(default)]
+    registry_operations: Option<Vec<RegistryOperation>>,
+    /// Expected hex SHA-256 of the download. Verified whenever present; required for "agent_update".
+    #[serde(default)]
+    sha256: Option<String>,
+    /// Version of the payload (used by "agent_update" to refuse downgrades, and pinned by
+    /// "package_manager" tasks).
+    #[serde(default)]
+    version: Option<String>,
+    /// Service (Windows) or systemd unit (Linux) targeted by a "service" task.
+    #[serde(default)]
+    service_name: Option<String>,
+    /// "start", "stop", "restart", "enable" or "disable"
+    #[serde(default)]
+    service_action: Option<String>,
+    /// Package manager of a "package_manager" task: "winget", "choco", "apt", "dnf", "zypper",
+    /// "flatpak" or "snap". Defaults to the one detected on Linux.
+    #[serde(default)]
+    manager: Option<String>,
+    /// Package to act on, e.g. "7zip.7zip" (winget), "7zip" (choco) or "org.mozilla.firefox" (flatpak).
+    #[serde(default)]
+    package_id: Option<String>,
+    /// "install" (default), "upgrade" or "uninstall"
+    #[serde(default, alias = "action")]
+    package_action: Option<String>,
+    /// Uninstall exactly the product with this DisplayName (the package name on Linux), compared
+    /// case-insensitively with whitespace normalized. No keyword matching; acks "not_installed" if absent.
+    #[serde(default)]
+    display_name: Option<String>,
+    /// With `display_name`: also require this DisplayVersion (package version on Linux).
+    #[serde(default)]
+    display_version: Option<String>,
+    /// With `display_name`: fall back to keyword matching on `software_name` if nothing matches exactly.
+    #[serde(default)]
+    fallback_fuzzy: bool,
+    /// Narrows registry matches of an uninstall task to entries whose DisplayName or DisplayVersion
+    /// contain this (case-insensitive), e.g. "(x64)" after an `ambiguous_match` ack.
+    #[serde(default)]
+    match_hint: Option<String>,
+    /// Launch the installer in the interactive user's session instead of the agent's (Windows only).
+    #[serde(default)]
+    run_as_logged_on_user: bool,
+    /// With `run_as_logged_on_user`: silently retry on a later heartbeat when nobody is logged on,
+    /// instead of acking `no_user_session`.
+    #[serde(default)]
+    defer_if_no_user: bool,
+    /// Current directory for the installer. Relative paths are resolved against the staging directory.
+    #[serde(default)]
+    working_dir: Option<String>,
+    /// Never send installer output to the backend (e.g. when it echoes license keys).
+    #[serde(default)]
+    redact_output: bool,
+    /// Extra environment variables for the installer, on top of the agent's own environment.
+    /// Values may use %STAGING_DIR%, %INSTALLER_PATH% and %TASK_ID%.
+    #[serde(default)]
+    env: HashMap<String, String>,
+    /// Command run after the download, before the installer (e.g. to stop an app).
+    #[serde(default)]
+    pre_command: Option<String>,
+    /// Command run after a successful install (e.g. shortcut cleanup).
+    #[serde(default)]
+    post_command: Option<String>,
+    /// Program run after a successful install to check it worked (e.g. `app.exe --version`); a non-zero
+    /// exit acks `verification_failed`.
+    #[serde(default)]
+    verify_command: Option<String>,
+    /// Arguments for `verify_command` or the script from `verify_url`.
+    #[serde(default)]
+    verify_args: Option<String>,
+    /// Script downloaded into the staging directory and run as the verification, instead of `verify_command`.
+    /// Only runs on agents with `allow_scripts`, and only with a matching `verify_sha256`.
+    #[serde(default)]
+    verify_url: Option<String>,
+    /// Expected SHA-256 (hex) of the `verify_url` script. Required with `verify_url`.
+    #[serde(default)]
+    verify_sha256: Option<String>,
+    /// Continue with the install even if `pre_command` fails.
+    #[serde(default)]
+    ignore_pre_failure: bool,
+    /// Skip the install (acking `already_installed`) when this matches before downloading.
+    #[serde(default)]
+    detect: Option<DetectSpec>,
+    /// Extra HTTP headers for downloading this task's files, merged over `default_download_headers`.
+    #[serde(default)]
+    download_headers: Option<HashMap<String, String>>,
+    /// Executable names (e.g. "chrome.exe") terminated before the installer runs. Exact, case-insensitive match.
+    #[serde(default)]
+    kill_processes: Vec<String>,
+    /// How long `kill_processes` get to exit after being asked to close before they are killed (default 10s).
+    #[serde(default)]
+    kill_timeout_secs: Option<u64>,
+    /// How often a failed installer run is repeated (reusing the download) before the task fails.
+    #[serde(default)]
+    max_retries: u32,
+    /// Total number of installer runs, including the first. Takes precedence over `max_retries`.
+    #[serde(default)]
+    max_attempts: Option<u32>,
+    /// Pause between retries.
+    #[serde(default)]
+    retry_delay_secs: u64,
+    /// Double the pause after every failed attempt.
+    #[serde(default)]
+    retry_backoff: bool,
+    /// Non-zero exit codes that also count as success (e.g. 2 for "already installed").
+    /// Exit code 0 and the msiexec reboot codes keep their meaning either way; listing 3010 or
+    /// 1641 here gives a non-msiexec installer the same "success_reboot_required" result.
+    #[serde(default)]
+    success_exit_codes: Vec<i32>,
+    /// Exit codes that mean retrying is pointless.
+    #[serde(default)]
+    fatal_exit_codes: Vec<i32>,
+    /// Free space (in MB) the task needs. Checked before downloading.
+    #[serde(default)]
+    required_disk_mb: Option<u64>,
+    /// MSI transforms (.mst) downloaded next to the package and applied via TRANSFORMS=, in order.
+    #[serde(default)]
+    transforms: Vec<TransformFile>,
+    /// Command that undoes this task, remembered locally for a later "rollback" task. On a
+    /// "rollback" task itself, overrides what was remembered.
+    #[serde(default)]
+    rollback_url: Option<String>,
+    /// Arguments for `rollback_url`.
+    #[serde(default)]
+    rollback_args: Option<String>,
+    /// The earlier task a "rollback" task undoes.
+    #[serde(default)]
+    rollback_of: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct TransformFile {
+    url: String,
+    /// Expected hex SHA-256 of the transform.
+    #[serde(default)]
+    sha256: Option<String>,
+}
+
+/// How to tell that a task's software is already present. Every check that is set must match.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct DetectSpec {
+    /// DisplayName of an Uninstall registry entry, compared case-insensitively (Windows).
+    #[serde(default)]
+    display_name: Option<String>,
+    /// File that must exist. With `min_version` its file version is checked too (Windows only).
+    #[serde(default)]
+    file_path: Option<String>,
+    /// Command that must be found on the PATH (`command -v`, or `where` on Windows).
+    #[serde(default)]
+    command: Option<String>,
+    /// Package that must be installed according to dpkg or rpm (Linux).
+    #[serde(default)]
+    package: Option<String>,
+    /// Minimum DisplayVersion, file version or package version.
+    #[serde(default)]
+    min_version: Option<String>,
+}
+
+/// A single change applied by a "registry" task.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct RegistryOperation {
+    /// "create_key", "set_value", "delete_value" or "delete_key"
+    action: String,
+    /// Full key path including the hive, e.g. "HKLM\\SOFTWARE\\Vendor\\App"
+    key: String,
+    /// Value name (set_value / delete_value). Empty string is the default value.
+    #[serde(default)]
+    name: Option<String>,
+    /// REG_SZ, REG_EXPAND_SZ, REG_MULTI_SZ, REG_DWORD, REG_QWORD or REG_BINARY
+    #[serde(default)]
+    value_type: Option<String>,
+    /// String, number, list of strings (REG_MULTI_SZ) or hex string (REG_BINARY)
+    #[serde(default)]
+    data: Option<serde_json::Value>,
 }
 
 #[derive(Deserialize, Debug)]
 struct HeartbeatResponse {
-    status: String,
     tasks: Vec<Task>,
     machine_token: Option<String>,
+    /// Tasks to drop from the queue or, if already running, kill and ack as "cancelled"
+    #[serde(default)]
+    cancelled_task_ids: Vec<i32>,
+    /// Tasks from `recent_results` the backend has recorded; they aren't reported again
+    #[serde(default, alias = "confirmed_results")]
+    acknowledged_ids: Vec<i32>,
+    /// Oldest `PROTOCOL_VERSION` the backend still supports
+    #[serde(default)]
+    min_protocol_version: Option<u32>,
+    /// Content-Encodings the backend accepts on request bodies (see `request_compression`)
+    #[serde(default)]
+    request_encodings: Vec<String>,
+    /// Per-machine key for `sign_requests`, issued once at enrollment
+    #[serde(default)]
+    signing_key: Option<String>,
 }
 
 fn derive_pseudo_mac(hostname: &str) -> String {
@@ -67,61 +789,194 @@ fn derive_pseudo_mac(hostname: &str) -> String {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Initialize Logger
-    env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--version" || arg == "-V") {
+        println!("{} {}", env!("CARGO_PKG_NAME"), AgentInfo::current());
+        return Ok(());
+    }
+    if args.iter().any(|arg| arg == "--check-config") {
+        std::process::exit(check_config());
+    }
 
-    info!("Starting ZLDAP Agent...");
+    // The config decides where the log goes, so it is read before the logger exists
+    let config = load_config();
+    init_logging(config.as_ref().ok());
 
-    // Load Configuration
-    let settings = Config::builder()
-        .add_source(config::File::with_name("config"))
-        .add_source(config::Environment::with_prefix("AGENT"))
-        .build()?;
+    info!("Starting ZLDAP Agent v{}...", AgentInfo::current());
+    cleanup_old_agent_binary();
 
-    let config: AgentConfig = settings.try_deserialize()?;
-    info!("Configuration loaded. Backend: {}", config.backend_url);
+    let config = Arc::new(config?);
+    info!("Configuration loaded. Backend: {}", config.backend_urls.join(", "));
+    for problem in config_problems(&config) {
+        warn!("Configuration: {}", problem);
+    }
+    if let Err(e) = check_backend_transport(&config).and_then(|_| check_signing(&config)) {
+        error!("{}", e);
+        return Err(e.into());
+    }
+
+    // Two agents would run every task twice
+    if let Err(e) = acquire_instance_lock(&config) {
+        error!("{}", e);
+        return Err(e);
+    }
+    if is_elevated() {
+        info!("Running elevated.");
+    } else if config.require_elevation {
+        warn!("Not running elevated. Install, uninstall and package tasks will be refused (require_elevation).");
+    } else {
+        warn!("Not running elevated. Installers that need administrator/root rights will fail.");
+    }
+    LOAD_USER_HIVES.store(config.load_user_hives, std::sync::atomic::Ordering::Relaxed);
+    load_signing_key(&config);
+    check_temp_dir(&config);
+    if cfg!(target_os = "linux") {
+        match detected_package_manager() {
+            Some(manager) => info!("Package manager: {}", manager),
+            None => info!("No supported package manager found. Package tasks will be refused."),
+        }
+    }
+    if config.dry_run {
+        warn!("Dry-run mode: tasks are downloaded and verified but never executed.");
+    }
 
-    let client = reqwest::Client::new();
+    // A bad client certificate fails here rather than as a TLS error on the first heartbeat
+    let mut client_cert = ClientCertWatch::new(&config);
+    let mut client = match build_client(&config) {
+        Ok(client) => client,
+        Err(e) => {
+            error!("{}", e);
+            return Err(e.into());
+        }
+    };
     let mut machine_token: Option<String> = None;
+    let semaphore = Arc::new(tokio::sync::Semaphore::new(config.max_concurrent_tasks.max(1)));
+    // Lets the scheduler ack with the current machine token, and use the client with the current certificate
+    let (machine_token_tx, machine_token_rx) = tokio::sync::watch::channel(None);
+    let (client_tx, client_rx) = tokio::sync::watch::channel(client.clone());
+    if config.long_poll {
+        tokio::spawn(run_long_poll(config.clone(), client_rx.clone(), machine_token_rx.clone(), semaphore.clone()));
+    }
+    tokio::spawn(run_scheduled_tasks(config.clone(), client_rx, machine_token_rx, semaphore.clone()));
 
-    loop {
-        let sys_info = get_system_info();
-        info!("Sending heartbeat for {}", sys_info.hostname);
+    if let Some(port) = config.metrics_port {
+        tokio::spawn(serve_metrics(config.metrics_bind.clone(), port));
+    }
+
+    // Spread out agents that all start at once (e.g. after a power restore)
+    if config.random_startup_delay && config.heartbeat_interval > 0 {
+        let delay = rand::thread_rng().gen_range(0..config.heartbeat_interval * 1000);
+        info!("Delaying first heartbeat by {}ms", delay);
+        tokio::time::sleep(Duration::from_millis(delay)).await;
+    }
 
-        let mut req = client.post(format!("{}/heartbeat", config.backend_url))
-            .header("X-Agent-Token", &config.auth_token);
+    let mut consecutive_failures: u32 = 0;
+    let mut inventory_sent: Option<Instant> = None;
+    // Hash of the last full inventory the backend accepted
+    let mut inventory_sent_hash: Option<String> = None;
+    let mut hardware: Option<(Instant, HardwareInfo)> = None;
+    let capabilities = agent_capabilities(&config);
+    info!("Protocol version {}. Capabilities: {}", PROTOCOL_VERSION, capabilities.join(", "));
+    let mut outdated_warned = false;
 
-        if let Some(token) = &machine_token {
-            req = req.header("X-Machine-Token", token);
+    loop {
+        if let Some(modified) = client_cert.changed() {
+            match build_client(&config) {
+                Ok(reloaded) => {
+                    info!("Client certificate changed. Reloaded it.");
+                    client_cert.reloaded(modified);
+                    client = reloaded;
+                    client_tx.send_replace(client.clone());
+                },
+                Err(e) => error!("{}. Keeping the previous certificate.", e),
+            }
+        }
+        let mut heartbeat_ok = false;
+        let mut sys_info = config.system_info.system_info();
+        if hardware.as_ref().is_none_or(|(collected, _)| collected.elapsed() >= HARDWARE_REFRESH_INTERVAL) {
+            hardware = Some((Instant::now(), collect_hardware(config.hardware_details)));
+        }
+        sys_info.hardware = hardware.as_ref().map(|(_, info)| info.clone());
+        sys_info.capabilities = capabilities.clone();
+        sys_info.recent_results = unconfirmed_results(&config);
+        let mut inventory_due = config.inventory_enabled
+            && inventory_sent.is_none_or(|sent| sent.elapsed() >= Duration::from_secs(config.inventory_interval_secs));
+        if inventory_due || (config.inventory_enabled && config.inventory_delta) {
+            let inventory = collect_inventory();
+            if config.inventory_delta {
+                let hash = inventory_hash(&inventory);
+                inventory_due |= inventory_sent_hash.as_ref() != Some(&hash);
+                sys_info.inventory_hash = Some(hash);
+            }
+            if inventory_due {
+                info!("Including software inventory ({} entries)", inventory.len());
+                sys_info.software_inventory = Some(inventory);
+            }
         }
+        let request_id = new_request_id();
+        info!("Sending heartbeat for {} (request {})", sys_info.hostname, request_id);
 
-        match req.json(&sys_info)
-            .send()
-            .await 
-        {
+        match send_heartbeat(&sys_info, &request_id, &config, &client, &machine_token).await {
             Ok(resp) => {
+                if config.sign_requests {
+                    check_clock_skew(&resp);
+                }
                 if resp.status().is_success() {
-                    match resp.json::<HeartbeatResponse>().await {
-                        Ok(hb_resp) => {
+                    // Parsed separately so a body mangled by a proxy can be logged; it counts as a failed heartbeat
+                    let parsed = match resp.text().await {
+                        Ok(body) => parse_heartbeat_response(&body),
+                        Err(e) => Err(format!("Failed to read heartbeat response: {}", e)),
+                    };
+                    match parsed {
+                        Ok(mut hb_resp) => {
+                            heartbeat_ok = true;
+                            if consecutive_failures >= config.backoff_after_failures {
+                                info!("Backend reachable again after {} failed heartbeats. Resuming normal interval.", consecutive_failures);
+                            }
+                            consecutive_failures = 0;
+
                             // Update machine token if provided
-                            if let Some(token) = hb_resp.machine_token {
+                            if let Some(token) = hb_resp.machine_token.take() {
                                 if machine_token.is_none() {
                                     info!("Received Machine Token.");
                                 }
                                 machine_token = Some(token);
+                                machine_token_tx.send_replace(machine_token.clone());
                             }
 
-                            if !hb_resp.tasks.is_empty() {
-                                info!("Received {} tasks", hb_resp.tasks.len());
-                                for task in hb_resp.tasks {
-                                    // Pass machine_token clone
-                                    if let Err(e) = process_task(&task, &config, &client, &machine_token).await {
-                                        error!("Failed to process task {}: {}", task.software_name, e);
-                                    }
+                            if let Some(key) = hb_resp.signing_key.take().filter(|key| !key.is_empty()) {
+                                store_signing_key(&config, key);
+                            }
+
+                            if !hb_resp.acknowledged_ids.is_empty() {
+                                let _state = STATE_LOCK.lock().await;
+                                if let Err(e) = confirm_results(&config, &hb_resp.acknowledged_ids) {
+                                    error!("Failed to record confirmed results: {}", e);
                                 }
                             }
+
+                            // Deliver results that could not be acknowledged earlier
+                            flush_ack_queue(&config, &client, &machine_token).await;
+
+                            let accepts_gzip = hb_resp.request_encodings.iter().any(|encoding| encoding.eq_ignore_ascii_case("gzip"));
+                            if config.request_compression == "auto" && accepts_gzip != BACKEND_ACCEPTS_GZIP.swap(accepts_gzip, std::sync::atomic::Ordering::Relaxed) {
+                                info!("Backend {} gzip request bodies.", if accepts_gzip { "accepts" } else { "no longer accepts" });
+                            }
+
+                            BACKEND_MIN_PROTOCOL_VERSION.store(hb_resp.min_protocol_version.unwrap_or(0), std::sync::atomic::Ordering::Relaxed);
+                            match hb_resp.min_protocol_version.filter(|min| *min > PROTOCOL_VERSION) {
+                                Some(min) => {
+                                    if !outdated_warned {
+                                        error!("!!! AGENT TOO OLD: protocol version {} but the backend requires at least {}. Update the agent. !!!", PROTOCOL_VERSION, min);
+                                        outdated_warned = true;
+                                    }
+                                },
+                                None => outdated_warned = false,
+                            }
+
+                            dispatch_tasks(hb_resp, &request_id, &config, &client, &machine_token, &semaphore).await;
                         },
-                        Err(e) => error!("Failed to parse heartbeat response: {}", e),
+                        Err(e) => error!("{}", e),
                     }
                 } else {
                     warn!("Heartbeat failed with status: {}", resp.status());
@@ -130,305 +985,6168 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             Err(e) => error!("Failed to send heartbeat: {}", e),
         }
 
-        tokio::time::sleep(Duration::from_secs(config.heartbeat_interval)).await;
+        METRICS.heartbeats_total.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        if heartbeat_ok {
+            *METRICS.last_heartbeat_success.lock().unwrap() = Some(Instant::now());
+            if inventory_due {
+                inventory_sent = Some(Instant::now());
+                inventory_sent_hash = sys_info.inventory_hash.clone();
+            }
+        } else {
+            METRICS.heartbeat_failures_total.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            consecutive_failures = consecutive_failures.saturating_add(1);
+            if consecutive_failures == config.backoff_after_failures {
+                warn!("{} consecutive heartbeats failed. Backing off (max {}s).", consecutive_failures, config.max_backoff_secs);
+            }
+        }
+
+        let interval = backoff_interval(config.heartbeat_interval, consecutive_failures, config.backoff_after_failures, config.max_backoff_secs);
+        if interval != config.heartbeat_interval {
+            info!("Next heartbeat in {}s (backing off)", interval);
+        }
+        tokio::time::sleep(jittered_interval(interval, config.heartbeat_jitter_secs)).await;
     }
 }
 
-fn get_system_info() -> SystemInfo {
-    let hostname = whoami::hostname();
-    let os_info = format!("{} {}", whoami::distro(), whoami::arch());
-    
-    let mac_address = match mac_address::get_mac_address() {
-        Ok(Some(mac)) => mac.to_string(),
-        Ok(None) | Err(_) => {
-            warn!("Failed to get MAC address. Generating deterministic pseudo-MAC from hostname.");
-            derive_pseudo_mac(&hostname)
-        },
-    };
+/// Oldest `PROTOCOL_VERSION` the backend supports, from the last heartbeat (0: no minimum).
+static BACKEND_MIN_PROTOCOL_VERSION: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
 
-    SystemInfo {
-        hostname,
-        mac_address,
-        os_info,
+/// Handle tasks and cancellations from the backend (a heartbeat or the long-poll channel): cancel what
+/// is queued or scheduled, refuse tasks the outdated agent shouldn't run, skip tasks already running
+/// or done, hold back those not due yet and run the rest in the background.
+async fn dispatch_tasks(response: HeartbeatResponse, request_id: &str, config: &Arc<AgentConfig>, client: &reqwest::Client, machine_token: &Option<String>, semaphore: &Arc<tokio::sync::Semaphore>) {
+    let HeartbeatResponse { mut tasks, cancelled_task_ids, min_protocol_version, .. } = response;
+    let cancelled_task_ids = cancelled_task_ids.as_slice();
+    // The heartbeat and the long-poll channel may deliver the same task at the same time
+    static DISPATCH_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+    let _dispatch = DISPATCH_LOCK.lock().await;
+
+    for task_id in cancelled_task_ids {
+        if cancel_task(*task_id) {
+            warn!("Backend cancelled task {}", task_id);
+            continue;
+        }
+        let unscheduled = {
+            let _state = STATE_LOCK.lock().await;
+            unschedule_task(config, *task_id).unwrap_or_else(|e| {
+                error!("Failed to remove task {} from the schedule: {}", task_id, e);
+                false
+            })
+        };
+        if unscheduled {
+            warn!("Backend cancelled scheduled task {}", task_id);
+            acknowledge(*task_id, "cancelled", "Cancelled by the backend before its scheduled time".to_string(), None, config, client, machine_token).await;
+        }
     }
-}
 
-#[cfg(target_os = "windows")]
-fn find_uninstall_command(software_name: &str) -> Option<String> {
-    let hives = [HKEY_LOCAL_MACHINE, HKEY_CURRENT_USER];
-    let paths = [
-        "SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\Uninstall",
-        "SOFTWARE\\WOW6432Node\\Microsoft\\Windows\\CurrentVersion\\Uninstall",
-    ];
+    // Cancellations still apply while the agent waits for running tasks before restarting
+    if !tasks.is_empty() && AGENT_RESTARTING.load(std::sync::atomic::Ordering::SeqCst) {
+        // Not acked, the backend sends them again to the restarted agent
+        info!("Agent is restarting. Not accepting {} task(s).", tasks.len());
+        return;
+    }
 
-    // Extract keywords from software_name for fuzzy matching (Fallback)
-    // e.g., "BraveBrowserStandaloneSilentNightlySetup" -> ["brave", "browser", "nightly"]
-    let keywords: Vec<String> = extract_keywords(software_name);
-    let software_name_clean = software_name.trim().to_lowercase();
-    
-    info!("Searching registry for software: '{}' (Keywords: {:?})", software_name, keywords);
-    
-    let mut best_fuzzy_match: Option<(String, usize)> = None; // (uninstall_command, match_score)
+    if !tasks.is_empty() {
+        info!("Received {} tasks (request {})", tasks.len(), request_id);
+        tasks.retain(|task| !cancelled_task_ids.contains(&task.id));
+        if let Some(min) = min_protocol_version.filter(|min| *min > PROTOCOL_VERSION && config.refuse_tasks_when_outdated) {
+            for task in tasks.iter().filter(|task| task.task_type != "agent_update") {
+                warn!("Refusing task {}: agent protocol is outdated.", task.id);
+                acknowledge(task.id, "agent_outdated", format!("Agent protocol version {} is older than the backend's minimum {}", PROTOCOL_VERSION, min), None, config, client, machine_token).await;
+            }
+            // Only an update can bring the agent up to date
+            tasks.retain(|task| task.task_type == "agent_update");
+        }
+        // Lowest priority first; tasks without one go last, ties by id
+        tasks.sort_by_key(|task| (task.priority.is_none(), task.priority, task.id));
+        let batch_ids: Vec<i32> = tasks.iter().map(|task| task.id).collect();
+        let (tasks, cyclic) = order_by_dependencies(tasks);
+        info!("Execution order: {:?}", tasks.iter().map(|task| task.id).collect::<Vec<_>>());
 
-    for hive in hives {
-        let root = RegKey::predef(hive);
-        for path in paths {
-            if let Ok(key) = root.open_subkey(path) {
-                for name in key.enum_keys().filter_map(|x| x.ok()) {
-                    if let Ok(subkey) = key.open_subkey(&name) {
-                        let display_name: String = subkey.get_value("DisplayName").unwrap_or_default();
-                        let display_name_lower = display_name.trim().to_lowercase();
-                        
-                        // 1. EXACT MATCH CHECK (Priority)
-                        if display_name_lower == software_name_clean {
-                             info!("Found EXACT MATCH for '{}'", display_name);
-                             // Try QuietUninstallString first, then UninstallString
-                             if let Ok(cmd) = subkey.get_value::<String, _>("QuietUninstallString") {
-                                 info!("Using QuietUninstallString: {}", cmd);
-                                 return Some(cmd);
-                             } else if let Ok(cmd) = subkey.get_value::<String, _>("UninstallString") {
-                                 info!("Using UninstallString: {}", cmd);
-                                 return Some(cmd);
-                             }
-                        }
+        if !cyclic.is_empty() {
+            let ids: Vec<i32> = cyclic.iter().map(|task| task.id).collect();
+            error!("Dependency cycle between tasks {:?}", ids);
+            for task in &cyclic {
+                acknowledge(task.id, "failed", format!("Dependency cycle between tasks {:?}", ids), None, config, client, machine_token).await;
+            }
+        }
 
-                        // 2. Fuzzy Match (Fallback)
-                        // Calculate match score (how many keywords match)
-                        let match_score = keywords.iter()
-                            .filter(|kw| display_name_lower.contains(kw.as_str()))
-                            .count();
-                        
-                        // Require at least 2 keywords to match, or 1 if there's only 1 keyword
-                        let min_required = if keywords.len() <= 1 { 1 } else { 2 };
-                        
-                        if match_score >= min_required {
-                            // Check if this is the best match so far
-                            let is_better = match &best_fuzzy_match {
-                                None => true,
-                                Some((_, prev_score)) => match_score > *prev_score,
-                            };
-                            
-                            if is_better {
-                                // Try QuietUninstallString first, then UninstallString
-                                let cmd_opt = subkey.get_value::<String, _>("QuietUninstallString")
-                                    .or_else(|_| subkey.get_value::<String, _>("UninstallString"));
-                                
-                                if let Ok(cmd) = cmd_opt {
-                                    info!("Found Candidate Match for '{}' (score: {}): {}", display_name, match_score, cmd);
-                                    best_fuzzy_match = Some((cmd, match_score));
-                                }
-                            }
-                        }
+        let completed = load_completed_tasks(config);
+        let scheduled: Vec<i32> = load_scheduled_tasks(config).iter().map(|task| task.id).collect();
+        let mut batch = Vec::new();
+        for task in tasks {
+            // A slow task from an earlier heartbeat may still be running
+            if is_in_flight(task.id) {
+                info!("Task {} is still running. Not starting it again.", task.id);
+                continue;
+            }
+
+            // The backend re-sends tasks whose ack it never got; don't install twice. A task that
+            // failed is sent again to retry it, so it runs again.
+            match completed.get(&task.id) {
+                Some(done) if is_successful_status(&done.ack.status) => {
+                    info!("Task {} already completed ({}). Re-sending result.", task.id, done.ack.status);
+                    if let Err(e) = send_ack(&done.ack, config, client, machine_token).await {
+                        warn!("Failed to re-send result of task {}: {}", task.id, e);
                     }
-                }
+                    continue;
+                },
+                Some(done) => info!("Task {} ended with {} before. Running it again.", task.id, done.ack.status),
+                None => {},
             }
+
+            // Held locally until due, the scheduler starts it
+            if scheduled.contains(&task.id) {
+                continue;
+            }
+            TASK_REQUEST_IDS.lock().unwrap().insert(task.id, request_id.to_string());
+            if let Some((status, message)) = hold_reason(&task, config) {
+                schedule_task(task, status, message, config, client, machine_token).await;
+                continue;
+            }
+
+            set_in_flight(task.id, true);
+            batch.push(task);
         }
+
+        // Runs in the background so heartbeats (and acks) continue while tasks are busy
+        tokio::spawn(run_task_batch(batch, batch_ids, config.clone(), client.clone(), machine_token.clone(), semaphore.clone()));
     }
-    
-    // If no exact match returned, return the best fuzzy match
-    if let Some((cmd, score)) = best_fuzzy_match {
-        info!("No exact match found. Using best fuzzy match (score: {}): {}", score, cmd);
-        return Some(cmd);
+}
+
+/// Body of a long-poll request.
+#[derive(Serialize)]
+struct LongPollRequest {
+    mac_address: String,
+    timeout_secs: u64,
+}
+
+/// The long-poll channel (`long_poll`): the backend holds each request to `/tasks/wait` open until it
+/// has tasks for this agent or `timeout_secs` pass, and answers like a heartbeat (only `tasks` and
+/// `cancelled_task_ids` are used). Gives up for good if the backend has no such endpoint.
+async fn run_long_poll(config: Arc<AgentConfig>, client_rx: tokio::sync::watch::Receiver<reqwest::Client>, mut machine_token: tokio::sync::watch::Receiver<Option<String>>, semaphore: Arc<tokio::sync::Semaphore>) {
+    // Retry delay after a failed request, doubled per further failure up to the heartbeat interval
+    const RETRY_SECS: u64 = 5;
+
+    // The backend only knows the agent after its first heartbeat
+    while machine_token.borrow().is_none() {
+        if machine_token.changed().await.is_err() {
+            return;
+        }
+    }
+    info!("Long polling {}/tasks/wait for tasks", active_backend(&config));
+
+    let mut failures: u32 = 0;
+    loop {
+        let token = machine_token.borrow().clone();
+        let client = client_rx.borrow().clone();
+        let request_id = new_request_id();
+        let body = LongPollRequest { mac_address: config.system_info.mac_address(), timeout_secs: config.long_poll_timeout_secs };
+        let mut req = client.post(format!("{}/tasks/wait", active_backend(&config)))
+            .header("X-Agent-Token", &config.auth_token)
+            .header("X-Request-Id", &request_id)
+            // Room for the backend's own timeout plus a slow network
+            .timeout(Duration::from_secs(config.long_poll_timeout_secs.saturating_add(30)));
+        if let Some(token) = &token {
+            req = req.header("X-Machine-Token", token);
+        }
+
+        let result = match signed_json(req, &body, &config).send().await.inspect(check_unsupported_encoding) {
+            Ok(resp) if matches!(resp.status().as_u16(), 404 | 405 | 501) => {
+                info!("Backend does not support long polling ({}). Tasks arrive with heartbeats.", resp.status());
+                return;
+            },
+            Ok(resp) if resp.status() == reqwest::StatusCode::NO_CONTENT => Ok(None),
+            Ok(resp) if resp.status().is_success() => match resp.text().await {
+                Ok(body) => parse_heartbeat_response(&body).map(Some),
+                Err(e) => Err(format!("Failed to read long-poll response: {}", e)),
+            },
+            Ok(resp) => Err(format!("Long poll failed with status: {}", resp.status())),
+            Err(e) if e.is_timeout() => Ok(None),
+            Err(e) => Err(format!("Long poll failed: {}", e)),
+        };
+
+        match result {
+            Ok(response) => {
+                failures = 0;
+                if let Some(mut response) = response {
+                    // Long-poll responses may leave out the minimum, the last heartbeat's still applies
+                    let known_min = BACKEND_MIN_PROTOCOL_VERSION.load(std::sync::atomic::Ordering::Relaxed);
+                    response.min_protocol_version = response.min_protocol_version.or((known_min > 0).then_some(known_min));
+                    dispatch_tasks(response, &request_id, &config, &client, &token, &semaphore).await;
+                }
+            },
+            Err(e) => {
+                failures = failures.saturating_add(1);
+                let delay = backoff_interval(RETRY_SECS, failures, 1, config.heartbeat_interval.max(RETRY_SECS));
+                warn!("{}. Retrying in {}s.", e, delay);
+                tokio::time::sleep(Duration::from_secs(delay)).await;
+            },
+        }
     }
-    
-    None
 }
 
-/// Extract meaningful keywords from a software name
-/// e.g., "BraveBrowserStandaloneSilentNightlySetup" -> ["brave", "browser", "nightly"]
-fn extract_keywords(name: &str) -> Vec<String> {
-    // Common words to filter out (not useful for matching)
-    let stop_words = ["standalone", "silent", "setup", "installer", "install", "x64", "x86", "win", "windows"];
-    
-    // Split by camelCase, underscores, dashes, and spaces
-    let mut words: Vec<String> = Vec::new();
-    let mut current_word = String::new();
-    
-    for c in name.chars() {
-        if c == '_' || c == '-' || c == ' ' || c == '.' {
-            if !current_word.is_empty() {
-                words.push(current_word.to_lowercase());
-                current_word.clear();
-            }
-        } else if c.is_uppercase() && !current_word.is_empty() {
-            // CamelCase split
-            words.push(current_word.to_lowercase());
-            current_word.clear();
-            current_word.push(c);
-        } else {
-            current_word.push(c);
+/// Initialize the logger: console only, or console plus `log_file`.
+fn init_logging(config: Option<&AgentConfig>) {
+    let mut builder = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    let mut failure = None;
+    if let Some((path, config)) = config.and_then(|config| config.log_file.as_deref().map(|path| (path, config))) {
+        let max_bytes = config.log_max_size_mb.saturating_mul(1024 * 1024);
+        match RotatingLog::open(path, max_bytes, config.log_keep_files) {
+            Ok(log) => {
+                builder.target(env_logger::Target::Pipe(Box::new(log)));
+            },
+            Err(e) => failure = Some(format!("Cannot open log file {}: {}. Logging to the console only.", path, e)),
         }
     }
-    if !current_word.is_empty() {
-        words.push(current_word.to_lowercase());
+    builder.init();
+    if let Some(failure) = failure {
+        warn!("{}", failure);
     }
-    
-    // Filter out stop words and short words (less than 3 chars)
-    words.into_iter()
-        .filter(|w| w.len() >= 3 && !stop_words.contains(&w.as_str()))
-        .collect()
 }
 
-#[derive(Serialize, Debug)]
-struct AckRequest {
-    task_id: i32,
-    status: String,
-    message: String,
-    mac_address: String,
+/// Log target that copies every record to stderr and appends it to a size-rotated file.
+/// env_logger writes each formatted record with one `write` call under its own lock, so a
+/// rotation never splits or interleaves records.
+struct RotatingLog {
+    path: std::path::PathBuf,
+    /// None only while rotating
+    file: Option<File>,
+    size: u64,
+    max_bytes: u64,
+    keep: usize,
 }
 
-async fn process_task(task: &Task, config: &AgentConfig, client: &reqwest::Client, machine_token: &Option<String>) -> Result<(), Box<dyn std::error::Error>> {
-    info!("--- Processing Task: {} ---", task.task_type);
-    info!("Target: {}", task.software_name);
-    
-    // 1. Download
-    let tmp_dir = tempfile::Builder::new().prefix("zldap_install_").tempdir()?;
-    // Fix: Remove query parameters from filename
-    let raw_name = task.download_url.split('/').last().unwrap_or("installer.exe");
-    let base_name = raw_name.split('?').next().unwrap_or("installer.exe");
-    
+impl RotatingLog {
+    fn open(path: &str, max_bytes: u64, keep: usize) -> std::io::Result<Self> {
+        let path = std::path::PathBuf::from(path);
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingLog { path, file: Some(file), size, max_bytes, keep })
+    }
 
-    // Extract just the filename component
-    let file_name = std::path::Path::new(base_name)
-        .file_name()
-        .and_then(|os_str| os_str.to_str())
-        .unwrap_or("installer.exe");
+    /// agent.log -> agent.log.1 -> ... -> agent.log.<keep>, dropping the oldest. Renames are
+    /// atomic, and the file is closed first since Windows can't rename open files.
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file = None;
+        let rotated = |n: usize| std::path::PathBuf::from(format!("{}.{}", self.path.display(), n));
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            let _ = std::fs::remove_file(rotated(self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = std::fs::rename(rotated(n), rotated(n + 1));
+            }
+            std::fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = Some(std::fs::OpenOptions::new().create(true).append(true).open(&self.path)?);
+        self.size = 0;
+        Ok(())
+    }
+}
 
-    let file_path = tmp_dir.path().join(file_name);
+impl Write for RotatingLog {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let _ = std::io::stderr().write_all(buf);
 
-    info!("Downloading from: {} to {:?}", task.download_url, file_path);
-    
-    {
-        let response = reqwest::get(&task.download_url).await?;
-        if !response.status().is_success() {
-             return Err(format!("Download failed with status: {}", response.status()).into());
+        if self.max_bytes > 0 && self.size > 0 && self.size + buf.len() as u64 > self.max_bytes {
+            if let Err(e) = self.rotate() {
+                eprintln!("Failed to rotate log file {}: {}", self.path.display(), e);
+            }
         }
-        let mut file = File::create(&file_path)?;
-        let content = response.bytes().await?;
-        copy(&mut content.as_ref(), &mut file)?;
-        
-        #[cfg(target_os = "linux")]
-        {
-            let mut perms = file.metadata()?.permissions();
-            perms.set_mode(0o755);
-            file.set_permissions(perms)?;
-            info!("Set executable permissions for {:?}", file_path);
+        // Reopen after a failed rotation, appending to whatever is there
+        if self.file.is_none() {
+            self.file = Some(std::fs::OpenOptions::new().create(true).append(true).open(&self.path)?);
+        }
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(buf)?;
+            self.size += buf.len() as u64;
         }
+        Ok(buf.len())
     }
 
-    info!("Download complete.");
+    fn flush(&mut self) -> std::io::Result<()> {
+        let _ = std::io::stderr().flush();
+        match self.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
 
-    // 2. Install / Uninstall
+/// Read config.toml (or .json/.yaml) and AGENT_* environment variables, plus command line overrides.
+fn load_config() -> Result<AgentConfig, Box<dyn std::error::Error>> {
+    let settings = Config::builder()
+        .add_source(config::File::with_name("config"))
+        .add_source(config::Environment::with_prefix("AGENT"))
+        .build()?;
 
-    let mut args: Vec<String> = split_args(&task.silent_args);
-    let mut command_path = file_path.clone();
-    
-    if task.task_type == "uninstall" {
-        info!("Executing UNINSTALL...");
+    let mut config: AgentConfig = settings.try_deserialize()?;
+    if std::env::args().skip(1).any(|arg| arg == "--dry-run") {
+        config.dry_run = true;
+    }
+    // backend_url is the single-backend form of backend_urls
+    config.backend_urls.retain(|url| !url.trim().is_empty());
+    match config.backend_urls.first() {
+        None if config.backend_url.trim().is_empty() => return Err("Either backend_url or backend_urls must be set".into()),
+        None => config.backend_urls.push(config.backend_url.clone()),
+        Some(first) if config.backend_url.trim().is_empty() => config.backend_url = first.clone(),
+        Some(_) => {},
+    }
+    Ok(config)
+}
 
-        if file_name.to_lowercase().ends_with(".msi") {
-            // For MSI, we use msiexec /x <file> /qn
-            command_path = std::path::PathBuf::from("msiexec");
-            args = vec!["/x".to_string(), file_path.to_str().unwrap().to_string(), "/qn".to_string()];
-        } else {
-            // New Registry-Based Uninstall Logic
-            #[cfg(target_os = "windows")]
-            {
-                if let Some(cmd) = find_uninstall_command(&task.software_name) {
-                    info!("Using Registry Uninstall Command: {}", cmd);
-                    // Split command into executable and args
-                    // This is tricky because the string might be "C:\Program Files\App\uninstall.exe" /S
-                    // We need to parse this properly.
-                    // Simple heuristic: 
-                    // 1. If starts with ", find closing "
-                    // 2. Else take first token
-                    
-                    let (cmd_exe, cmd_args_str) = parse_command_string(&cmd);
-                    command_path = std::path::PathBuf::from(cmd_exe);
-                    
-                    // If it was a standard UninstallString (not quiet), append our silent args
-                    // But if it was QuietUninstallString, it might already have them. 
-                    // For safety, if the user provided silent_args, we append them? 
-                    // Implementation choice: Append user args to the registry command string.
-                    
-                    let mut new_args = split_args(&cmd_args_str);
-                    if !task.silent_args.is_empty() {
-                         new_args.extend(split_args(&task.silent_args));
-                    }
-                    args = new_args;
-                    
+/// Index into `backend_urls` of the backend that last answered a heartbeat.
+static ACTIVE_BACKEND: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+/// Base URL of the backend in use (see `backend_urls`).
+fn active_backend(config: &AgentConfig) -> &str {
+    config.backend_urls.get(ACTIVE_BACKEND.load(std::sync::atomic::Ordering::Relaxed))
+        .unwrap_or(&config.backend_url)
+}
+
+/// Point a URL below one of the other `backend_urls` at the active backend, so files served by the
+/// backend come from the one that is up. Other URLs are returned unchanged.
+fn rebase_on_active_backend(url: &str, config: &AgentConfig) -> String {
+    let active = active_backend(config);
+    for backend_url in config.backend_urls.iter().filter(|backend_url| backend_url.as_str() != active) {
+        let base = backend_url.trim_end_matches('/');
+        if let Some(path) = url.strip_prefix(base).filter(|path| path.is_empty() || path.starts_with('/') || path.starts_with('?')) {
+            return format!("{}{}", active.trim_end_matches('/'), path);
+        }
+    }
+    url.to_string()
+}
+
+/// POST a heartbeat to the active backend, then the other `backend_urls` in order until one answers
+/// without a server error. That one becomes the active backend.
+async fn send_heartbeat(sys_info: &SystemInfo, request_id: &str, config: &AgentConfig, client: &reqwest::Client, machine_token: &Option<String>) -> reqwest::Result<reqwest::Response> {
+    let count = config.backend_urls.len().max(1);
+    let start = ACTIVE_BACKEND.load(std::sync::atomic::Ordering::Relaxed) % count;
+    let mut offset = 0;
+    loop {
+        let index = (start + offset) % count;
+        let backend_url = config.backend_urls.get(index).unwrap_or(&config.backend_url);
+        let mut req = client.post(format!("{}/heartbeat", backend_url))
+            .header("X-Agent-Token", &config.auth_token)
+            .header("X-Request-Id", request_id);
+        if let Some(token) = machine_token {
+            req = req.header("X-Machine-Token", token);
+        }
+
+        let result = signed_json(req, sys_info, config).send().await;
+        if let Ok(resp) = &result {
+            check_unsupported_encoding(resp);
+        }
+        offset += 1;
+        let reached = result.as_ref().is_ok_and(|resp| !resp.status().is_server_error());
+        if reached || offset == count {
+            if reached && index != start {
+                warn!("Switched to backend {}", backend_url);
+                ACTIVE_BACKEND.store(index, std::sync::atomic::Ordering::Relaxed);
+                // Negotiated with the previous backend, this one says in its own response
+                BACKEND_ACCEPTS_GZIP.store(false, std::sync::atomic::Ordering::Relaxed);
+            }
+            return result;
+        }
+        match &result {
+            Ok(resp) => warn!("Backend {} answered {}. Trying the next one.", backend_url, resp.status()),
+            Err(e) => warn!("Backend {} unreachable ({}). Trying the next one.", backend_url, e),
+        }
+    }
+}
+
+/// Settings that parse but can't work as intended.
+fn config_problems(config: &AgentConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+    for backend_url in &config.backend_urls {
+        match reqwest::Url::parse(backend_url) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {},
+            Ok(url) => problems.push(format!("backend_url '{}' has unsupported scheme '{}'", backend_url, url.scheme())),
+            Err(e) => problems.push(format!("backend_url '{}' is invalid: {}", backend_url, e)),
+        }
+    }
+    if config.auth_token.trim().is_empty() {
+        problems.push("auth_token is empty".to_string());
+    }
+    if config.heartbeat_interval == 0 {
+        problems.push("heartbeat_interval is 0".to_string());
+    }
+    if config.long_poll && config.long_poll_timeout_secs == 0 {
+        problems.push("long_poll_timeout_secs is 0".to_string());
+    }
+    if !["off", "auto", "gzip"].contains(&config.request_compression.as_str()) {
+        problems.push(format!("request_compression '{}' is not one of off, auto, gzip", config.request_compression));
+    }
+    if !["never", "notify", "auto"].contains(&config.reboot_policy.as_str()) {
+        problems.push(format!("reboot_policy '{}' is not one of never, notify, auto", config.reboot_policy));
+    }
+    if config.maintenance_window.is_some() && !config.maintenance_windows.is_empty() {
+        problems.push("maintenance_window is ignored because maintenance_windows is set".to_string());
+    }
+    for window in config.maintenance_window.iter().chain(&config.maintenance_windows) {
+        for range in window.times.iter().filter(|range| parse_time_range(range).is_none()) {
+            problems.push(format!("maintenance window time range '{}' is invalid (expected HH:MM-HH:MM), it is ignored", range));
+        }
+    }
+    if let Some(bundle_dir) = &config.bundle_dir {
+        if !std::path::Path::new(bundle_dir).is_dir() {
+            problems.push(format!("bundle_dir '{}' is not a directory", bundle_dir));
+        }
+    }
+    problems
+}
+
+/// Refuse `sign_requests` without a `signing_secret`: requests would go out unsigned.
+fn check_signing(config: &AgentConfig) -> Result<(), String> {
+    if config.sign_requests && config.signing_secret.as_deref().is_none_or(|secret| secret.trim().is_empty()) {
+        return Err("sign_requests is set but signing_secret is missing".to_string());
+    }
+    Ok(())
+}
+
+/// Differences between the local clock and the backend's beyond this make signed requests look
+/// like replays to a backend checking X-Timestamp.
+const MAX_CLOCK_SKEW_SECS: i64 = 300;
+
+/// Warn (once per episode) when the backend's Date header is far from the local clock.
+fn check_clock_skew(resp: &reqwest::Response) {
+    static SKEWED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+    let Some(backend_time) = resp.headers().get(reqwest::header::DATE)
+        .and_then(|date| date.to_str().ok())
+        .and_then(|date| chrono::DateTime::parse_from_rfc2822(date).ok()) else {
+        return;
+    };
+    let skew = chrono::Utc::now().timestamp() - backend_time.timestamp();
+    let skewed = skew.abs() > MAX_CLOCK_SKEW_SECS;
+    if skewed && !SKEWED.swap(true, std::sync::atomic::Ordering::Relaxed) {
+        warn!("Local clock is {}s {} the backend's. Signed requests may be rejected; check time synchronization.", skew.abs(), if skew > 0 { "ahead of" } else { "behind" });
+    } else if !skewed && SKEWED.swap(false, std::sync::atomic::Ordering::Relaxed) {
+        info!("Local clock is in sync with the backend again.");
+    }
+}
+
+/// Refuse a plain http:// `backend_url` unless `allow_insecure_backend` is set, since every request
+/// carries the auth token.
+fn check_backend_transport(config: &AgentConfig) -> Result<(), String> {
+    let insecure = config.backend_urls.iter().any(|backend_url| reqwest::Url::parse(backend_url).is_ok_and(|url| url.scheme() == "http"));
+    if !insecure {
+        return Ok(());
+    }
+    if !config.allow_insecure_backend {
+        return Err("backend_url uses http://, which would send auth_token in cleartext. Use https:// or set allow_insecure_backend = true.".to_string());
+    }
+    warn!("**********************************************************************");
+    warn!("INSECURE: backend_url uses http://. The auth token and all task data");
+    warn!("are sent unencrypted (allow_insecure_backend = true).");
+    warn!("**********************************************************************");
+    Ok(())
+}
+
+/// `--check-config`: print the resolved settings (secrets redacted) and any problems.
+/// Returns the process exit code.
+fn check_config() -> i32 {
+    let config = match load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            return 1;
+        }
+    };
+
+    let mut resolved = match serde_json::to_value(&config) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            eprintln!("Failed to render configuration: {}", e);
+            return 1;
+        }
+    };
+    let redacted = serde_json::Value::String("<redacted>".to_string());
+    resolved["auth_token"] = redacted.clone();
+    if config.signing_secret.is_some() {
+        resolved["signing_secret"] = redacted.clone();
+    }
+    if config.client_cert_password.is_some() {
+        resolved["client_cert_password"] = redacted.clone();
+    }
+    // Header values are typically tokens
+    if let Some(headers) = resolved["default_download_headers"].as_object_mut() {
+        headers.values_mut().for_each(|value| *value = redacted.clone());
+    }
+    println!("{}", serde_json::to_string_pretty(&resolved).unwrap_or_default());
+
+    let mut problems = config_problems(&config);
+    problems.extend(check_backend_transport(&config).err());
+    problems.extend(check_signing(&config).err());
+    problems.extend(build_client(&config).err());
+    if problems.is_empty() {
+        println!("Configuration OK");
+        0
+    } else {
+        for problem in &problems {
+            eprintln!("Problem: {}", problem);
+        }
+        1
+    }
+}
+
+/// Order tasks so every task comes after its in-batch dependencies, otherwise keeping the given
+/// order. Returns the ordered tasks and the ones caught in a dependency cycle.
+fn order_by_dependencies(tasks: Vec<Task>) -> (Vec<Task>, Vec<Task>) {
+    let batch_ids: Vec<i32> = tasks.iter().map(|task| task.id).collect();
+    let mut remaining = tasks;
+    let mut ordered: Vec<Task> = Vec::new();
+
+    loop {
+        let ready = remaining.iter().position(|task| {
+            task.depends_on.iter()
+                .filter(|dep| batch_ids.contains(dep))
+                .all(|dep| ordered.iter().any(|done| done.id == *dep))
+        });
+        match ready {
+            Some(index) => ordered.push(remaining.remove(index)),
+            None => break,
+        }
+    }
+    (ordered, remaining)
+}
+
+/// Tasks currently queued or running (so a re-sent task isn't started twice),
+/// each with a flag the backend can raise to cancel it
+static IN_FLIGHT_TASKS: Mutex<BTreeMap<i32, tokio::sync::watch::Sender<bool>>> = Mutex::new(BTreeMap::new());
+
+/// Windows Installer runs one execute sequence at a time, so msiexec runs take turns. Held only
+/// while msiexec (or a Windows package manager, which mostly runs it) runs, not while downloading.
+static MSI_LANE: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+fn is_in_flight(task_id: i32) -> bool {
+    IN_FLIGHT_TASKS.lock().unwrap().contains_key(&task_id)
+}
+
+fn set_in_flight(task_id: i32, in_flight: bool) {
+    let mut tasks = IN_FLIGHT_TASKS.lock().unwrap();
+    if in_flight {
+        tasks.insert(task_id, tokio::sync::watch::channel(false).0);
+    } else {
+        tasks.remove(&task_id);
+    }
+}
+
+/// Flag a queued or running task as cancelled. Unknown or finished tasks are ignored.
+fn cancel_task(task_id: i32) -> bool {
+    match IN_FLIGHT_TASKS.lock().unwrap().get(&task_id) {
+        Some(flag) => {
+            flag.send_replace(true);
+            true
+        },
+        None => false,
+    }
+}
+
+fn is_cancelled(task_id: i32) -> bool {
+    IN_FLIGHT_TASKS.lock().unwrap().get(&task_id).is_some_and(|flag| *flag.borrow())
+}
+
+/// Receiver that turns true when the task is cancelled, for killing its installer
+fn cancellation(task_id: i32) -> Option<tokio::sync::watch::Receiver<bool>> {
+    IN_FLIGHT_TASKS.lock().unwrap().get(&task_id).map(|flag| flag.subscribe())
+}
+
+/// Start the tasks of one heartbeat in order, each once its dependencies have finished and a
+/// slot (`max_concurrent_tasks`) is free. A task waiting for a dependency holds back the tasks
+/// after it, so the start order always follows priority.
+async fn run_task_batch(tasks: Vec<Task>, batch_ids: Vec<i32>, config: Arc<AgentConfig>, client: reqwest::Client, machine_token: Option<String>, semaphore: Arc<tokio::sync::Semaphore>) {
+    // Fires when a task of this batch has finished, for tasks depending on it
+    let mut finished: HashMap<i32, tokio::sync::watch::Receiver<bool>> = HashMap::new();
+
+    for task in tasks {
+        for dependency in &task.depends_on {
+            if let Some(done) = finished.get_mut(dependency) {
+                let _ = done.wait_for(|done| *done).await;
+            }
+        }
+
+        // Dependencies from earlier heartbeats may still be running or waiting for their time
+        let mut pending_ids = batch_ids.clone();
+        pending_ids.extend(load_scheduled_tasks(&config).iter().map(|scheduled| scheduled.id));
+        match unmet_dependency(&task, &load_completed_tasks(&config), &pending_ids) {
+            Some(DependencyBlock::Failed(reason)) => {
+                warn!("Skipping task {}: {}", task.id, reason);
+                acknowledge(task.id, "dependency_failed", reason, None, &config, &client, &machine_token).await;
+                set_in_flight(task.id, false);
+                continue;
+            },
+            Some(DependencyBlock::Pending(dependency)) => {
+                info!("Postponing task {}: dependency {} has no result yet", task.id, dependency);
+                acknowledge(task.id, "waiting_on_dependency", format!("Waiting for task {} to succeed", dependency), None, &config, &client, &machine_token).await;
+                set_in_flight(task.id, false);
+                continue;
+            },
+            None => {},
+        }
+
+        let permit = semaphore.clone().acquire_owned().await.expect("task semaphore is never closed");
+        if AGENT_RESTARTING.load(std::sync::atomic::Ordering::SeqCst) {
+            info!("Not starting task {}: the agent is restarting.", task.id);
+            set_in_flight(task.id, false);
+            continue;
+        }
+        if is_cancelled(task.id) {
+            info!("Task {} was cancelled before it started", task.id);
+            acknowledge(task.id, "cancelled", "Cancelled by the backend before it started".to_string(), None, &config, &client, &machine_token).await;
+            set_in_flight(task.id, false);
+            continue;
+        }
+        let (finished_tx, finished_rx) = tokio::sync::watch::channel(false);
+        finished.insert(task.id, finished_rx);
+
+        let (config, client, machine_token) = (config.clone(), client.clone(), machine_token.clone());
+        tokio::spawn(async move {
+            if let Err(e) = process_task(&task, &config, &client, &machine_token).await {
+                *METRICS.task_errors_total.lock().unwrap().entry(e.kind()).or_insert(0) += 1;
+                if e.is_retryable() {
+                    // Not acked, so the backend hands the task out again
+                    error!("Failed to process task {}: {}. Retrying on a later heartbeat.", task.software_name, e);
                 } else {
-                     warn!("Could not find uninstall command in registry for {}. Fallback to unsafe EXE?", task.software_name);
-                     return Err(format!("Registry lookup failed for {}. Generic EXE uninstall unavailable.", task.software_name).into());
+                    error!("Failed to process task {}: {}", task.software_name, e);
+                    let (status, message) = e.ack();
+                    acknowledge(task.id, status, message, None, &config, &client, &machine_token).await;
+                }
+            }
+            drop(permit);
+            set_in_flight(task.id, false);
+            let _ = finished_tx.send(true);
+        });
+    }
+}
+
+enum DependencyBlock {
+    /// A dependency failed or is unknown; the task can never run
+    Failed(String),
+    /// A dependency that is known but has no result yet (deferred, scheduled or still running
+    /// from an earlier heartbeat); acked "waiting_on_dependency" and tried again next heartbeat
+    Pending(i32),
+}
+
+/// Statuses that satisfy a `depends_on` reference
+fn is_successful_status(status: &str) -> bool {
+    status.starts_with("success") || status == "already_installed"
+}
+
+/// `pending_ids` are tasks that will still produce a result (this batch and scheduled tasks).
+fn unmet_dependency(task: &Task, completed: &HashMap<i32, CompletedTask>, pending_ids: &[i32]) -> Option<DependencyBlock> {
+    for dependency in &task.depends_on {
+        match completed.get(dependency) {
+            Some(done) if is_successful_status(&done.ack.status) => continue,
+            Some(done) => return Some(DependencyBlock::Failed(format!("Dependency task {} did not succeed ({})", dependency, done.ack.status))),
+            None if pending_ids.contains(dependency) || is_in_flight(*dependency) => return Some(DependencyBlock::Pending(*dependency)),
+            None => return Some(DependencyBlock::Failed(format!("Dependency task {} is unknown", dependency))),
+        }
+    }
+    None
+}
+
+/// Deserialize a heartbeat response body. The error includes the start of the body for diagnosis.
+fn parse_heartbeat_response(body: &str) -> Result<HeartbeatResponse, String> {
+    serde_json::from_str(body).map_err(|e| {
+        let mut end = body.len().min(HEARTBEAT_BODY_EXCERPT_BYTES);
+        while !body.is_char_boundary(end) {
+            end -= 1;
+        }
+        let ellipsis = if end < body.len() { "..." } else { "" };
+        format!("Failed to parse heartbeat response ({} bytes): {}. Body: {:?}{}", body.len(), e, &body[..end], ellipsis)
+    })
+}
+
+/// Heartbeat interval after `failures` consecutive failures: unchanged below `threshold`,
+/// then doubled per further failure, capped at `max_secs` (but never below `base_secs`).
+fn backoff_interval(base_secs: u64, failures: u32, threshold: u32, max_secs: u64) -> u64 {
+    if threshold == 0 || failures < threshold {
+        return base_secs;
+    }
+    let doublings = (failures - threshold + 1).min(16);
+    base_secs.saturating_mul(1 << doublings).min(max_secs.max(base_secs))
+}
+
+/// `base_secs` shifted by a random offset in `[-jitter_secs, +jitter_secs]`, never less than one second.
+fn jittered_interval(base_secs: u64, jitter_secs: u64) -> Duration {
+    let base_ms = base_secs.saturating_mul(1000) as i64;
+    let jitter_ms = jitter_secs.saturating_mul(1000) as i64;
+    let offset = if jitter_ms > 0 {
+        rand::thread_rng().gen_range(-jitter_ms..=jitter_ms)
+    } else {
+        0
+    };
+    Duration::from_millis((base_ms + offset).max(1000) as u64)
+}
+
+fn get_system_info() -> SystemInfo {
+    let hostname = whoami::fallible::hostname().unwrap_or_else(|_| "unknown".to_string());
+    #[cfg(target_os = "macos")]
+    let os_info = match macos_version() {
+        Some(version) => format!("{} {}", version, whoami::arch()),
+        None => format!("{} {}", whoami::distro(), whoami::arch()),
+    };
+    #[cfg(not(target_os = "macos"))]
+    let os_info = format!("{} {}", whoami::distro(), whoami::arch());
+    
+    let interfaces = network_interfaces();
+    let mac_address = match primary_mac_address(&interfaces).or_else(|| mac_address::get_mac_address().ok().flatten()) {
+        Some(mac) => mac.to_string(),
+        None => {
+            warn!("Failed to get MAC address. Generating deterministic pseudo-MAC from hostname.");
+            derive_pseudo_mac(&hostname)
+        },
+    };
+    let mut mac_addresses: Vec<String> = interfaces.iter().map(|(mac, _)| mac.to_string()).collect();
+    mac_addresses.sort();
+    mac_addresses.dedup();
+    let (domain, workgroup) = domain_membership(&hostname);
+
+    SystemInfo {
+        hostname,
+        mac_address,
+        mac_addresses,
+        interfaces: interface_details(),
+        os_info,
+        pending_reboot: pending_reboot(),
+        ipv4_address: primary_ip_address("8.8.8.8:53"),
+        ipv6_address: primary_ip_address("[2001:4860:4860::8888]:53"),
+        domain,
+        workgroup,
+        recent_results: Vec::new(),
+        software_inventory: None,
+        inventory_hash: None,
+        agent_info: AgentInfo::current(),
+        hardware: None,
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: Vec::new(),
+    }
+}
+
+/// Where the identity and system details the agent reports come from (`AgentConfig::system_info`),
+/// so tests and embedders can substitute their own.
+trait SystemInfoProvider: Send + Sync + std::fmt::Debug {
+    fn system_info(&self) -> SystemInfo;
+
+    /// MAC address acks and other requests identify the agent with.
+    fn mac_address(&self) -> String;
+}
+
+/// The real machine, see `get_system_info`.
+#[derive(Debug, Default)]
+struct OsSystemInfo {
+    /// From the last `system_info`, so acks and progress reports don't scan the interfaces again
+    mac_address: Mutex<Option<String>>,
+}
+
+impl SystemInfoProvider for OsSystemInfo {
+    fn system_info(&self) -> SystemInfo {
+        let info = get_system_info();
+        *self.mac_address.lock().unwrap() = Some(info.mac_address.clone());
+        info
+    }
+
+    fn mac_address(&self) -> String {
+        let cached = self.mac_address.lock().unwrap().clone();
+        cached.unwrap_or_else(|| self.system_info().mac_address)
+    }
+}
+
+fn default_system_info_provider() -> Arc<dyn SystemInfoProvider> {
+    Arc::new(OsSystemInfo::default())
+}
+
+/// CPU, memory and (with `details`) the CPU model and volumes of the machine.
+fn collect_hardware(details: bool) -> HardwareInfo {
+    // Not real disks, or read-only images (snaps) that are always full
+    const PSEUDO_FILE_SYSTEMS: [&str; 7] = ["tmpfs", "devtmpfs", "overlay", "squashfs", "ramfs", "proc", "sysfs"];
+
+    let mut system = sysinfo::System::new();
+    system.refresh_memory();
+    system.refresh_cpu_list(sysinfo::CpuRefreshKind::nothing());
+    let cpu_model = system.cpus().first()
+        .map(|cpu| cpu.brand().trim().to_string())
+        .filter(|brand| details && !brand.is_empty());
+
+    let mut disks: Vec<DiskInfo> = Vec::new();
+    if details {
+        for disk in sysinfo::Disks::new_with_refreshed_list().list() {
+            let file_system = disk.file_system().to_string_lossy().to_string();
+            let mount_point = disk.mount_point().to_string_lossy().to_string();
+            if disk.total_space() == 0 || PSEUDO_FILE_SYSTEMS.contains(&file_system.as_str()) || disks.iter().any(|known| known.mount_point == mount_point) {
+                continue;
+            }
+            disks.push(DiskInfo {
+                mount_point,
+                file_system,
+                total_bytes: disk.total_space(),
+                available_bytes: disk.available_space(),
+                removable: disk.is_removable(),
+            });
+        }
+    }
+
+    HardwareInfo {
+        cpu_model,
+        cpu_physical_cores: system.physical_core_count(),
+        cpu_logical_cores: system.cpus().len(),
+        total_memory_bytes: system.total_memory(),
+        disks,
+    }
+}
+
+/// Hex SHA-256 of the inventory as sent, so the backend can compare it with the list it has.
+fn inventory_hash(inventory: &[InstalledSoftware]) -> String {
+    use sha2::{Digest, Sha256};
+
+    hex::encode(Sha256::digest(serde_json::to_vec(inventory).unwrap_or_default()))
+}
+
+/// Installed software: the Uninstall keys (as for `find_uninstall_candidates`) on Windows, the dpkg
+/// or rpm database on Linux. Sorted, and capped at `INVENTORY_LIMIT` entries.
+fn collect_inventory() -> Vec<InstalledSoftware> {
+    #[cfg(target_os = "windows")]
+    let mut inventory: Vec<InstalledSoftware> = uninstall_entries().into_iter()
+        .filter_map(|UninstallEntry { key: subkey, .. }| {
+            let name: String = subkey.get_value("DisplayName").ok()?;
+            // Hidden components and updates aren't shown in "Programs and Features" either
+            let hidden = subkey.get_value::<u32, _>("SystemComponent").is_ok_and(|value| value == 1)
+                || subkey.get_value::<String, _>("ParentKeyName").is_ok();
+            if hidden || name.trim().is_empty() {
+                return None;
+            }
+            let text = |value: &str| subkey.get_value::<String, _>(value).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+            Some(InstalledSoftware {
+                name: name.trim().to_string(),
+                version: text("DisplayVersion"),
+                publisher: text("Publisher"),
+                size_kb: subkey.get_value("EstimatedSize").ok(),
+            })
+        })
+        .collect();
+
+    #[cfg(not(target_os = "windows"))]
+    let mut inventory: Vec<InstalledSoftware> = installed_packages().unwrap_or_default().into_iter()
+        .map(|(name, version)| InstalledSoftware { name, version: Some(version), publisher: None, size_kb: None })
+        .collect();
+
+    inventory.sort();
+    // Listed once per view (HKLM/HKCU, WOW6432Node) or architecture
+    inventory.dedup_by(|a, b| a.name == b.name && a.version == b.version);
+    if inventory.len() > INVENTORY_LIMIT {
+        warn!("Inventory has {} entries. Only sending the first {}.", inventory.len(), INVENTORY_LIMIT);
+        inventory.truncate(INVENTORY_LIMIT);
+    }
+    inventory
+}
+
+/// The local address the OS would send traffic to `remote` from. Connecting a UDP socket only
+/// selects the route, nothing is sent.
+fn primary_ip_address(remote: &str) -> Option<String> {
+    let bind = if remote.starts_with('[') { "[::]:0" } else { "0.0.0.0:0" };
+    let socket = std::net::UdpSocket::bind(bind).ok()?;
+    socket.connect(remote).ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    (!ip.is_unspecified() && !ip.is_loopback()).then(|| ip.to_string())
+}
+
+/// Domain and workgroup of the machine. Windows asks NetGetJoinInformation; elsewhere the DNS
+/// domain comes from the hostname or /etc/resolv.conf and there is no workgroup.
+#[cfg(target_os = "windows")]
+fn domain_membership(_hostname: &str) -> (Option<String>, Option<String>) {
+    use windows_sys::Win32::NetworkManagement::NetManagement::{NetApiBufferFree, NetGetJoinInformation, NetSetupDomainName, NetSetupWorkgroupName, NETSETUP_JOIN_STATUS};
+
+    let mut buffer: *mut u16 = std::ptr::null_mut();
+    let mut status: NETSETUP_JOIN_STATUS = 0;
+    // SAFETY: both out-pointers are valid; the buffer is freed with NetApiBufferFree below
+    if unsafe { NetGetJoinInformation(std::ptr::null(), &mut buffer, &mut status) } != 0 || buffer.is_null() {
+        return (None, None);
+    }
+    // SAFETY: on success the buffer holds a NUL-terminated UTF-16 string
+    let name = unsafe {
+        let len = (0..).take_while(|&i| *buffer.add(i) != 0).count();
+        String::from_utf16_lossy(std::slice::from_raw_parts(buffer, len))
+    };
+    unsafe { NetApiBufferFree(buffer as *const std::ffi::c_void) };
+
+    match status {
+        NetSetupDomainName => (Some(name), None),
+        NetSetupWorkgroupName => (None, Some(name)),
+        _ => (None, None),
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn domain_membership(hostname: &str) -> (Option<String>, Option<String>) {
+    let from_hostname = hostname.split_once('.').map(|(_, domain)| domain.to_string());
+    let from_resolv_conf = || {
+        let content = std::fs::read_to_string("/etc/resolv.conf").ok()?;
+        // "domain" wins over the first "search" entry, as in the resolver
+        let entry = |keyword: &str| content.lines()
+            .filter_map(|line| line.trim().strip_prefix(keyword))
+            .filter(|rest| rest.starts_with(char::is_whitespace))
+            .find_map(|rest| rest.split_whitespace().next().map(String::from));
+        entry("domain").or_else(|| entry("search"))
+    };
+    let domain = from_hostname.or_else(from_resolv_conf).filter(|domain| !domain.is_empty() && domain != "localdomain");
+    (domain, None)
+}
+
+/// Whether the agent has administrator rights (an elevated token) on Windows, or runs as root elsewhere.
+fn is_elevated() -> bool {
+    static ELEVATED: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+    *ELEVATED.get_or_init(|| {
+        #[cfg(target_os = "windows")]
+        {
+            use windows_sys::Win32::Foundation::{CloseHandle, HANDLE};
+            use windows_sys::Win32::Security::{GetTokenInformation, TokenElevation, TOKEN_ELEVATION, TOKEN_QUERY};
+            use windows_sys::Win32::System::Threading::{GetCurrentProcess, OpenProcessToken};
+
+            unsafe {
+                let mut token: HANDLE = std::ptr::null_mut();
+                if OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) == 0 {
+                    return false;
+                }
+                let mut elevation = TOKEN_ELEVATION { TokenIsElevated: 0 };
+                let mut len = 0u32;
+                let ok = GetTokenInformation(token, TokenElevation, (&mut elevation as *mut TOKEN_ELEVATION).cast(), std::mem::size_of::<TOKEN_ELEVATION>() as u32, &mut len) != 0;
+                CloseHandle(token);
+                ok && elevation.TokenIsElevated != 0
+            }
+        }
+
+        #[cfg(unix)]
+        {
+            unsafe { libc::geteuid() == 0 }
+        }
+
+        #[cfg(not(any(target_os = "windows", unix)))]
+        {
+            false
+        }
+    })
+}
+
+/// Whether the OS has a reboot pending: the Component Based Servicing and Windows Update
+/// markers or queued file renames on Windows, /var/run/reboot-required on Debian-style Linux.
+fn pending_reboot() -> bool {
+    #[cfg(target_os = "windows")]
+    {
+        let hklm = RegKey::predef(HKEY_LOCAL_MACHINE);
+        let key_exists = |path: &str| hklm.open_subkey(path).is_ok();
+        key_exists("SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\Component Based Servicing\\RebootPending")
+            || key_exists("SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\WindowsUpdate\\Auto Update\\RebootRequired")
+            || hklm.open_subkey("SYSTEM\\CurrentControlSet\\Control\\Session Manager")
+                .and_then(|key| key.get_raw_value("PendingFileRenameOperations"))
+                .is_ok_and(|value| value.bytes.iter().any(|byte| *byte != 0))
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        std::path::Path::new("/var/run/reboot-required").exists()
+    }
+
+    #[cfg(not(any(target_os = "windows", target_os = "linux")))]
+    {
+        false
+    }
+}
+
+/// OUI prefixes of hypervisor / container virtual NICs
+const VIRTUAL_MAC_PREFIXES: [[u8; 3]; 9] = [
+    [0x00, 0x15, 0x5D], // Hyper-V
+    [0x00, 0x05, 0x69], // VMware
+    [0x00, 0x0C, 0x29], // VMware
+    [0x00, 0x1C, 0x14], // VMware
+    [0x00, 0x50, 0x56], // VMware
+    [0x08, 0x00, 0x27], // VirtualBox
+    [0x0A, 0x00, 0x27], // VirtualBox host-only
+    [0x52, 0x54, 0x00], // QEMU/KVM
+    [0x00, 0x16, 0x3E], // Xen
+];
+
+/// Interface name prefixes of virtual adapters (bridges, containers, VPNs). Loopback has an all-zero MAC.
+const VIRTUAL_INTERFACE_PREFIXES: [&str; 12] = [
+    "docker", "veth", "br-", "virbr", "vmnet", "vboxnet", "vethernet", "tun", "tap", "wg", "utun", "zt",
+];
+
+/// All interfaces with a non-zero MAC, with their name if it can be resolved.
+fn network_interfaces() -> Vec<(mac_address::MacAddress, Option<String>)> {
+    let iter = match mac_address::MacAddressIterator::new() {
+        Ok(iter) => iter,
+        Err(e) => {
+            warn!("Failed to enumerate network interfaces: {}", e);
+            return Vec::new();
+        }
+    };
+    iter.filter(|mac| mac.bytes() != [0; 6])
+        .map(|mac| (mac, mac_address::name_by_mac_address(&mac).ok().flatten()))
+        .collect()
+}
+
+/// Every adapter with its addresses, except loopback.
+fn interface_details() -> Vec<NetworkInterface> {
+    let networks = sysinfo::Networks::new_with_refreshed_list();
+    let mut interfaces: Vec<NetworkInterface> = networks.list().iter()
+        .filter(|(name, data)| name.as_str() != "lo" && !data.ip_networks().iter().any(|network| network.addr.is_loopback()))
+        .map(|(name, data)| {
+            let mac = mac_address::MacAddress::new(data.mac_address().0);
+            let addresses = |v4: bool| -> Vec<String> {
+                data.ip_networks().iter()
+                    .filter(|network| network.addr.is_ipv4() == v4)
+                    .map(|network| network.addr.to_string())
+                    .collect()
+            };
+            let ipv4_addresses = addresses(true);
+            let ipv6_addresses = addresses(false);
+            let has_address = !ipv4_addresses.is_empty() || !ipv6_addresses.is_empty();
+            let is_up = std::fs::read_to_string(format!("/sys/class/net/{}/operstate", name))
+                .map(|state| state.trim() == "up" || (state.trim() == "unknown" && has_address))
+                .unwrap_or(has_address);
+            NetworkInterface {
+                name: name.clone(),
+                mac_address: mac.to_string(),
+                ipv4_addresses,
+                ipv6_addresses,
+                is_up,
+                is_virtual: looks_virtual(&mac, Some(name)),
+            }
+        })
+        .collect();
+    interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+    interfaces
+}
+
+/// Known hypervisor OUIs, locally administered addresses such as docker's 02:42:..., and
+/// bridge/container/VPN interface names.
+fn looks_virtual(mac: &mac_address::MacAddress, name: Option<&str>) -> bool {
+    let bytes = mac.bytes();
+    let locally_administered = bytes[0] & 0x02 != 0;
+    let virtual_oui = VIRTUAL_MAC_PREFIXES.iter().any(|prefix| bytes[..3] == prefix[..]);
+    let virtual_name = name
+        .map(|name| name.to_lowercase())
+        .is_some_and(|name| VIRTUAL_INTERFACE_PREFIXES.iter().any(|prefix| name.starts_with(prefix)));
+    locally_administered || virtual_oui || virtual_name
+}
+
+/// Pick the MAC that identifies this machine: the numerically lowest MAC of an interface that
+/// doesn't look virtual (known hypervisor OUIs, locally administered addresses such as docker's
+/// 02:42:..., and bridge/container/VPN interface names are skipped). Independent of enumeration
+/// order, so it stays the same across heartbeats as long as the hardware doesn't change.
+fn primary_mac_address(interfaces: &[(mac_address::MacAddress, Option<String>)]) -> Option<mac_address::MacAddress> {
+    interfaces.iter()
+        .filter(|(mac, name)| !looks_virtual(mac, name.as_deref()))
+        .map(|(mac, _)| *mac)
+        .min_by_key(|mac| mac.bytes())
+}
+
+/// e.g. "macOS 14.4.1 (23E224)", from sw_vers
+#[cfg(target_os = "macos")]
+fn macos_version() -> Option<String> {
+    let field = |flag: &str| -> Option<String> {
+        let output = Command::new("sw_vers").arg(flag).output().ok()?;
+        let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        (output.status.success() && !value.is_empty()).then_some(value)
+    };
+    let name = field("-productName").unwrap_or_else(|| "macOS".to_string());
+    let version = field("-productVersion")?;
+    match field("-buildVersion") {
+        Some(build) => Some(format!("{} {} ({})", name, version, build)),
+        None => Some(format!("{} {}", name, version)),
+    }
+}
+
+/// A subkey of an Uninstall key.
+#[cfg(target_os = "windows")]
+struct UninstallEntry {
+    name: String,
+    key: RegKey,
+    /// "64-bit" or "32-bit" registry view
+    view: &'static str,
+    /// Owner of the hive under HKEY_USERS the entry is in, for per-user installs
+    user_sid: Option<String>,
+    /// Keeps a hive loaded for this entry until `key` is closed (fields drop in order)
+    _hive: Option<Arc<LoadedHive>>,
+}
+
+/// Whether `uninstall_entries` loads the hives of users who aren't logged on (`load_user_hives`).
+static LOAD_USER_HIVES: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// A registry root searched for an Uninstall key.
+#[cfg(target_os = "windows")]
+struct UninstallRoot {
+    key: RegKey,
+    name: String,
+    user_sid: Option<String>,
+    hive: Option<Arc<LoadedHive>>,
+}
+
+/// Every entry under the Uninstall keys (HKLM, HKCU and each user's hive under HKEY_USERS,
+/// 64-bit and 32-bit registry views)
+#[cfg(target_os = "windows")]
+fn uninstall_entries() -> Vec<UninstallEntry> {
+    let mut roots = vec![
+        UninstallRoot { key: RegKey::predef(HKEY_LOCAL_MACHINE), name: "HKLM".to_string(), user_sid: None, hive: None },
+        // Empty when running as SYSTEM, the users' hives below cover that
+        UninstallRoot { key: RegKey::predef(HKEY_CURRENT_USER), name: "HKCU".to_string(), user_sid: None, hive: None },
+    ];
+    roots.extend(user_hive_roots());
+    // Explicit views instead of the WOW6432Node path: a 32-bit agent is redirected to the
+    // 32-bit view and would never see 64-bit software otherwise
+    let views = [(KEY_WOW64_64KEY, "64-bit"), (KEY_WOW64_32KEY, "32-bit")];
+    let path = "SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\Uninstall";
+
+    let mut seen = std::collections::HashSet::new();
+    let mut entries = Vec::new();
+    for root in &roots {
+        for (view, view_name) in views {
+            if let Ok(key) = root.key.open_subkey_with_flags(path, KEY_READ | view) {
+                for name in key.enum_keys().filter_map(|x| x.ok()) {
+                    if let Ok(subkey) = key.open_subkey_with_flags(&name, KEY_READ | view) {
+                        // User hives (and everything on 32-bit Windows) look the same in both views
+                        let display_name: String = subkey.get_value("DisplayName").unwrap_or_default();
+                        if seen.insert((root.name.clone(), name.to_lowercase(), display_name)) {
+                            entries.push(UninstallEntry { name, key: subkey, view: view_name, user_sid: root.user_sid.clone(), _hive: root.hive.clone() });
+                        }
+                    }
                 }
             }
-            #[cfg(not(target_os = "windows"))]
-            {
-                 return Err("Registry uninstall only supported on Windows".into());
+        }
+    }
+    entries
+}
+
+/// The hives of real users under HKEY_USERS, plus (with `load_user_hives`) those of users who
+/// aren't logged on, loaded from their profile's NTUSER.DAT.
+#[cfg(target_os = "windows")]
+fn user_hive_roots() -> Vec<UninstallRoot> {
+    let users = RegKey::predef(HKEY_USERS);
+    let loaded: Vec<String> = users.enum_keys().filter_map(|x| x.ok()).filter(|sid| is_user_sid(sid)).collect();
+    let mut roots: Vec<UninstallRoot> = loaded.iter()
+        .filter_map(|sid| {
+            let key = users.open_subkey_with_flags(sid, KEY_READ).ok()?;
+            Some(UninstallRoot { key, name: format!("HKU\\{}", sid), user_sid: Some(sid.clone()), hive: None })
+        })
+        .collect();
+    if !LOAD_USER_HIVES.load(std::sync::atomic::Ordering::Relaxed) {
+        return roots;
+    }
+
+    for (sid, profile_dir) in user_profiles() {
+        if loaded.iter().any(|loaded_sid| loaded_sid.eq_ignore_ascii_case(&sid)) {
+            continue;
+        }
+        let hive = match LoadedHive::load(&sid, &profile_dir.join("NTUSER.DAT")) {
+            Ok(hive) => hive,
+            Err(e) => {
+                warn!("Could not load the registry hive of {}: {}", sid, e);
+                continue;
+            }
+        };
+        if let Ok(key) = users.open_subkey_with_flags(&hive.mount_name, KEY_READ) {
+            roots.push(UninstallRoot { key, name: format!("HKU\\{}", sid), user_sid: Some(sid), hive: Some(Arc::new(hive)) });
+        }
+    }
+    roots
+}
+
+/// Local and Azure AD accounts. Skips .DEFAULT, the service accounts (S-1-5-18/19/20) and the
+/// `_Classes` hives.
+#[cfg(target_os = "windows")]
+fn is_user_sid(sid: &str) -> bool {
+    (sid.starts_with("S-1-5-21-") || sid.starts_with("S-1-12-1-")) && !sid.ends_with("_Classes")
+}
+
+/// SIDs and profile directories of the users with a profile on this machine.
+#[cfg(target_os = "windows")]
+fn user_profiles() -> Vec<(String, std::path::PathBuf)> {
+    let Ok(profiles) = RegKey::predef(HKEY_LOCAL_MACHINE).open_subkey("SOFTWARE\\Microsoft\\Windows NT\\CurrentVersion\\ProfileList") else {
+        return Vec::new();
+    };
+    profiles.enum_keys().filter_map(|x| x.ok())
+        .filter(|sid| is_user_sid(sid))
+        .filter_map(|sid| {
+            let path: String = profiles.open_subkey(&sid).ok()?.get_value("ProfileImagePath").ok()?;
+            let system_drive = std::env::var("SystemDrive").unwrap_or_else(|_| "C:".to_string());
+            Some((sid, std::path::PathBuf::from(path.replace("%SystemDrive%", &system_drive))))
+        })
+        .collect()
+}
+
+/// A user's NTUSER.DAT loaded under HKEY_USERS, unloaded again when dropped.
+#[cfg(target_os = "windows")]
+struct LoadedHive {
+    mount_name: String,
+}
+
+#[cfg(target_os = "windows")]
+impl LoadedHive {
+    fn load(sid: &str, file: &std::path::Path) -> Result<Self, String> {
+        use windows_sys::Win32::System::Registry::{RegLoadKeyW, HKEY_USERS};
+
+        // SYSTEM holds both privileges, but they are disabled by default
+        if !enable_privilege("SeRestorePrivilege") || !enable_privilege("SeBackupPrivilege") {
+            return Err("SeRestorePrivilege and SeBackupPrivilege are not available".to_string());
+        }
+        let mount_name = format!("ZE-SilentSync_{}", sid);
+        let code = unsafe { RegLoadKeyW(HKEY_USERS, to_wide(&mount_name).as_ptr(), to_wide(file.as_os_str()).as_ptr()) };
+        if code != 0 {
+            return Err(format!("RegLoadKeyW failed for {} (error {})", file.display(), code));
+        }
+        debug!("Loaded registry hive {} as HKU\\{}", file.display(), mount_name);
+        Ok(Self { mount_name })
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl Drop for LoadedHive {
+    fn drop(&mut self) {
+        use windows_sys::Win32::System::Registry::{RegUnLoadKeyW, HKEY_USERS};
+
+        let code = unsafe { RegUnLoadKeyW(HKEY_USERS, to_wide(&self.mount_name).as_ptr()) };
+        if code != 0 {
+            warn!("Could not unload registry hive HKU\\{} (error {})", self.mount_name, code);
+        }
+    }
+}
+
+/// Enable a privilege the agent's token holds, e.g. "SeRestorePrivilege".
+#[cfg(target_os = "windows")]
+fn enable_privilege(name: &str) -> bool {
+    use windows_sys::Win32::Foundation::{CloseHandle, GetLastError, HANDLE, LUID};
+    use windows_sys::Win32::Security::{AdjustTokenPrivileges, LookupPrivilegeValueW, LUID_AND_ATTRIBUTES, SE_PRIVILEGE_ENABLED, TOKEN_ADJUST_PRIVILEGES, TOKEN_PRIVILEGES, TOKEN_QUERY};
+    use windows_sys::Win32::System::Threading::{GetCurrentProcess, OpenProcessToken};
+
+    unsafe {
+        let mut token: HANDLE = std::ptr::null_mut();
+        if OpenProcessToken(GetCurrentProcess(), TOKEN_ADJUST_PRIVILEGES | TOKEN_QUERY, &mut token) == 0 {
+            return false;
+        }
+        let mut luid = LUID { LowPart: 0, HighPart: 0 };
+        let enabled = LookupPrivilegeValueW(std::ptr::null(), to_wide(name).as_ptr(), &mut luid) != 0 && {
+            let privileges = TOKEN_PRIVILEGES {
+                PrivilegeCount: 1,
+                Privileges: [LUID_AND_ATTRIBUTES { Luid: luid, Attributes: SE_PRIVILEGE_ENABLED }],
+            };
+            // Also "succeeds" without the privilege, GetLastError tells
+            AdjustTokenPrivileges(token, 0, &privileges, 0, std::ptr::null_mut(), std::ptr::null_mut()) != 0 && GetLastError() == 0
+        };
+        CloseHandle(token);
+        enabled
+    }
+}
+
+/// NUL-terminated UTF-16 for Win32 APIs.
+#[cfg(target_os = "windows")]
+fn to_wide(s: impl AsRef<std::ffi::OsStr>) -> Vec<u16> {
+    use std::os::windows::ffi::OsStrExt;
+    s.as_ref().encode_wide().chain(std::iter::once(0)).collect()
+}
+
+/// How to remove a product found in the Uninstall keys.
+#[cfg(target_os = "windows")]
+enum UninstallCommand {
+    /// Windows Installer product, removed with `msiexec /x {ProductCode}`
+    MsiProduct(String),
+    /// QuietUninstallString or UninstallString
+    CommandLine(String),
+}
+
+#[cfg(target_os = "windows")]
+impl std::fmt::Display for UninstallCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UninstallCommand::MsiProduct(product_code) => write!(f, "msiexec /x {}", product_code),
+            UninstallCommand::CommandLine(cmd) => write!(f, "{}", cmd),
+        }
+    }
+}
+
+/// An MSI entry's key is named after its ProductCode. Its UninstallString is often `MsiExec.exe /I{GUID}`,
+/// which opens the maintenance UI, so the product code is used directly instead.
+#[cfg(target_os = "windows")]
+fn entry_uninstall_command(name: &str, subkey: &RegKey) -> Option<UninstallCommand> {
+    let windows_installer = subkey.get_value::<u32, _>("WindowsInstaller").is_ok_and(|value| value == 1);
+    let product_code = Some(name).filter(|name| is_product_code(name)).map(String::from).or_else(|| {
+        // Some MSI entries use another key name, the UninstallString still carries the code
+        let cmd: String = subkey.get_value("UninstallString").ok().filter(|_| windows_installer)?;
+        let start = cmd.find('{')?;
+        let end = start + cmd[start..].find('}')?;
+        Some(cmd[start..=end].to_string()).filter(|code| is_product_code(code))
+    });
+    if let Some(product_code) = product_code {
+        return Some(UninstallCommand::MsiProduct(product_code.to_uppercase()));
+    }
+
+    // Try QuietUninstallString first, then UninstallString
+    subkey.get_value::<String, _>("QuietUninstallString")
+        .or_else(|_| subkey.get_value::<String, _>("UninstallString"))
+        .ok()
+        .map(UninstallCommand::CommandLine)
+}
+
+/// "{12345678-1234-1234-1234-123456789ABC}"
+#[cfg(target_os = "windows")]
+fn is_product_code(value: &str) -> bool {
+    let Some(inner) = value.strip_prefix('{').and_then(|rest| rest.strip_suffix('}')) else {
+        return false;
+    };
+    let groups: Vec<&str> = inner.split('-').collect();
+    groups.iter().map(|group| group.len()).eq([8, 4, 4, 4, 12])
+        && groups.iter().all(|group| group.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// A registry uninstall entry matching a task's software name.
+#[cfg(target_os = "windows")]
+struct UninstallCandidate {
+    display_name: String,
+    display_version: String,
+    /// Matching keywords; exact name matches rank above every keyword match
+    score: usize,
+    command: UninstallCommand,
+    /// Registry view the entry was found in (see `UninstallEntry`)
+    view: &'static str,
+    /// Owner of the user hive the entry was found in
+    user_sid: Option<String>,
+}
+
+#[cfg(target_os = "windows")]
+impl std::fmt::Display for UninstallCandidate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} ({}, ", self.display_name, self.display_version, self.view)?;
+        if let Some(sid) = &self.user_sid {
+            write!(f, "user {}, ", sid)?;
+        }
+        write!(f, "score {}): {}", self.score, self.command)
+    }
+}
+
+/// Every uninstall entry whose DisplayName matches `software_name` exactly or by enough keywords,
+/// best first. With `match_hint`, only entries whose DisplayName or DisplayVersion contain it count.
+#[cfg(target_os = "windows")]
+fn find_uninstall_candidates(software_name: &str, match_hint: Option<&str>, config: &AgentConfig) -> Vec<UninstallCandidate> {
+    // Extract keywords from software_name for fuzzy matching (Fallback)
+    // e.g., "BraveBrowserStandaloneSilentNightlySetup" -> ["brave", "browser", "nightly"]
+    let keywords = extract_keywords(software_name, &config.extra_stop_words);
+    let software_name_clean = software_name.trim().to_lowercase();
+    let hint = match_hint.map(|hint| hint.trim().to_lowercase()).filter(|hint| !hint.is_empty());
+    
+    info!("Searching registry for software: '{}' (Keywords: {:?}, hint: {:?})", software_name, keywords, hint);
+    let exact_score = exact_match_score(&keywords);
+
+    collect_uninstall_candidates(|display_name, display_version| {
+        let display_name_lower = display_name.trim().to_lowercase();
+        if let Some(hint) = &hint {
+            if !display_name_lower.contains(hint.as_str()) && !display_version.to_lowercase().contains(hint.as_str()) {
+                return None;
+            }
+        }
+
+        // 1. EXACT MATCH (Priority), 2. Fuzzy Match: how many keywords match
+        if display_name_lower == software_name_clean {
+            Some(exact_score)
+        } else {
+            keyword_score(&keywords, display_name)
+        }
+    })
+}
+
+/// Uninstall entries whose DisplayName is exactly `display_name` (ignoring case and whitespace
+/// differences) and, if given, whose DisplayVersion is `display_version`. No keyword matching.
+#[cfg(target_os = "windows")]
+fn find_exact_uninstall_candidates(display_name: &str, display_version: Option<&str>) -> Vec<UninstallCandidate> {
+    let wanted = normalize_display_name(display_name);
+    info!("Searching registry for exact DisplayName '{}' (version: {:?})", display_name, display_version);
+    collect_uninstall_candidates(|name, version| {
+        let version_matches = display_version.is_none_or(|wanted| version.trim().eq_ignore_ascii_case(wanted.trim()));
+        (normalize_display_name(name) == wanted && version_matches).then_some(1)
+    })
+}
+
+/// Uninstall entries that `scorer` (given DisplayName and DisplayVersion) accepts, best first and
+/// without duplicate commands.
+#[cfg(target_os = "windows")]
+fn collect_uninstall_candidates(scorer: impl Fn(&str, &str) -> Option<usize>) -> Vec<UninstallCandidate> {
+    let mut candidates: Vec<UninstallCandidate> = Vec::new();
+    for UninstallEntry { name, key: subkey, view, user_sid, .. } in uninstall_entries() {
+        let display_name: String = subkey.get_value("DisplayName").unwrap_or_default();
+        let display_version: String = subkey.get_value("DisplayVersion").unwrap_or_default();
+        let Some(score) = scorer(&display_name, &display_version) else {
+            continue;
+        };
+
+        let Some(command) = entry_uninstall_command(&name, &subkey) else {
+            continue;
+        };
+        // The same product often appears in several views (HKLM/HKCU, WOW6432Node)
+        let command_line = command.to_string();
+        if candidates.iter().any(|candidate| candidate.command.to_string().eq_ignore_ascii_case(&command_line)) {
+            continue;
+        }
+        info!("Found Candidate Match for '{}' in the {} registry view (score: {}): {}", display_name, view, score, command);
+        candidates.push(UninstallCandidate { display_name, display_version, score, command, view, user_sid });
+    }
+    candidates.sort_by_key(|candidate| std::cmp::Reverse(candidate.score));
+    candidates
+}
+
+/// " (version x)" for messages about a task's `display_version`, if it has one.
+fn version_suffix(task: &Task) -> String {
+    task.display_version.as_deref().map(|version| format!(" (version {})", version)).unwrap_or_default()
+}
+
+/// Lowercase, with runs of whitespace collapsed to single spaces, for exact name comparisons.
+fn normalize_display_name(name: &str) -> String {
+    name.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+/// Words that say nothing about which product a name means. Extended by `extra_stop_words`.
+const STOP_WORDS: [&str; 15] = [
+    "standalone", "silent", "setup", "installer", "install", "win", "windows",
+    // Architectures, spelled every way vendors do
+    "x64", "x86", "win64", "win32", "amd64", "arm64", "64bit", "32bit",
+];
+
+/// Weight of a matching word in a keyword score; matching versions only add 1 each.
+const KEYWORD_WEIGHT: usize = 10;
+
+/// A token of a software name used for fuzzy matching.
+#[derive(Debug, PartialEq)]
+enum Keyword {
+    /// Matched anywhere in a name, also with its punctuation removed ("7zip" in "7-Zip 23.01")
+    Word(String),
+    /// Under three characters ("r"), only matched as a whole word
+    Short(String),
+    /// A number or version ("2024", "v2"), matched as a whole word to break ties but never required
+    Version(String),
+}
+
+/// Extract meaningful keywords from a software name
+/// e.g., "BraveBrowserStandaloneSilentNightlySetup" -> ["brave", "browser", "nightly"]
+fn extract_keywords(name: &str, extra_stop_words: &[String]) -> Vec<Keyword> {
+    let mut keywords = Vec::new();
+    for word in name_tokens(name) {
+        if STOP_WORDS.contains(&word.as_str()) || extra_stop_words.iter().any(|stop| stop.trim().eq_ignore_ascii_case(&word)) {
+            continue;
+        }
+        let digits = word.strip_prefix('v').unwrap_or(&word);
+        let keyword = if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+            Keyword::Version(word)
+        } else if word.chars().count() < 3 {
+            Keyword::Short(word)
+        } else {
+            Keyword::Word(word)
+        };
+        if !keywords.contains(&keyword) {
+            keywords.push(keyword);
+        }
+    }
+    keywords
+}
+
+/// Split a name into lowercase words at punctuation, spaces and camelCase ("VLCMediaPlayer" ->
+/// ["vlc", "media", "player"]). Digits run into the letters after them ("7-Zip", "7Zip" -> "7zip").
+fn name_tokens(name: &str) -> Vec<String> {
+    let chars: Vec<char> = name.chars().collect();
+    let mut words = Vec::new();
+    let mut current = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        let prev = i.checked_sub(1).map(|p| chars[p]);
+        let next = chars.get(i + 1).copied();
+        let leading_number = !current.is_empty() && current.chars().all(|ch| ch.is_ascii_digit());
+        if c.is_alphanumeric() {
+            // CamelCase split, keeping acronyms ("VLC") and numbered names ("7Zip") together
+            let boundary = c.is_uppercase() && prev.is_some_and(|p| {
+                p.is_lowercase()
+                    || (p.is_uppercase() && next.is_some_and(char::is_lowercase))
+                    || (p.is_ascii_digit() && !leading_number)
+            });
+            if boundary && !current.is_empty() {
+                words.push(std::mem::take(&mut current).to_lowercase());
+            }
+            current.push(c);
+        } else if c == '-' && leading_number && next.is_some_and(char::is_alphabetic) {
+            continue;
+        } else if !current.is_empty() {
+            words.push(std::mem::take(&mut current).to_lowercase());
+        }
+    }
+    if !current.is_empty() {
+        words.push(current.to_lowercase());
+    }
+    words
+}
+
+/// How well `name` (a DisplayName or package name) matches `keywords`: `KEYWORD_WEIGHT` per matching
+/// word plus 1 per matching version. `None` unless 2 words match, or 1 if there's only 1.
+fn keyword_score(keywords: &[Keyword], name: &str) -> Option<usize> {
+    let name_lower = name.to_lowercase();
+    let tokens = name_tokens(name);
+    let compact = tokens.concat();
+    let (mut words, mut matched, mut versions) = (0, 0, 0);
+    for keyword in keywords {
+        match keyword {
+            Keyword::Word(word) => {
+                words += 1;
+                if name_lower.contains(word.as_str()) || compact.contains(word.as_str()) {
+                    matched += 1;
+                }
+            },
+            Keyword::Short(word) => {
+                words += 1;
+                if tokens.contains(word) {
+                    matched += 1;
+                }
+            },
+            Keyword::Version(version) => {
+                if tokens.contains(version) {
+                    versions += 1;
+                }
+            },
+        }
+    }
+    (matched > 0 && matched >= words.min(2)).then_some(matched * KEYWORD_WEIGHT + versions.min(KEYWORD_WEIGHT - 1))
+}
+
+/// Score of an exact name match, above every keyword score for `keywords`.
+fn exact_match_score(keywords: &[Keyword]) -> usize {
+    (keywords.len() + 1) * KEYWORD_WEIGHT
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct AckRequest {
+    task_id: i32,
+    status: String,
+    message: String,
+    mac_address: String,
+    /// Tail of the installer's stdout/stderr
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    output: Option<String>,
+    /// Id of the heartbeat that delivered the task, also sent as X-Request-Id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+impl AckRequest {
+    fn new(task_id: i32, status: &str, message: String, output: Option<String>, system_info: &dyn SystemInfoProvider) -> Self {
+        AckRequest {
+            task_id,
+            status: status.to_string(),
+            message,
+            mac_address: system_info.mac_address(),
+            output,
+            request_id: task_request_id(task_id),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct ProgressRequest {
+    task_id: i32,
+    phase: String,
+    percent: u8,
+    mac_address: String,
+}
+
+/// Minimum time between two progress reports for the same task.
+const PROGRESS_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// How often download progress is logged when stderr isn't a terminal.
+const DOWNLOAD_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Merge `default_download_headers` with the task's own. Values are marked sensitive and never logged.
+fn build_download_headers(task: &Task, config: &AgentConfig) -> Result<HeaderMap, String> {
+    let mut merged = config.default_download_headers.clone();
+    if let Some(task_headers) = &task.download_headers {
+        merged.extend(task_headers.clone());
+    }
+
+    let mut headers = HeaderMap::new();
+    for (name, value) in merged {
+        let header_name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("Invalid download header name '{}'", name))?;
+        let mut header_value = HeaderValue::from_str(&value)
+            .map_err(|_| format!("Invalid value for download header '{}'", name))?;
+        header_value.set_sensitive(true);
+        headers.insert(header_name, header_value);
+    }
+    if !headers.is_empty() {
+        info!("Using download headers: {:?}", headers.keys().collect::<Vec<_>>());
+    }
+    Ok(headers)
+}
+
+/// Token bucket for download throttling. Holds at most one second worth of tokens,
+/// so the speed can't burst above the cap after an idle period.
+struct RateLimiter {
+    bytes_per_sec: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    fn new(kbps: u64) -> Self {
+        let bytes_per_sec = (kbps.max(1).saturating_mul(1000) / 8) as f64;
+        Self { bytes_per_sec, tokens: bytes_per_sec, last_refill: Instant::now() }
+    }
+
+    /// Take `bytes` tokens, sleeping until the bucket is out of debt.
+    async fn consume(&mut self, bytes: usize) {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last_refill).as_secs_f64() * self.bytes_per_sec;
+        self.tokens = (self.tokens + refill).min(self.bytes_per_sec);
+        self.last_refill = now;
+
+        self.tokens -= bytes as f64;
+        if self.tokens < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-self.tokens / self.bytes_per_sec)).await;
+        }
+    }
+}
+
+/// Stream `url` into `file`. Progress is reported for `progress_task_id` if given.
+/// Returns the number of bytes written.
+async fn download_to_file(url: &str, headers: &HeaderMap, progress_task_id: Option<i32>, file: &mut File, config: &AgentConfig, client: &reqwest::Client, machine_token: &Option<String>) -> Result<u64, Box<dyn std::error::Error>> {
+    let url = rebase_on_active_backend(url, config);
+    let url = url.as_str();
+    check_download_url(url, config).map_err(|reason| DownloadUrlRejected { reason })?;
+    let response = client.get(url).headers(headers.clone()).send().await?;
+    if !response.status().is_success() {
+         return Err(format!("Download failed with status: {}", response.status()).into());
+    }
+
+    let total_bytes = response.content_length();
+    if let (Some(total), Some(limit)) = (total_bytes, config.max_download_bytes) {
+        if total > limit {
+            return Err(DownloadLimitExceeded { limit }.into());
+        }
+    }
+
+    // Operators running the agent by hand get a progress bar, services a log line now and then
+    let progress_bar = total_bytes.filter(|total| *total > 0 && std::io::stderr().is_terminal()).map(|total| {
+        let style = indicatif::ProgressStyle::with_template("{bar:40} {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
+            .unwrap_or_else(|_| indicatif::ProgressStyle::default_bar());
+        indicatif::ProgressBar::new(total).with_style(style)
+    });
+
+    let mut downloaded: u64 = 0;
+    let mut last_report = Instant::now();
+    let mut last_log = Instant::now();
+    let mut stream = response.bytes_stream();
+    let mut limiter = config.max_download_kbps.map(RateLimiter::new);
+
+    while let Some(chunk) = stream.next().await {
+        // A large download shouldn't run to the end just to be thrown away
+        if progress_task_id.is_some_and(is_cancelled) {
+            return Err(DownloadCancelled.into());
+        }
+        let chunk = chunk?;
+        if let Some(limiter) = limiter.as_mut() {
+            limiter.consume(chunk.len()).await;
+        }
+        downloaded += chunk.len() as u64;
+        METRICS.bytes_downloaded_total.fetch_add(chunk.len() as u64, std::sync::atomic::Ordering::Relaxed);
+        // Content-Length may be missing or lie, so enforce the cap on what actually arrives
+        if let Some(limit) = config.max_download_bytes {
+            if downloaded > limit {
+                return Err(DownloadLimitExceeded { limit }.into());
+            }
+        }
+        file.write_all(&chunk)?;
+
+        match (&progress_bar, total_bytes.filter(|t| *t > 0)) {
+            (Some(bar), _) => bar.inc(chunk.len() as u64),
+            (None, Some(total)) if last_log.elapsed() >= DOWNLOAD_LOG_INTERVAL => {
+                info!("Downloaded {}% ({} of {} bytes)", (downloaded.saturating_mul(100) / total).min(100), downloaded, total);
+                last_log = Instant::now();
+            },
+            _ => {},
+        }
+        if let (Some(total), Some(task_id)) = (total_bytes.filter(|t| *t > 0), progress_task_id) {
+            if last_report.elapsed() >= PROGRESS_REPORT_INTERVAL {
+                let percent = (downloaded.saturating_mul(100) / total).min(100) as u8;
+                report_progress(task_id, "download", percent, config, client, machine_token).await;
+                last_report = Instant::now();
+            }
+        }
+    }
+    file.flush()?;
+    if let Some(bar) = progress_bar {
+        bar.finish_and_clear();
+    }
+
+    if let (Some(_), Some(task_id)) = (total_bytes, progress_task_id) {
+        report_progress(task_id, "download", 100, config, client, machine_token).await;
+    }
+
+    Ok(downloaded)
+}
+
+/// Why `process_task` failed, by the stage that failed.
+///
+/// There is no `Signature` variant: the agent doesn't verify code signatures of installers,
+/// downloads are pinned by their `sha256` instead (a mismatch is `Checksum`).
+#[derive(Debug, thiserror::Error)]
+enum TaskError {
+    /// `permanent` failures (size limit, rejected URL, missing pre-staged file) won't go away by retrying
+    #[error("{reason}")]
+    Download { reason: String, permanent: bool },
+    #[error("Checksum mismatch: expected {expected}, got {actual}")]
+    Checksum { expected: String, actual: String },
+    /// The installer (or uninstaller/script) could not be started or did not succeed
+    #[error("{0}")]
+    Execute(String),
+    #[error("Killed after running for {elapsed_secs}s (timeout: {limit_secs}s, working directory: {})", .work_dir.display())]
+    Timeout { elapsed_secs: u64, limit_secs: u64, work_dir: std::path::PathBuf },
+    /// No installed product (registry entry or package) matches an uninstall
+    #[error("{0}")]
+    RegistryLookup(String),
+    /// The file to run doesn't look like what its name says (see `check_executable`)
+    #[error("downloaded file is not a valid executable ({0})")]
+    NotExecutable(String),
+    /// The backend cancelled the task (`cancelled_task_ids`) before its installer started
+    #[error("{0}")]
+    Cancelled(String),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    /// Failures of the other task types, which ack their own results
+    #[error("{0}")]
+    Other(String),
+}
+
+/// Classify an error of `download_to_file`: cancellation, or a download failure that is permanent
+/// for a rejected URL or an exceeded size limit.
+fn download_error(e: Box<dyn std::error::Error>) -> TaskError {
+    if e.is::<DownloadCancelled>() {
+        TaskError::Cancelled("Cancelled by the backend during the download".to_string())
+    } else {
+        TaskError::Download { reason: e.to_string(), permanent: e.is::<DownloadLimitExceeded>() || e.is::<DownloadUrlRejected>() }
+    }
+}
+
+impl From<Box<dyn std::error::Error>> for TaskError {
+    fn from(e: Box<dyn std::error::Error>) -> Self {
+        TaskError::Other(e.to_string())
+    }
+}
+
+impl TaskError {
+    /// Label on the `task_errors_total` metric.
+    fn kind(&self) -> &'static str {
+        match self {
+            TaskError::Download { .. } => "download",
+            TaskError::Checksum { .. } => "checksum",
+            TaskError::Execute(_) => "execute",
+            TaskError::Timeout { .. } => "timeout",
+            TaskError::RegistryLookup(_) => "registry_lookup",
+            TaskError::Cancelled(_) => "cancelled",
+            TaskError::NotExecutable(_) => "not_executable",
+            TaskError::Io(_) => "io",
+            TaskError::Other(_) => "other",
+        }
+    }
+
+    /// Transient failures are left unacked so the task comes back with a later heartbeat.
+    fn is_retryable(&self) -> bool {
+        matches!(self, TaskError::Download { permanent: false, .. } | TaskError::Io(_) | TaskError::Other(_))
+    }
+
+    /// Ack status and message reported to the backend.
+    fn ack(&self) -> (&'static str, String) {
+        let status = match self {
+            TaskError::Download { .. } => "download_failed",
+            TaskError::Checksum { .. } => "checksum_mismatch",
+            TaskError::Timeout { .. } => "timeout",
+            TaskError::RegistryLookup(_) => "not_installed",
+            TaskError::Cancelled(_) => "cancelled",
+            TaskError::Execute(_) | TaskError::NotExecutable(_) | TaskError::Io(_) | TaskError::Other(_) => "failed",
+        };
+        (status, self.to_string())
+    }
+}
+
+/// The download exceeded `max_download_bytes`.
+#[derive(Debug)]
+struct DownloadLimitExceeded {
+    limit: u64,
+}
+
+impl std::fmt::Display for DownloadLimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Download exceeds the size limit of {} bytes", self.limit)
+    }
+}
+
+impl std::error::Error for DownloadLimitExceeded {}
+
+/// The task was cancelled while its file was downloading.
+#[derive(Debug)]
+struct DownloadCancelled;
+
+impl std::fmt::Display for DownloadCancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Download cancelled")
+    }
+}
+
+impl std::error::Error for DownloadCancelled {}
+
+/// The download URL is not allowed by `allowed_download_hosts` / `allow_insecure_downloads`.
+#[derive(Debug)]
+struct DownloadUrlRejected {
+    reason: String,
+}
+
+impl std::fmt::Display for DownloadUrlRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Download URL rejected: {}", self.reason)
+    }
+}
+
+impl std::error::Error for DownloadUrlRejected {}
+
+/// The pre-staged file a task uses instead of a download (`local_path` or a file:// URL), if any.
+/// It has to exist inside `bundle_dir`.
+fn resolve_local_source(task: &Task, config: &AgentConfig) -> Result<Option<std::path::PathBuf>, String> {
+    let requested = match &task.local_path {
+        Some(local_path) => std::path::PathBuf::from(local_path),
+        None if task.download_url.to_lowercase().starts_with("file:") => reqwest::Url::parse(&task.download_url)
+            .ok()
+            .and_then(|url| url.to_file_path().ok())
+            .ok_or_else(|| format!("Invalid file URL '{}'", task.download_url))?,
+        None => return Ok(None),
+    };
+
+    let bundle_dir = config.bundle_dir.as_deref()
+        .ok_or("Local files are disabled on this agent (no bundle_dir configured)")?;
+    let bundle_dir = std::path::Path::new(bundle_dir).canonicalize()
+        .map_err(|e| format!("bundle_dir {} is not accessible: {}", bundle_dir, e))?;
+    // Relative paths are taken from the bundle directory, absolute ones must point into it
+    let source = bundle_dir.join(&requested).canonicalize()
+        .map_err(|e| format!("Local file {} not found: {}", requested.display(), e))?;
+    if !source.starts_with(&bundle_dir) {
+        return Err(format!("Local file {} is outside of bundle_dir", requested.display()));
+    }
+    if !source.is_file() {
+        return Err(format!("Local file {} is not a regular file", requested.display()));
+    }
+    Ok(Some(source))
+}
+
+/// Only https (or http with `allow_insecure_downloads`) from an allowed host may be downloaded.
+fn check_download_url(url: &str, config: &AgentConfig) -> Result<(), String> {
+    check_url_allowed(url, config.allow_insecure_downloads, &config.allowed_download_hosts)
+}
+
+/// `check_download_url` on owned settings, for the redirect policy.
+fn check_url_allowed(url: &str, allow_insecure_downloads: bool, allowed_download_hosts: &[String]) -> Result<(), String> {
+    let parsed = reqwest::Url::parse(url).map_err(|e| format!("invalid URL '{}': {}", url, e))?;
+    match parsed.scheme() {
+        "https" => {},
+        "http" if allow_insecure_downloads => {},
+        "http" => return Err(format!("'{}' is not https (set allow_insecure_downloads to permit http)", url)),
+        scheme => return Err(format!("scheme '{}' is not allowed", scheme)),
+    }
+
+    let host = parsed.host_str().ok_or_else(|| format!("'{}' has no host", url))?.to_lowercase();
+    if allowed_download_hosts.is_empty() {
+        return Ok(());
+    }
+    let allowed = allowed_download_hosts.iter().any(|entry| {
+        let entry = entry.trim().to_lowercase();
+        match entry.strip_prefix("*.") {
+            Some(domain) => host.ends_with(&format!(".{}", domain)),
+            None => host == entry,
+        }
+    });
+    if allowed {
+        Ok(())
+    } else {
+        Err(format!("host '{}' is not in allowed_download_hosts", host))
+    }
+}
+
+/// Best-effort progress update. Failures are logged and otherwise ignored.
+async fn report_progress(task_id: i32, phase: &str, percent: u8, config: &AgentConfig, client: &reqwest::Client, machine_token: &Option<String>) {
+    debug!("Task {} {}: {}%", task_id, phase, percent);
+
+    let progress = ProgressRequest {
+        task_id,
+        phase: phase.to_string(),
+        percent,
+        mac_address: config.system_info.mac_address(),
+    };
+
+    let mut req = client.post(format!("{}/progress", active_backend(config)))
+        .header("X-Agent-Token", &config.auth_token)
+        .timeout(Duration::from_secs(10));
+    if let Some(request_id) = task_request_id(task_id) {
+        req = req.header("X-Request-Id", request_id);
+    }
+    req = signed_json(req, &progress, config);
+
+    if let Some(token) = machine_token {
+        req = req.header("X-Machine-Token", token);
+    }
+
+    match req.send().await.inspect(check_unsupported_encoding) {
+        Ok(resp) if !resp.status().is_success() => debug!("Progress report rejected with status: {}", resp.status()),
+        Err(e) => debug!("Failed to send progress report: {}", e),
+        _ => {}
+    }
+}
+
+/// Failures that aren't acked on the spot are returned as a `TaskError` and acked (or left for a
+/// later heartbeat) by the caller.
+async fn process_task(task: &Task, config: &AgentConfig, client: &reqwest::Client, machine_token: &Option<String>) -> Result<(), TaskError> {
+    info!("--- Processing Task: {} ---", task.task_type);
+    info!("Target: {}", task.software_name);
+
+    if task.task_type == "reboot" {
+        return Ok(process_reboot_task(task, config, client, machine_token).await?);
+    }
+
+    // Every other task type counts, so a reboot or restart waits for it
+    let _running = RunningTaskGuard::new();
+
+    if config.require_elevation && matches!(task.task_type.as_str(), "install" | "uninstall" | "rollback" | "package_manager") && !is_elevated() {
+        warn!("Refusing task {}: agent not running elevated.", task.id);
+        acknowledge(task.id, "failed", "agent not running elevated".to_string(), None, config, client, machine_token).await;
+        return Ok(());
+    }
+
+    if task.task_type == "service" {
+        return Ok(process_service_task(task, config, client, machine_token).await?);
+    }
+
+    // On Linux, an install/uninstall without anything to download names a distribution package
+    let distro_package = cfg!(target_os = "linux")
+        && (task.task_type == "install" || task.task_type == "uninstall")
+        && task.download_url.trim().is_empty()
+        && task.local_path.is_none();
+    if task.task_type == "package_manager" || distro_package {
+        return Ok(process_package_manager_task(task, config, client, machine_token).await?);
+    }
+
+    // A rollback runs the remembered command like an install
+    let resolved;
+    let task = if task.task_type == "rollback" {
+        let rollback = {
+            let _state = STATE_LOCK.lock().await;
+            resolve_rollback_task(task, config)
+        };
+        match rollback {
+            Ok(rollback) => {
+                resolved = rollback;
+                &resolved
+            },
+            Err(reason) => {
+                error!("Cannot roll back for task {}: {}", task.id, reason);
+                acknowledge(task.id, "failed", reason, None, config, client, machine_token).await;
+                return Ok(());
+            }
+        }
+    } else {
+        task
+    };
+
+    if task.task_type != "rollback" && task.rollback_url.is_some() {
+        let _state = STATE_LOCK.lock().await;
+        if let Err(e) = remember_rollback(config, task) {
+            warn!("Failed to store rollback information for task {}: {}", task.id, e);
+        }
+    }
+
+    if task.task_type == "script" && !config.allow_scripts {
+        warn!("Refusing script task {}: scripts are disabled on this agent.", task.id);
+        acknowledge(task.id, "failed", "Script tasks are disabled on this agent (allow_scripts = false)".to_string(), None, config, client, machine_token).await;
+        return Ok(());
+    }
+
+    // A verification script is a script too; refused before anything is installed
+    if task.verify_url.as_deref().is_some_and(|url| !url.trim().is_empty()) {
+        let refusal = if !config.allow_scripts {
+            Some("verify_url runs a script, but scripts are disabled on this agent (allow_scripts = false)")
+        } else if task.verify_sha256.as_deref().is_none_or(|sha| sha.trim().is_empty()) {
+            Some("verify_url requires verify_sha256")
+        } else {
+            None
+        };
+        if let Some(reason) = refusal {
+            warn!("Refusing task {}: {}", task.id, reason);
+            acknowledge(task.id, "failed", reason.to_string(), None, config, client, machine_token).await;
+            return Ok(());
+        }
+    }
+
+    if task.task_type == "registry" {
+        return Ok(process_registry_task(task, config, client, machine_token).await?);
+    }
+
+    if task.task_type == "agent_update" {
+        if let Err(reason) = check_agent_update(task) {
+            warn!("Refusing agent update: {}", reason);
+            acknowledge(task.id, "failed", reason, None, config, client, machine_token).await;
+            return Ok(());
+        }
+    }
+    
+    if task.task_type == "install" {
+        if let Some(spec) = &task.detect {
+            if let Some(found) = detect_installed(spec) {
+                info!("{} is already installed ({}). Skipping.", task.software_name, found);
+                acknowledge(task.id, "already_installed", format!("Already installed: {}", found), None, config, client, machine_token).await;
+                return Ok(());
+            }
+            info!("Detection did not match. Installing {}.", task.software_name);
+        }
+    }
+
+    if let Some(required_mb) = task.required_disk_mb {
+        if let Err(reason) = check_disk_space(required_mb, config) {
+            error!("Refusing task {}: {}", task.id, reason);
+            acknowledge(task.id, "failed", reason, None, config, client, machine_token).await;
+            return Ok(());
+        }
+    }
+
+    let headers = match build_download_headers(task, config) {
+        Ok(headers) => headers,
+        Err(reason) => {
+            error!("Invalid download headers for task {}: {}", task.id, reason);
+            acknowledge(task.id, "failed", reason, None, config, client, machine_token).await;
+            return Ok(());
+        }
+    };
+
+    let local_source = match resolve_local_source(task, config) {
+        Ok(source) => source,
+        Err(reason) => {
+            error!("Invalid local source for task {}: {}", task.id, reason);
+            acknowledge(task.id, "failed", reason, None, config, client, machine_token).await;
+            return Ok(());
+        }
+    };
+
+    // Uninstalls can go by the installed product alone; everything else needs its file
+    let has_source = local_source.is_some() || !task.download_url.trim().is_empty();
+    if !has_source && task.task_type != "uninstall" {
+        error!("Task {} ({}) has no download_url", task.id, task.task_type);
+        acknowledge(task.id, "failed", format!("{} tasks require a download_url", task.task_type), None, config, client, machine_token).await;
+        return Ok(());
+    }
+
+    // 1. Download (the staging directory is still needed without one, for the msiexec log and
+    // as the default working directory)
+    let tmp_dir = staging_dir(config)?;
+    // Fix: Remove query parameters from filename
+    let raw_name = task.download_url.split('/').next_back().unwrap_or("installer.exe");
+    let base_name = raw_name.split('?').next().unwrap_or("installer.exe");
+    
+
+    // Extract just the filename component
+    let file_name = local_source.as_deref().unwrap_or(std::path::Path::new(base_name))
+        .file_name()
+        .and_then(|os_str| os_str.to_str())
+        .unwrap_or("installer.exe");
+
+    let file_path = tmp_dir.path().join(file_name);
+
+    let cached = if has_source && local_source.is_none() { cached_download(task, config) } else { None };
+    if !has_source {
+        info!("No download_url. Uninstalling {} by its installed product.", task.software_name);
+    } else {
+        let mut file = File::create(&file_path)?;
+        // Oversized payloads, disallowed URLs and missing local files won't change by retrying,
+        // so they are permanent and reported right away
+        let result = match (&local_source, &cached) {
+            // Copied, so the bundle is never modified and cleanup works as for downloads
+            (Some(source), _) => {
+                info!("Copying pre-staged file {:?} to {:?}", source, file_path);
+                File::open(source).and_then(|mut source_file| std::io::copy(&mut source_file, &mut file))
+                    .map_err(|e| TaskError::Download { reason: format!("Failed to read pre-staged file {}: {}", source.display(), e), permanent: true })
+            },
+            // The URL must still be allowed, even if nothing is fetched from it
+            (None, Some(cached)) => check_download_url(&task.download_url, config)
+                .map_err(|reason| TaskError::Download { reason: DownloadUrlRejected { reason }.to_string(), permanent: true })
+                .and_then(|()| {
+                    info!("Using cached download {:?}", cached);
+                    File::open(cached).and_then(|mut cached_file| std::io::copy(&mut cached_file, &mut file))
+                        .map_err(|e| TaskError::Download { reason: format!("Failed to read cached download {}: {}", cached.display(), e), permanent: false })
+                }),
+            (None, None) => {
+                info!("Downloading from: {} to {:?}", task.download_url, file_path);
+                download_to_file(&task.download_url, &headers, Some(task.id), &mut file, config, client, machine_token).await
+                    .map_err(download_error)
+            },
+        };
+        let downloaded = match result {
+            Ok(downloaded) => downloaded,
+            Err(e) => {
+                drop(file);
+                let _ = std::fs::remove_file(&file_path);
+                return Err(e);
+            }
+        };
+        info!("Downloaded {} bytes.", downloaded);
+        
+        // Deployed files keep their default mode unless the task sets file_mode
+        #[cfg(target_os = "linux")]
+        if task.task_type != "file_deploy" {
+            let mut perms = file.metadata()?.permissions();
+            perms.set_mode(0o755);
+            file.set_permissions(perms)?;
+            info!("Set executable permissions for {:?}", file_path);
+        }
+        info!("Download complete.");
+    }
+
+    if let Some(expected) = task.sha256.as_ref().filter(|_| has_source) {
+        let actual = sha256_file(&file_path)?;
+        if !actual.eq_ignore_ascii_case(expected.trim()) {
+            return Err(TaskError::Checksum { expected: expected.trim().to_string(), actual });
+        }
+        info!("Checksum verified.");
+    }
+
+    // Uninstalls only run their file for packages; otherwise it just names the product
+    let runs_file = match task.task_type.as_str() {
+        "install" | "rollback" | "agent_update" => true,
+        "uninstall" => file_name.to_lowercase().ends_with(".msi") || is_appx_package(file_name),
+        _ => false,
+    };
+    if has_source && runs_file {
+        check_executable(&file_path, file_name, &task.task_type).map_err(TaskError::NotExecutable)?;
+    }
+
+    if has_source && local_source.is_none() && cached.is_none() {
+        if let Err(e) = store_in_cache(task, config, &file_path) {
+            warn!("Failed to cache the download of task {}: {}", task.id, e);
+        }
+    }
+
+    let dry_run = task.dry_run || config.dry_run;
+    if dry_run && matches!(task.task_type.as_str(), "agent_update" | "file_deploy") {
+        let message = if task.task_type == "agent_update" {
+            format!("Would replace the agent binary with version {}", task.version.as_deref().unwrap_or("unknown"))
+        } else {
+            format!("Would deploy {} to {}", file_name, task.destination_path.as_deref().unwrap_or("(no destination_path)"))
+        };
+        info!("Dry run of task {}: {}", task.id, message);
+        acknowledge(task.id, "dry_run", message, None, config, client, machine_token).await;
+        return Ok(());
+    }
+
+    if task.task_type == "agent_update" {
+        let new_version = task.version.clone().unwrap_or_default();
+        match replace_agent_binary(&file_path).map_err(|e| e.to_string()) {
+            Ok(()) => {
+                info!("Agent binary replaced with version {}. Restarting...", new_version);
+                acknowledge(task.id, "success", format!("Updated agent from {} to {}", env!("CARGO_PKG_VERSION"), new_version), None, config, client, machine_token).await;
+                drain_running_tasks().await;
+                // Already acked as a success: the new binary is in place and runs on the next start
+                if let Err(e) = restart_agent() {
+                    error!("Failed to restart the agent after the update to {}: {}", new_version, e);
+                    AGENT_RESTARTING.store(false, std::sync::atomic::Ordering::SeqCst);
+                }
+            },
+            Err(e) => {
+                error!("Agent update failed: {}", e);
+                acknowledge(task.id, "failed", format!("Agent update failed: {}", e), None, config, client, machine_token).await;
+            },
+        }
+        return Ok(());
+    }
+
+    if task.task_type == "file_deploy" {
+        let (ack_status, message) = match deploy_file(task, &file_path, tmp_dir.path(), config) {
+            Ok(message) => {
+                info!("Task Complete: {} ({})", task.software_name, message);
+                ("success", message)
+            },
+            Err(e) => {
+                error!("File deployment failed for {}: {}", task.software_name, e);
+                ("failed", format!("File deployment failed: {}", e))
+            },
+        };
+        acknowledge(task.id, ack_status, message, None, config, client, machine_token).await;
+        return Ok(());
+    }
+
+    // .pkg inside a disk image, run like a downloaded .pkg while the image stays mounted
+    #[cfg_attr(not(target_os = "macos"), allow(unused_mut))]
+    let mut inner_package: Option<std::path::PathBuf> = None;
+    #[cfg(target_os = "macos")]
+    let mut _mounted_image: Option<MountedImage> = None;
+
+    // Disk images are mounted and either their installer package runs, or their apps are copied
+    #[cfg(target_os = "macos")]
+    if task.task_type == "install" && file_name.to_lowercase().ends_with(".dmg") {
+        if dry_run {
+            let message = format!("Would mount {} and install its package or copy its applications to /Applications", file_name);
+            info!("Dry run of task {}: {}", task.id, message);
+            acknowledge(task.id, "dry_run", message, None, config, client, machine_token).await;
+            return Ok(());
+        }
+        let image = match MountedImage::attach(&file_path, tmp_dir.path()).map_err(|e| e.to_string()) {
+            Ok(image) => image,
+            Err(e) => {
+                error!("DMG installation failed for {}: {}", task.software_name, e);
+                acknowledge(task.id, "failed", format!("DMG installation failed: {}", e), None, config, client, machine_token).await;
+                return Ok(());
+            }
+        };
+        match find_volume_package(&image.mount_point) {
+            Some(package) => {
+                info!("Disk image contains installer package {:?}", package);
+                inner_package = Some(package);
+                _mounted_image = Some(image);
+            },
+            None => {
+                let (ack_status, message) = match copy_apps_from_volume(&image.mount_point) {
+                    Ok(installed) => {
+                        let message = format!("Installed {} to /Applications", installed.join(", "));
+                        info!("Task Complete: {} ({})", task.software_name, message);
+                        ("success", message)
+                    },
+                    Err(e) => {
+                        error!("DMG installation failed for {}: {}", task.software_name, e);
+                        ("failed", format!("DMG installation failed: {}", e))
+                    },
+                };
+                drop(image);
+                acknowledge(task.id, ack_status, message, None, config, client, machine_token).await;
+                return Ok(());
+            },
+        }
+    }
+
+    // 2. Install / Uninstall
+
+    let mut args: Vec<String> = split_args(&task.silent_args);
+    // Appended to the command line verbatim (msiexec wants PROPERTY="value", not "PROPERTY=value")
+    let mut raw_args: Vec<String> = Vec::new();
+    let mut command_path = file_path.clone();
+    // Registry uninstall string (or Linux package) the command was built from, reported by dry runs
+    let mut uninstall_string: Option<String> = None;
+    // Registry view the uninstall entry came from, reported in the ack
+    #[cfg_attr(not(target_os = "windows"), allow(unused_mut))]
+    let mut registry_view: Option<&str> = None;
+    // Owner of the user hive the uninstall entry came from; its uninstaller may expect that user's context
+    #[cfg_attr(not(target_os = "windows"), allow(unused_mut))]
+    let mut registry_user: Option<String> = None;
+    
+    if task.task_type == "uninstall" {
+        info!("Executing UNINSTALL...");
+
+        if file_name.to_lowercase().ends_with(".msi") {
+            // For MSI, we use msiexec /x <file> /qn
+            command_path = std::path::PathBuf::from("msiexec");
+            args = vec!["/x".to_string(), file_path.to_str().unwrap().to_string(), "/qn".to_string()];
+        } else if is_appx_package(file_name) {
+            info!("Detected MSIX/Appx package. Removing it by its manifest identity.");
+            command_path = std::path::PathBuf::from("powershell");
+            args = powershell_args(&appx_uninstall_script(&file_path, task.per_user));
+        } else {
+            // New Registry-Based Uninstall Logic
+            #[cfg(target_os = "windows")]
+            {
+                let candidates = match task.display_name.as_deref() {
+                    Some(display_name) => {
+                        let exact = find_exact_uninstall_candidates(display_name, task.display_version.as_deref());
+                        if exact.is_empty() && !task.fallback_fuzzy {
+                            warn!("No uninstall entry is exactly named '{}'.", display_name);
+                            acknowledge(task.id, "not_installed", format!("No installed product is named '{}'{}", display_name, version_suffix(task)), None, config, client, machine_token).await;
+                            return Ok(());
+                        }
+                        if exact.is_empty() {
+                            info!("No exact match for '{}'. Falling back to keyword matching.", display_name);
+                            find_uninstall_candidates(&task.software_name, task.match_hint.as_deref(), config)
+                        } else {
+                            exact
+                        }
+                    },
+                    None => find_uninstall_candidates(&task.software_name, task.match_hint.as_deref(), config),
+                };
+                let tied = candidates.iter().take_while(|candidate| candidate.score == candidates[0].score).count();
+                if tied > 1 {
+                    // Guessing could remove the wrong product (e.g. the x86 instead of the x64 runtime)
+                    let listed: Vec<String> = candidates.iter().map(|candidate| candidate.to_string()).collect();
+                    warn!("{} uninstall entries match {} equally well. Not uninstalling.", tied, task.software_name);
+                    acknowledge(task.id, "ambiguous_match", format!("{} entries match equally well, set match_hint to pick one: {}", tied, listed.join("; ")), None, config, client, machine_token).await;
+                    return Ok(());
+                }
+                let found = candidates.into_iter().next().map(|candidate| {
+                    registry_view = Some(candidate.view);
+                    registry_user = candidate.user_sid;
+                    candidate.command
+                });
+                if let Some(UninstallCommand::MsiProduct(product_code)) = &found {
+                    info!("Uninstalling Windows Installer product {}", product_code);
+                    command_path = std::path::PathBuf::from("msiexec");
+                    args = normalize_msi_uninstall_args(&["/x".to_string(), product_code.clone()], &task.silent_args);
+                    uninstall_string = found.as_ref().map(UninstallCommand::to_string);
+                } else if let Some(UninstallCommand::CommandLine(cmd)) = found {
+                    info!("Using Registry Uninstall Command: {}", cmd);
+                    // Split command into executable and args
+                    // This is tricky because the string might be "C:\Program Files\App\uninstall.exe" /S
+                    let (cmd_exe, cmd_args) = parse_command_string(&cmd);
+                    command_path = std::path::PathBuf::from(cmd_exe);
+                    
+                    // If it was a standard UninstallString (not quiet), append our silent args
+                    // But if it was QuietUninstallString, it might already have them. 
+                    // Implementation choice: Append user args to the registry command string.
+                    if is_msiexec(&command_path) {
+                         // "MsiExec.exe /I{GUID}": EXE-style silent args would break it
+                         args = normalize_msi_uninstall_args(&split_args(&cmd_args.join(" ")), &task.silent_args);
+                    } else {
+                         // Passed exactly as written: re-quoting /LOG="C:\a b\u.log" or the arguments
+                         // of rundll32 and cmd /c would change what the uninstaller sees
+                         args = Vec::new();
+                         raw_args = cmd_args;
+                         if !task.silent_args.trim().is_empty() {
+                             raw_args.push(task.silent_args.trim().to_string());
+                         }
+                    }
+                    uninstall_string = Some(cmd);
+                    
+                } else {
+                     warn!("Could not find uninstall command in registry for {}. Fallback to unsafe EXE?", task.software_name);
+                     return Err(TaskError::RegistryLookup(format!("Registry lookup failed for {}. Generic EXE uninstall unavailable.", task.software_name)));
+                }
+            }
+            #[cfg(not(target_os = "windows"))]
+            {
+                if !cfg!(target_os = "linux") {
+                    return Err(TaskError::Execute("Registry uninstall only supported on Windows".to_string()));
+                }
+                let package = match find_installed_package(task, config) {
+                    Ok(Some(package)) => package,
+                    Ok(None) if task.display_name.is_some() => {
+                        acknowledge(task.id, "not_installed", format!("No installed package is named '{}'{}", task.display_name.as_deref().unwrap_or_default(), version_suffix(task)), None, config, client, machine_token).await;
+                        return Ok(());
+                    },
+                    Ok(None) => return Err(TaskError::RegistryLookup(format!("Package lookup failed for {}. No installed package matches.", task.software_name))),
+                    Err(candidates) => {
+                        warn!("Several installed packages match {} equally well. Not uninstalling.", task.software_name);
+                        acknowledge(task.id, "ambiguous_match", candidates, None, config, client, machine_token).await;
+                        return Ok(());
+                    }
+                };
+                let (program, remove_args) = package_remove_command(&package.name)
+                    .ok_or_else(|| TaskError::Execute(format!("No package manager found to remove {}", package.name)))?;
+                info!("Removing package {} {} via {:?}", package.name, package.version, program);
+                command_path = program;
+                args = remove_args;
+                uninstall_string = Some(format!("{} {}", package.name, package.version));
+            }
+        }
+    } else if task.task_type == "script" {
+        // SCRIPT (content is deliberately never logged, it may contain secrets)
+        info!("Executing script {} with {} argument(s)", file_name, args.len());
+        let (interpreter, script_args) = script_command(&file_path, args).map_err(|e| TaskError::Execute(e.to_string()))?;
+        command_path = interpreter;
+        args = script_args;
+    } else {
+        // INSTALL
+        info!("Executing installer with args: {}", task.silent_args);
+
+        if file_name.to_lowercase().ends_with(".msp") || is_msi_patch(&file_path) {
+             info!("Detected MSI patch. Using msiexec /p.");
+             if !task.transforms.is_empty() {
+                 acknowledge(task.id, "failed", "Transforms can only be applied to .msi installs".to_string(), None, config, client, machine_token).await;
+                 return Ok(());
+             }
+             command_path = std::path::PathBuf::from("msiexec");
+             // msiexec /p <file> /qn REINSTALLMODE=omus REINSTALL=ALL <args>
+             let mut new_args = vec!["/p".to_string(), file_path.to_str().unwrap().to_string(), "/qn".to_string(), "REINSTALLMODE=omus".to_string(), "REINSTALL=ALL".to_string()];
+             new_args.extend(args);
+             args = new_args;
+        } else if file_name.to_lowercase().ends_with(".msi") {
+             info!("Detected MSI installer. Using msiexec.");
+             command_path = std::path::PathBuf::from("msiexec");
+             // msiexec /i <file> <args>
+             let mut new_args = vec!["/i".to_string(), file_path.to_str().unwrap().to_string()];
+             new_args.extend(args);
+             args = new_args;
+
+             if !task.transforms.is_empty() {
+                 let paths = download_transforms(task, &headers, tmp_dir.path(), config, client, machine_token).await?;
+                 let joined = paths.iter().map(|p| p.to_string_lossy()).collect::<Vec<_>>().join(";");
+                 info!("Applying {} transform(s)", paths.len());
+                 raw_args.push(format!("TRANSFORMS=\"{}\"", joined));
+             }
+        } else if is_appx_package(file_name) {
+             info!("Detected MSIX/Appx package. Using PowerShell.");
+             command_path = std::path::PathBuf::from("powershell");
+             args = powershell_args(&appx_install_script(&file_path, task.per_user));
+        } else if cfg!(target_os = "macos") && (file_name.to_lowercase().ends_with(".pkg") || inner_package.is_some()) {
+             info!("Detected macOS package. Using installer.");
+             let package = inner_package.as_ref().unwrap_or(&file_path);
+             command_path = std::path::PathBuf::from("/usr/sbin/installer");
+             // installer -pkg <file> -target / <args>
+             let mut new_args = vec!["-pkg".to_string(), package.to_str().unwrap().to_string(), "-target".to_string(), "/".to_string()];
+             new_args.extend(args);
+             args = new_args;
+        }
+    }
+
+    if !task.transforms.is_empty() && !is_msiexec(&command_path) {
+        acknowledge(task.id, "failed", "Transforms can only be applied to .msi installs".to_string(), None, config, client, machine_token).await;
+        return Ok(());
+    }
+
+    let msi_log = (config.msi_verbose_log && is_msiexec(&command_path)).then(|| tmp_dir.path().join("install.log"));
+    if let Some(log) = &msi_log {
+        args.push("/l*v".to_string());
+        args.push(log.to_string_lossy().to_string());
+    }
+
+    if !is_executable_allowed(config, &command_path) {
+        error!("Refusing to run {:?}: not in allowed_executables", command_path);
+        acknowledge(task.id, "failed", format!("Executable '{}' is not in the allowlist", command_path.display()), None, config, client, machine_token).await;
+        return Ok(());
+    }
+
+    if dry_run {
+        let mut message = format!("Would run: {}", display_command_line(&command_path, &args, &raw_args));
+        if let Some(uninstall_string) = &uninstall_string {
+            message.push_str(&format!("\nUninstall target: {}", uninstall_string));
+        }
+        if let Some(view) = registry_view {
+            message.push_str(&format!(" ({} registry view)", view));
+        }
+        if let Some(sid) = &registry_user {
+            message.push_str(&format!("\nPer-user install of {}", sid));
+        }
+        for (hook, command) in [("pre_command", &task.pre_command), ("post_command", &task.post_command), ("verify_command", &task.verify_command), ("verify_url", &task.verify_url)] {
+            if let Some(command) = command.as_deref().filter(|c| !c.trim().is_empty()) {
+                message.push_str(&format!("\n{}: {}", hook, command));
+            }
+        }
+        info!("Dry run of task {}: {}", task.id, message);
+        acknowledge(task.id, "dry_run", message, None, config, client, machine_token).await;
+        return Ok(());
+    }
+
+    let timeout = Duration::from_secs(task.timeout_secs.unwrap_or(config.task_timeout_secs));
+    let work_dir = match resolve_working_dir(task, config, tmp_dir.path()) {
+        Ok(dir) => dir,
+        Err(reason) => {
+            error!("Invalid working directory for task {}: {}", task.id, reason);
+            acknowledge(task.id, "failed", reason, None, config, client, machine_token).await;
+            return Ok(());
+        }
+    };
+    info!("Working directory: {:?}", work_dir);
+    let task_env = match build_task_env(task, tmp_dir.path(), &file_path) {
+        Ok(env) => env,
+        Err(reason) => {
+            error!("Invalid environment for task {}: {}", task.id, reason);
+            acknowledge(task.id, "failed", reason, None, config, client, machine_token).await;
+            return Ok(());
+        }
+    };
+    if !task_env.is_empty() {
+        // Names only, values may be secrets
+        info!("Setting environment variables: {:?}", task_env.keys().collect::<Vec<_>>());
+        if task.run_as_logged_on_user {
+            warn!("Task environment variables are not applied to processes launched in the user's session.");
+        }
+    }
+
+    if is_cancelled(task.id) {
+        info!("Task {} was cancelled during download", task.id);
+        acknowledge(task.id, "cancelled", "Cancelled by the backend before the installer started".to_string(), None, config, client, machine_token).await;
+        return Ok(());
+    }
+
+    let mut notes: Vec<String> = Vec::new();
+    let mut hook_outputs: Vec<String> = Vec::new();
+    if let Some(view) = registry_view {
+        notes.push(format!("{} registry view", view));
+    }
+    if let Some(sid) = &registry_user {
+        warn!("Uninstalling a per-user install of {} from the agent's context. Its uninstaller may not find the user's files.", sid);
+        notes.push(format!("per-user install of {}", sid));
+    }
+
+    if let Some(pre_command) = task.pre_command.as_deref().filter(|c| !c.trim().is_empty()) {
+        let (succeeded, note, hook_output) = run_hook("pre_command", pre_command, config, &work_dir, &task_env, timeout).await;
+        if !hook_output.is_empty() && !task.redact_output {
+            hook_outputs.push(format!("[pre_command]\n{}", hook_output));
+        }
+        if !succeeded && !task.ignore_pre_failure {
+            error!("Pre-install command failed for task {}: {}", task.id, note);
+            let output = Some(hook_outputs.join("\n")).filter(|o| !o.is_empty());
+            acknowledge(task.id, "failed", format!("Pre-install command failed: {}", note), output, config, client, machine_token).await;
+            return Ok(());
+        }
+        notes.push(note);
+    }
+
+    if !task.kill_processes.is_empty() {
+        let grace = Duration::from_secs(task.kill_timeout_secs.unwrap_or(10));
+        let killed = kill_blocking_processes(&task.kill_processes, grace).await;
+        if !killed.is_empty() {
+            notes.push(format!("killed: {}", killed.join(", ")));
+        }
+    }
+
+    let max_attempts = task.max_attempts.unwrap_or(task.max_retries.saturating_add(1)).max(1);
+    let mut attempts: Vec<String> = Vec::new();
+    let mut retry_delay = Duration::from_secs(task.retry_delay_secs);
+    let (mut ack_status, mut message, output) = loop {
+        let mut cmd = tokio::process::Command::new(&command_path);
+        cmd.args(&args).current_dir(&work_dir).envs(&task_env);
+        #[cfg(target_os = "windows")]
+        for raw in &raw_args {
+            cmd.raw_arg(raw);
+        }
+        #[cfg(not(target_os = "windows"))]
+        cmd.args(&raw_args);
+
+        let status = {
+            // Other tasks can download meanwhile; msiexec runs take turns
+            let _msi_lane = if is_msiexec(&command_path) { Some(MSI_LANE.lock().await) } else { None };
+            if task.run_as_logged_on_user {
+                match launch_in_user_session(&command_path, &args, &raw_args, &work_dir, timeout).await {
+                    Ok(UserSessionLaunch::Ran(outcome)) => Ok(outcome),
+                    Ok(UserSessionLaunch::NoUserSession) => {
+                        if task.defer_if_no_user {
+                            info!("No interactive user logged on. Deferring task {} to a later heartbeat.", task.id);
+                        } else {
+                            warn!("No interactive user logged on for task {}.", task.id);
+                            acknowledge(task.id, "no_user_session", "No interactive user session is active".to_string(), None, config, client, machine_token).await;
+                        }
+                        return Ok(());
+                    },
+                    Ok(UserSessionLaunch::Unavailable(reason)) => {
+                        warn!("Cannot launch in the user's session ({}). Running in the agent's own context.", reason);
+                        run_command(cmd, timeout, config.output_tail_bytes, cancellation(task.id)).await
+                    },
+                    Err(e) => Err(e),
+                }
+            } else {
+                run_command(cmd, timeout, config.output_tail_bytes, cancellation(task.id)).await
+            }
+        };
+        let output = status.as_ref().ok()
+            .map(|outcome| outcome.combined_output())
+            .filter(|output| !output.is_empty() && !task.redact_output);
+
+        let exit_code = status.as_ref().ok().and_then(|outcome| outcome.exit_status).and_then(|s| s.code());
+        let (ack_status, message) = match status {
+            Ok(ExecOutcome { cancelled: true, elapsed, .. }) => {
+                warn!("Task Cancelled: {} (killed after {}s)", task.software_name, elapsed.as_secs());
+                ("cancelled", format!("Cancelled by the backend, installer killed after running for {}s", elapsed.as_secs()))
+            },
+            Ok(ExecOutcome { exit_status: None, elapsed, .. }) => {
+                error!("Task Timed Out: {} (killed after {}s)", task.software_name, elapsed.as_secs());
+                TaskError::Timeout { elapsed_secs: elapsed.as_secs(), limit_secs: timeout.as_secs(), work_dir: work_dir.clone() }.ack()
+            },
+            Ok(ExecOutcome { exit_status: Some(exit_status), stderr, .. }) => {
+                if let Some((ack_status, message)) = exit_code_status(task, exit_status.code(), is_msiexec(&command_path)) {
+                    match ack_status {
+                        "success" => info!("Task Complete: {} ({})", task.software_name, message),
+                        _ => warn!("Task Complete: {} ({}: {})", task.software_name, ack_status, message),
+                    }
+                    (ack_status, message)
+                } else if is_appx_package(file_name) {
+                    // The cmdlets only ever exit with 1, the reason is what the script wrote to stderr
+                    let reason = stderr.lines().map(str::trim).find(|line| !line.is_empty()).filter(|_| !task.redact_output);
+                    error!("Task Failed: {} (PowerShell: {})", task.software_name, reason.unwrap_or("no error output"));
+                    TaskError::Execute(match reason {
+                        Some(reason) => format!("PowerShell error: {}", reason),
+                        None => format!("PowerShell exited with code {:?}", exit_status.code()),
+                    }).ack()
+                } else {
+                    error!("Task Failed: {} (Exit Code: {:?})", task.software_name, exit_status.code());
+                    TaskError::Execute(format!("Exit Code: {:?} (working directory: {})", exit_status.code(), work_dir.display())).ack()
+                }
+            },
+            Err(e) => {
+                if cfg!(target_os = "linux") && file_name.to_lowercase().ends_with(".exe") {
+                     if !config.simulate_exe_on_linux {
+                         error!("Cannot run .exe on Linux: {}", e);
+                         acknowledge(task.id, "failed", "cannot run .exe on this platform".to_string(), None, config, client, machine_token).await;
+                         return Ok(());
+                     }
+                     warn!("Cannot run .exe on Linux. Simulating success for verification (simulate_exe_on_linux).");
+                     ("success", "Simulated success on Linux".to_string())
+                } else {
+                     return Err(TaskError::Execute(format!("Failed to start {}: {}", command_path.display(), e)));
+                }
+            }
+        };
+
+        attempts.push(match exit_code {
+            Some(code) => format!("exit {}", code),
+            None => ack_status.to_string(),
+        });
+        if !is_retryable_result(task, ack_status, exit_code) || attempts.len() as u32 >= max_attempts {
+            break (ack_status, message, output);
+        }
+        warn!("Attempt {}/{} of task {} failed ({:?}). Retrying in {}s.", attempts.len(), max_attempts, task.id, exit_code, retry_delay.as_secs());
+        tokio::time::sleep(retry_delay).await;
+        if task.retry_backoff {
+            retry_delay = retry_delay.saturating_mul(2);
+        }
+    };
+    if max_attempts > 1 {
+        notes.push(format!("attempt {}/{}: {}", attempts.len(), max_attempts, attempts.join(", ")));
+    }
+
+    if ack_status.starts_with("success") {
+        if let Some(post_command) = task.post_command.as_deref().filter(|c| !c.trim().is_empty()) {
+            let (succeeded, note, hook_output) = run_hook("post_command", post_command, config, &work_dir, &task_env, timeout).await;
+            if !succeeded {
+                warn!("Post-install command failed for task {}: {}", task.id, note);
+            }
+            if !hook_output.is_empty() && !task.redact_output {
+                hook_outputs.push(format!("[post_command]\n{}", hook_output));
+            }
+            notes.push(note);
+        }
+
+        let verification = match verification_command(task, &headers, tmp_dir.path(), config, client, machine_token).await {
+            Some(Ok((program, args))) => Some(run_hook_program("verification", &program, &args, config, &work_dir, &task_env, timeout).await),
+            Some(Err(reason)) => Some((false, reason, String::new())),
+            None => None,
+        };
+        if let Some((succeeded, note, verify_output)) = verification {
+            if !verify_output.is_empty() && !task.redact_output {
+                hook_outputs.push(format!("[verification]\n{}", verify_output));
+            }
+            if succeeded {
+                notes.push(note);
+            } else {
+                error!("Verification failed for task {}: {}", task.id, note);
+                ack_status = "verification_failed";
+                message = format!("Installed, but verification failed: {}", note);
+            }
+        }
+    }
+
+    let message = if notes.is_empty() {
+        message
+    } else {
+        format!("{} ({})", message, notes.join(", "))
+    };
+    let output = if hook_outputs.is_empty() {
+        output
+    } else {
+        hook_outputs.extend(output);
+        Some(hook_outputs.join("\n"))
+    };
+
+    // Failures are diagnosed from the output, so put its tail right into the message
+    let message = match &output {
+        Some(output) if !ack_status.starts_with("success") => format!("{}\n--- Output (tail) ---\n{}", message, output),
+        _ => message,
+    };
+    // msiexec prints nothing useful; the reason is in its log around the first failing action
+    let message = match msi_log.as_deref().filter(|_| !ack_status.starts_with("success") && !task.redact_output).and_then(msi_log_excerpt) {
+        Some(excerpt) => format!("{}\n--- MSI log (Return value 3) ---\n{}", message, excerpt),
+        None => message,
+    };
+
+    // 3. Acknowledge
+    acknowledge(task.id, ack_status, message, output, config, client, machine_token).await;
+
+    // Successful tasks always clean up; failed ones may be kept for debugging
+    if config.keep_temp_on_failure && !ack_status.starts_with("success") {
+        let kept = tmp_dir.keep();
+        warn!("Keeping staging directory of failed task {} at {:?}", task.id, kept);
+    }
+
+    // 4. Reboot handling (after the ack, so the backend learns the result first)
+    if ack_status == "success_reboot_required" {
+        match config.reboot_policy.as_str() {
+            "auto" => {
+                // Same guards as a reboot task; this task's own guard is still alive
+                let others = RUNNING_TASKS.load(std::sync::atomic::Ordering::SeqCst).saturating_sub(1);
+                if !config.allow_reboot {
+                    warn!("Not rebooting after task {}: reboots are disabled on this machine.", task.id);
+                } else if others > 0 {
+                    warn!("Not rebooting after task {}: {} other task(s) still running.", task.id, others);
+                } else {
+                    info!("Reboot policy is 'auto'. Scheduling reboot...");
+                    if let Err(e) = schedule_reboot(60) {
+                        error!("Failed to schedule reboot: {}", e);
+                    }
+                }
+            },
+            "never" => {},
+            _ => warn!("A reboot is required to complete the installation of {}.", task.software_name),
+        }
+    }
+
+    Ok(())
+}
+
+/// Make sure the temp volume (and the configured install volume) have `required_mb` free.
+fn check_disk_space(required_mb: u64, config: &AgentConfig) -> Result<(), String> {
+    let mut volumes = vec![temp_root(config)];
+    if let Some(volume) = &config.install_volume {
+        volumes.push(std::path::PathBuf::from(volume));
+    }
+
+    for volume in volumes {
+        let available_mb = match fs2::available_space(&volume) {
+            Ok(bytes) => bytes / (1024 * 1024),
+            Err(e) => {
+                warn!("Could not determine free space on {:?}: {}", volume, e);
+                continue;
+            }
+        };
+        if available_mb < required_mb {
+            return Err(format!("insufficient disk space: need {} MB have {} MB (on {})", required_mb, available_mb, volume.display()));
+        }
+        info!("Free space on {:?}: {} MB (need {} MB)", volume, available_mb, required_mb);
+    }
+    Ok(())
+}
+
+/// Directory staging directories are created in: `temp_dir`, or the system temp directory.
+fn temp_root(config: &AgentConfig) -> std::path::PathBuf {
+    config.temp_dir.as_ref().map(std::path::PathBuf::from).unwrap_or_else(std::env::temp_dir)
+}
+
+/// Fresh per-task staging directory, removed when dropped.
+fn staging_dir(config: &AgentConfig) -> std::io::Result<tempfile::TempDir> {
+    tempfile::Builder::new().prefix("zldap_install_").tempdir_in(temp_root(config))
+}
+
+/// Warn at startup if installers won't be able to run from the temp directory.
+fn check_temp_dir(config: &AgentConfig) {
+    let root = temp_root(config);
+    if config.temp_dir.is_none() {
+        warn!("No temp_dir configured. Using the system temp directory {:?}.", root);
+    } else if let Err(e) = std::fs::create_dir_all(&root) {
+        error!("Cannot create temp_dir {:?}: {}", root, e);
+        return;
+    }
+
+    if let Err(e) = tempfile::tempfile_in(&root) {
+        error!("Temp directory {:?} is not writable: {}. Tasks will fail until this is fixed.", root, e);
+        return;
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Some(mount_point) = noexec_mount(&root) {
+        warn!("Temp directory {:?} is on a noexec mount ({:?}). Installers can't run from it; set temp_dir to an executable location.", root, mount_point);
+    }
+}
+
+/// The mount point containing `path`, if it is mounted noexec.
+#[cfg(target_os = "linux")]
+fn noexec_mount(path: &std::path::Path) -> Option<std::path::PathBuf> {
+    let path = path.canonicalize().ok()?;
+    let mounts = std::fs::read_to_string("/proc/mounts").ok()?;
+    // The longest mount point containing the path is the one it lives on
+    let (mount_point, options) = mounts.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            // Spaces in mount points are escaped as \040
+            let mount_point = std::path::PathBuf::from(fields.get(1)?.replace("\\040", " "));
+            Some((mount_point, fields.get(3)?.to_string()))
+        })
+        .filter(|(mount_point, _)| path.starts_with(mount_point))
+        .max_by_key(|(mount_point, _)| mount_point.as_os_str().len())?;
+    options.split(',').any(|option| option == "noexec").then_some(mount_point)
+}
+
+/// Ack status and message for an installer that exited with `code`, or None if it failed. Exit
+/// code 0 always succeeds; msiexec's reboot codes and 1642 (patch not applicable) only count for
+/// msiexec, other programs may use them for anything unless they are in `success_exit_codes`.
+fn exit_code_status(task: &Task, code: Option<i32>, msiexec: bool) -> Option<(&'static str, String)> {
+    let code = code?;
+    let reboot_code = REBOOT_REQUIRED_EXIT_CODES.contains(&code);
+    if code == 0 && task.task_type == "script" {
+        Some(("success", "Script completed successfully (Exit Code: 0)".to_string()))
+    } else if code == 0 {
+        Some(match task.rollback_of {
+            Some(rolled_back) => ("success", format!("Rolled back task {} successfully", rolled_back)),
+            None => ("success", "Installed successfully".to_string()),
+        })
+    } else if reboot_code && (msiexec || task.success_exit_codes.contains(&code)) {
+        Some(("success_reboot_required", format!("Installed successfully, reboot required (Exit Code: {})", code)))
+    } else if task.success_exit_codes.contains(&code) {
+        Some(("success", format!("Installed successfully (Exit Code: {}, listed in success_exit_codes)", code)))
+    } else if msiexec && code == MSI_PATCH_NOT_APPLICABLE_EXIT_CODE {
+        Some(("patch_not_applicable", format!("Patch does not apply to any installed product (Exit Code: {})", code)))
+    } else {
+        None
+    }
+}
+
+/// Whether another attempt may change a result: only failures, and not those with one of the
+/// task's `fatal_exit_codes`.
+fn is_retryable_result(task: &Task, ack_status: &str, exit_code: Option<i32>) -> bool {
+    ack_status == "failed" && !exit_code.is_some_and(|code| task.fatal_exit_codes.contains(&code))
+}
+
+/// Download a task's MSI transforms into the staging directory, verifying checksums where given.
+async fn download_transforms(task: &Task, headers: &HeaderMap, staging_dir: &std::path::Path, config: &AgentConfig, client: &reqwest::Client, machine_token: &Option<String>) -> Result<Vec<std::path::PathBuf>, TaskError> {
+    let mut paths = Vec::new();
+    for (index, transform) in task.transforms.iter().enumerate() {
+        let path = staging_dir.join(format!("transform_{}.mst", index + 1));
+        info!("Downloading transform from: {} to {:?}", transform.url, path);
+        let mut file = File::create(&path)?;
+        download_to_file(&transform.url, headers, None, &mut file, config, client, machine_token).await.map_err(download_error)?;
+        drop(file);
+
+        if let Some(expected) = &transform.sha256 {
+            let actual = sha256_file(&path)?;
+            if !actual.eq_ignore_ascii_case(expected.trim()) {
+                warn!("Checksum mismatch for transform {}", transform.url);
+                return Err(TaskError::Checksum { expected: expected.trim().to_string(), actual });
+            }
+        }
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// A .dmg attached below the staging directory, detached again when dropped.
+#[cfg(target_os = "macos")]
+struct MountedImage {
+    mount_point: std::path::PathBuf,
+}
+
+#[cfg(target_os = "macos")]
+impl MountedImage {
+    fn attach(image: &std::path::Path, staging_dir: &std::path::Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mount_point = staging_dir.join("mnt");
+        std::fs::create_dir_all(&mount_point)?;
+
+        let attach = Command::new("hdiutil")
+            .args(["attach", "-nobrowse", "-readonly", "-noautoopen", "-mountpoint"])
+            .arg(&mount_point)
+            .arg(image)
+            .output()?;
+        if !attach.status.success() {
+            return Err(format!("hdiutil attach failed: {}", String::from_utf8_lossy(&attach.stderr).trim()).into());
+        }
+        Ok(MountedImage { mount_point })
+    }
+}
+
+#[cfg(target_os = "macos")]
+impl Drop for MountedImage {
+    fn drop(&mut self) {
+        // Always detach, whatever happened while it was mounted
+        match Command::new("hdiutil").args(["detach", "-force"]).arg(&self.mount_point).output() {
+            Ok(detach) if detach.status.success() => {},
+            Ok(detach) => warn!("hdiutil detach failed: {}", String::from_utf8_lossy(&detach.stderr).trim()),
+            Err(e) => warn!("hdiutil detach failed: {}", e),
+        }
+    }
+}
+
+/// The installer package at the top level of a mounted volume, if any.
+#[cfg(target_os = "macos")]
+fn find_volume_package(volume: &std::path::Path) -> Option<std::path::PathBuf> {
+    let mut packages: Vec<std::path::PathBuf> = std::fs::read_dir(volume).ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| matches!(path.extension().and_then(|e| e.to_str()), Some("pkg") | Some("mpkg")))
+        .collect();
+    packages.sort();
+    packages.into_iter().next()
+}
+
+#[cfg(target_os = "macos")]
+fn copy_apps_from_volume(volume: &std::path::Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut installed = Vec::new();
+    for entry in std::fs::read_dir(volume)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("app") {
+            continue;
+        }
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let destination = std::path::Path::new("/Applications").join(&name);
+        if destination.exists() {
+            std::fs::remove_dir_all(&destination)?;
+        }
+        // ditto keeps symlinks, permissions and extended attributes (code signatures) intact
+        let copy = Command::new("ditto").arg(&path).arg(&destination).output()?;
+        if !copy.status.success() {
+            return Err(format!("Copying {} failed: {}", name, String::from_utf8_lossy(&copy.stderr).trim()).into());
+        }
+        info!("Copied {} to {:?}", name, destination);
+        installed.push(name);
+    }
+    if installed.is_empty() {
+        return Err("No .app bundle found in the disk image".into());
+    }
+    Ok(installed)
+}
+
+/// Terminate running processes whose executable name matches one of `names` (case-insensitive,
+/// ".exe" optional). They are asked to close first and killed if still running after `grace`.
+/// Returns "name (pid)" for every process that was terminated.
+async fn kill_blocking_processes(names: &[String], grace: Duration) -> Vec<String> {
+    use sysinfo::{ProcessesToUpdate, System};
+
+    fn normalize(name: &str) -> String {
+        let lower = name.trim().to_lowercase();
+        lower.strip_suffix(".exe").map(str::to_string).unwrap_or(lower)
+    }
+    let wanted: Vec<String> = names.iter().map(|n| normalize(n)).collect();
+    let own_pid = std::process::id();
+
+    let mut system = System::new();
+    system.refresh_processes(ProcessesToUpdate::All, true);
+    let targets: Vec<(sysinfo::Pid, String)> = system.processes().iter()
+        .filter(|(pid, _)| pid.as_u32() != own_pid)
+        .map(|(pid, process)| (*pid, process.name().to_string_lossy().to_string()))
+        .filter(|(_, name)| wanted.contains(&normalize(name)))
+        .collect();
+    if targets.is_empty() {
+        return Vec::new();
+    }
+
+    for (pid, name) in &targets {
+        info!("Asking {} (pid {}) to close", name, pid);
+        // WM_CLOSE on Windows, SIGTERM elsewhere
+        #[cfg(target_os = "windows")]
+        let _ = Command::new("taskkill").args(["/PID", &pid.to_string()]).output();
+        #[cfg(not(target_os = "windows"))]
+        if let Some(process) = system.process(*pid) {
+            process.kill_with(sysinfo::Signal::Term);
+        }
+    }
+
+    let pids: Vec<sysinfo::Pid> = targets.iter().map(|(pid, _)| *pid).collect();
+    async fn wait_for_exit(system: &mut System, pids: &[sysinfo::Pid], timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        loop {
+            system.refresh_processes(ProcessesToUpdate::Some(pids), true);
+            if pids.iter().all(|pid| system.process(*pid).is_none()) || Instant::now() >= deadline {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    }
+    wait_for_exit(&mut system, &pids, grace).await;
+
+    let mut escalated = false;
+    for (pid, name) in &targets {
+        if let Some(process) = system.process(*pid) {
+            warn!("{} (pid {}) did not close within {}s. Killing it.", name, pid, grace.as_secs());
+            process.kill();
+            escalated = true;
+        }
+    }
+    if escalated {
+        wait_for_exit(&mut system, &pids, Duration::from_secs(5)).await;
+    }
+
+    targets.into_iter().map(|(pid, name)| format!("{} ({})", name, pid)).collect()
+}
+
+/// Run a pre/post hook with the same parsing, environment, timeout and output capture as the installer.
+/// Returns whether it succeeded, a short summary (exit code) for the ack, and its output.
+async fn run_hook(name: &str, command_line: &str, config: &AgentConfig, work_dir: &std::path::Path, env: &HashMap<String, String>, timeout: Duration) -> (bool, String, String) {
+    let parts = split_args(command_line);
+    let Some((program, args)) = parts.split_first() else {
+        return (false, format!("{} is empty", name), String::new());
+    };
+    run_hook_program(name, std::path::Path::new(program), args, config, work_dir, env, timeout).await
+}
+
+/// Run an already split hook command; see `run_hook`.
+async fn run_hook_program(name: &str, program: &std::path::Path, args: &[String], config: &AgentConfig, work_dir: &std::path::Path, env: &HashMap<String, String>, timeout: Duration) -> (bool, String, String) {
+    if !is_executable_allowed(config, program) {
+        return (false, format!("{} executable '{}' is not in the allowlist", name, program.display()), String::new());
+    }
+
+    info!("Running {}: {}", name, program.display());
+    let mut cmd = tokio::process::Command::new(program);
+    cmd.args(args).current_dir(work_dir).envs(env);
+
+    match run_command(cmd, timeout, config.output_tail_bytes, None).await {
+        Ok(outcome) => match outcome.exit_status {
+            Some(status) => (status.success(), format!("{} exit code: {:?}", name, status.code()), outcome.combined_output()),
+            None => (false, format!("{} timed out after {}s", name, outcome.elapsed.as_secs()), outcome.combined_output()),
+        },
+        Err(e) => (false, format!("{} failed to start: {}", name, e), String::new()),
+    }
+}
+
+/// The task's post-install verification, if it has one: the script from `verify_url`, else `verify_command`.
+/// Errors are the reason the verifier could not be prepared.
+async fn verification_command(task: &Task, headers: &HeaderMap, staging_dir: &std::path::Path, config: &AgentConfig, client: &reqwest::Client, machine_token: &Option<String>) -> Option<Result<(std::path::PathBuf, Vec<String>), String>> {
+    let verify_url = task.verify_url.as_deref().filter(|u| !u.trim().is_empty());
+    let verify_command = task.verify_command.as_deref().filter(|c| !c.trim().is_empty());
+    let args = split_args(task.verify_args.as_deref().unwrap_or(""));
+
+    match (verify_url, verify_command) {
+        (Some(_), _) => Some(download_verifier(task, headers, staging_dir, args, config, client, machine_token).await
+            .map_err(|e| format!("verification script download failed: {}", e))),
+        (None, Some(command)) => Some(Ok((std::path::PathBuf::from(command), args))),
+        (None, None) => None,
+    }
+}
+
+/// Download `verify_url` next to the installer, check it against `verify_sha256` and build its
+/// invocation (see `script_command`).
+async fn download_verifier(task: &Task, headers: &HeaderMap, staging_dir: &std::path::Path, args: Vec<String>, config: &AgentConfig, client: &reqwest::Client, machine_token: &Option<String>) -> Result<(std::path::PathBuf, Vec<String>), Box<dyn std::error::Error>> {
+    let url = task.verify_url.as_deref().unwrap_or_default().trim();
+    let name = url.split('?').next().and_then(|u| u.rsplit('/').next()).filter(|n| !n.is_empty()).unwrap_or("verify");
+    let path = staging_dir.join(format!("verify_{}", name));
+    info!("Downloading verification script from: {} to {:?}", url, path);
+    let mut file = File::create(&path)?;
+    download_to_file(url, headers, None, &mut file, config, client, machine_token).await?;
+    drop(file);
+    let expected = task.verify_sha256.as_deref().unwrap_or_default().trim();
+    let actual = sha256_file(&path)?;
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(format!("checksum mismatch: expected {}, got {}", expected, actual).into());
+    }
+
+    #[cfg(unix)]
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+    script_command(&path, args)
+}
+
+/// Build the interpreter invocation for a downloaded script, based on its extension, unless the first
+/// of `args` names an interpreter (powershell, pwsh, bash, sh, cmd). The other `args` are passed to the script.
+fn script_command(script: &std::path::Path, mut args: Vec<String>) -> Result<(std::path::PathBuf, Vec<String>), Box<dyn std::error::Error>> {
+    let script_str = script.to_str().ok_or("Script path is not valid UTF-8")?.to_string();
+
+    // An interpreter named as the first silent arg wins over the file extension
+    let requested = args.first()
+        .map(|first| first.to_lowercase())
+        .filter(|first| ["powershell", "pwsh", "bash", "sh", "cmd"].contains(&first.as_str()));
+    if let Some(interpreter) = requested {
+        args.remove(0);
+        let mut full_args = interpreter_args(&interpreter, script_str);
+        full_args.extend(args);
+        return Ok((std::path::PathBuf::from(interpreter), full_args));
+    }
+
+    let extension = script.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    let interpreter = match extension.as_str() {
+        "ps1" => "powershell",
+        "bat" | "cmd" => "cmd",
+        _ if cfg!(target_os = "windows") => {
+            return Err(format!("Unsupported script type '.{}' on Windows", extension).into());
+        },
+        _ => {
+            // Respect a shebang, the file was already made executable after download
+            let mut head = [0u8; 2];
+            let has_shebang = std::io::Read::read_exact(&mut File::open(script)?, &mut head).is_ok() && &head == b"#!";
+            if has_shebang {
+                return Ok((script.to_path_buf(), args));
+            }
+            "/bin/sh"
+        },
+    };
+
+    let mut full_args = interpreter_args(interpreter, script_str);
+    full_args.extend(args);
+    Ok((std::path::PathBuf::from(interpreter), full_args))
+}
+
+/// Arguments that make `interpreter` run `script` non-interactively.
+fn interpreter_args(interpreter: &str, script: String) -> Vec<String> {
+    match interpreter {
+        "powershell" | "pwsh" => vec![
+            "-NoProfile".to_string(),
+            "-NonInteractive".to_string(),
+            "-ExecutionPolicy".to_string(),
+            "Bypass".to_string(),
+            "-File".to_string(),
+            script,
+        ],
+        "cmd" => vec!["/C".to_string(), script],
+        _ => vec![script],
+    }
+}
+
+/// Copy a downloaded payload to the task's `destination_path`.
+/// The file is written next to the destination first and renamed into place, so readers never see a partial file.
+/// Returns the ack message containing the final path and the file's SHA-256.
+fn deploy_file(task: &Task, source: &std::path::Path, staging_dir: &std::path::Path, config: &AgentConfig) -> Result<String, Box<dyn std::error::Error>> {
+    let destination = std::path::PathBuf::from(
+        task.destination_path.as_deref().ok_or("file_deploy task has no destination_path")?
+    );
+    if !destination.is_absolute() {
+        return Err(format!("destination_path must be absolute: {:?}", destination).into());
+    }
+    let file_name = destination.file_name().ok_or("destination_path has no file name")?;
+    let parent = destination.parent().ok_or("destination_path has no parent directory")?;
+
+    if !parent.exists() {
+        if task.create_dirs {
+            std::fs::create_dir_all(parent)?;
+            info!("Created directory {:?}", parent);
+        } else {
+            return Err(format!("Parent directory {:?} does not exist (set create_dirs to create it)", parent).into());
+        }
+    }
+
+    // Never write into our own staging area or state directory
+    let parent_canon = parent.canonicalize()?;
+    let staging_canon = staging_dir.canonicalize()?;
+    let state_canon = std::path::Path::new(&config.state_dir).canonicalize().ok();
+    let in_staging = parent_canon.starts_with(&staging_canon)
+        || parent_canon.components().any(|c| c.as_os_str().to_string_lossy().starts_with("zldap_install_"));
+    let in_state = state_canon.map(|state| parent_canon.starts_with(state)).unwrap_or(false);
+    if in_staging || in_state {
+        return Err(format!("Refusing to deploy into the agent's own staging/state area: {:?}", destination).into());
+    }
+
+    let destination = parent_canon.join(file_name);
+    if destination.exists() {
+        match task.overwrite.as_deref().unwrap_or("fail") {
+            "replace" => info!("Replacing existing file {:?}", destination),
+            "backup" => {
+                let mut backup = destination.clone().into_os_string();
+                backup.push(".bak");
+                std::fs::copy(&destination, &backup)?;
+                info!("Backed up existing file to {:?}", backup);
+            },
+            "fail" => return Err(format!("Destination {:?} already exists (overwrite policy: fail)", destination).into()),
+            other => return Err(format!("Unknown overwrite policy '{}'", other).into()),
+        }
+    }
+
+    let mut tmp_path = destination.clone().into_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = std::path::PathBuf::from(tmp_path);
+    std::fs::copy(source, &tmp_path)?;
+    if let Err(e) = std::fs::rename(&tmp_path, &destination) {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e.into());
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        if let Some(mode) = &task.file_mode {
+            let mode = u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+                .map_err(|_| format!("Invalid file_mode '{}'", mode))?;
+            std::fs::set_permissions(&destination, std::fs::Permissions::from_mode(mode))?;
+        }
+        if let Some(owner) = &task.file_owner {
+            let status = Command::new("chown").arg(owner).arg(&destination).status()?;
+            if !status.success() {
+                return Err(format!("chown {} failed with {:?}", owner, status.code()).into());
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    if task.file_mode.is_some() || task.file_owner.is_some() {
+        warn!("file_mode/file_owner are only supported on Unix. Ignoring.");
+    }
+
+    let sha256 = sha256_file(&destination)?;
+    Ok(format!("Deployed to {} (sha256: {})", destination.display(), sha256))
+}
+
+/// Apply the registry operations of a "registry" task and ack per-operation results.
+async fn process_registry_task(task: &Task, config: &AgentConfig, client: &reqwest::Client, machine_token: &Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+    if !cfg!(target_os = "windows") {
+        warn!("Registry task {} is not supported on this platform.", task.id);
+        acknowledge(task.id, "unsupported_platform", "Registry tasks are only supported on Windows".to_string(), None, config, client, machine_token).await;
+        return Ok(());
+    }
+
+    let operations = match &task.registry_operations {
+        Some(operations) => operations.clone(),
+        None => {
+            let headers = match build_download_headers(task, config) {
+                Ok(headers) => headers,
+                Err(reason) => {
+                    acknowledge(task.id, "failed", reason, None, config, client, machine_token).await;
+                    return Ok(());
+                }
+            };
+            let tmp_dir = staging_dir(config)?;
+            let file_path = tmp_dir.path().join("registry.json");
+            let mut file = File::create(&file_path)?;
+            download_to_file(&task.download_url, &headers, Some(task.id), &mut file, config, client, machine_token).await?;
+            match serde_json::from_str::<Vec<RegistryOperation>>(&std::fs::read_to_string(&file_path)?) {
+                Ok(operations) => operations,
+                Err(e) => {
+                    acknowledge(task.id, "failed", format!("Invalid registry operations file: {}", e), None, config, client, machine_token).await;
+                    return Ok(());
+                }
+            }
+        }
+    };
+
+    if task.dry_run || config.dry_run {
+        let planned: Vec<String> = operations.iter()
+            .map(|op| match &op.name {
+                Some(name) => format!("{} {} (value '{}')", op.action, op.key, name),
+                None => format!("{} {}", op.action, op.key),
+            })
+            .collect();
+        let message = format!("Would apply {} registry operation(s):\n{}", operations.len(), planned.join("\n"));
+        info!("Dry run of task {}: {}", task.id, message);
+        acknowledge(task.id, "dry_run", message, None, config, client, machine_token).await;
+        return Ok(());
+    }
+
+    info!("Applying {} registry operation(s)...", operations.len());
+    let (ack_status, message) = match apply_registry_operations(&operations) {
+        Ok(results) => ("success", results.join("\n")),
+        Err(results) => ("failed", results.join("\n")),
+    };
+    acknowledge(task.id, ack_status, message, None, config, client, machine_token).await;
+    Ok(())
+}
+
+/// Split `HKLM\Path\To\Key` into a normalized hive name and the subkey path.
+fn split_registry_path(key: &str) -> Result<(&'static str, &str), String> {
+    let key = key.trim().trim_matches('\\');
+    let (hive, path) = key.split_once('\\').unwrap_or((key, ""));
+    let hive = match hive.to_uppercase().as_str() {
+        "HKLM" | "HKEY_LOCAL_MACHINE" => "HKLM",
+        "HKCU" | "HKEY_CURRENT_USER" => "HKCU",
+        "HKU" | "HKEY_USERS" => "HKU",
+        "HKCR" | "HKEY_CLASSES_ROOT" => "HKCR",
+        other => return Err(format!("Unknown registry hive '{}'", other)),
+    };
+    if path.is_empty() {
+        return Err(format!("Refusing to operate on the root of {}", hive));
+    }
+    Ok((hive, path))
+}
+
+/// Encode JSON data as raw registry bytes for the given value type.
+fn encode_registry_value(value_type: &str, data: &serde_json::Value) -> Result<Vec<u8>, String> {
+    fn utf16_bytes(s: &str) -> Vec<u8> {
+        s.encode_utf16().chain(std::iter::once(0)).flat_map(|c| c.to_le_bytes()).collect()
+    }
+
+    match value_type.to_uppercase().as_str() {
+        "REG_SZ" | "REG_EXPAND_SZ" => {
+            let s = data.as_str().ok_or("expected a string")?;
+            Ok(utf16_bytes(s))
+        },
+        "REG_MULTI_SZ" => {
+            let items = data.as_array().ok_or("expected a list of strings")?;
+            let mut bytes = Vec::new();
+            for item in items {
+                bytes.extend(utf16_bytes(item.as_str().ok_or("expected a list of strings")?));
+            }
+            bytes.extend([0, 0]);
+            Ok(bytes)
+        },
+        "REG_DWORD" => {
+            let n = data.as_u64().ok_or("expected a non-negative number")?;
+            let n = u32::try_from(n).map_err(|_| "value does not fit in a DWORD")?;
+            Ok(n.to_le_bytes().to_vec())
+        },
+        "REG_QWORD" => {
+            let n = data.as_u64().ok_or("expected a non-negative number")?;
+            Ok(n.to_le_bytes().to_vec())
+        },
+        "REG_BINARY" => {
+            let s = data.as_str().ok_or("expected a hex string")?;
+            hex::decode(s.replace([' ', ','], "")).map_err(|e| format!("invalid hex data: {}", e))
+        },
+        other => Err(format!("Unsupported value type '{}'", other)),
+    }
+}
+
+/// Validate all operations up front, so malformed payloads never cause partial changes.
+fn validate_registry_operations(operations: &[RegistryOperation]) -> Result<(), String> {
+    for (i, op) in operations.iter().enumerate() {
+        split_registry_path(&op.key).map_err(|e| format!("Operation {}: {}", i + 1, e))?;
+        match op.action.as_str() {
+            "create_key" | "delete_key" => {},
+            "delete_value" => {
+                op.name.as_ref().ok_or(format!("Operation {}: delete_value requires a name", i + 1))?;
+            },
+            "set_value" => {
+                let value_type = op.value_type.as_deref().ok_or(format!("Operation {}: set_value requires a value_type", i + 1))?;
+                let data = op.data.as_ref().ok_or(format!("Operation {}: set_value requires data", i + 1))?;
+                encode_registry_value(value_type, data).map_err(|e| format!("Operation {}: {}", i + 1, e))?;
+            },
+            other => return Err(format!("Operation {}: unknown action '{}'", i + 1, other)),
+        }
+    }
+    Ok(())
+}
+
+/// Apply the operations in order. If one fails, the already applied ones are rolled back where possible
+/// (deleted keys cannot be restored). Returns the per-operation result lines.
+#[cfg(target_os = "windows")]
+fn apply_registry_operations(operations: &[RegistryOperation]) -> Result<Vec<String>, Vec<String>> {
+    use winreg::enums::RegType;
+    use winreg::RegValue;
+
+    enum Undo {
+        RestoreValue(HKEY, String, String, Option<RegValue>),
+        DeleteKey(HKEY, String),
+    }
+
+    fn hive_handle(hive: &str) -> HKEY {
+        match hive {
+            "HKCU" => HKEY_CURRENT_USER,
+            "HKU" => HKEY_USERS,
+            "HKCR" => HKEY_CLASSES_ROOT,
+            _ => HKEY_LOCAL_MACHINE,
+        }
+    }
+
+    fn reg_type(value_type: &str) -> RegType {
+        match value_type.to_uppercase().as_str() {
+            "REG_EXPAND_SZ" => RegType::REG_EXPAND_SZ,
+            "REG_MULTI_SZ" => RegType::REG_MULTI_SZ,
+            "REG_DWORD" => RegType::REG_DWORD,
+            "REG_QWORD" => RegType::REG_QWORD,
+            "REG_BINARY" => RegType::REG_BINARY,
+            _ => RegType::REG_SZ,
+        }
+    }
+
+    fn apply(op: &RegistryOperation, undo: &mut Vec<Undo>) -> std::io::Result<String> {
+        let (hive, path) = split_registry_path(&op.key)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let root = RegKey::predef(hive_handle(hive));
+        let name = op.name.clone().unwrap_or_default();
+
+        match op.action.as_str() {
+            "create_key" => {
+                let (_, disposition) = root.create_subkey(path)?;
+                if let RegDisposition::REG_CREATED_NEW_KEY = disposition {
+                    undo.push(Undo::DeleteKey(hive_handle(hive), path.to_string()));
+                    Ok("created".to_string())
+                } else {
+                    Ok("already exists".to_string())
+                }
+            },
+            "set_value" => {
+                let (key, disposition) = root.create_subkey(path)?;
+                if let RegDisposition::REG_CREATED_NEW_KEY = disposition {
+                    undo.push(Undo::DeleteKey(hive_handle(hive), path.to_string()));
+                }
+                let value_type = op.value_type.as_deref().unwrap_or("REG_SZ");
+                let bytes = encode_registry_value(value_type, op.data.as_ref().unwrap_or(&serde_json::Value::Null))
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+                let previous = key.get_raw_value(&name).ok();
+                key.set_raw_value(&name, &RegValue { bytes, vtype: reg_type(value_type) })?;
+                undo.push(Undo::RestoreValue(hive_handle(hive), path.to_string(), name, previous));
+                Ok("set".to_string())
+            },
+            "delete_value" => {
+                let key = match root.open_subkey_with_flags(path, KEY_ALL_ACCESS) {
+                    Ok(key) => key,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok("already absent".to_string()),
+                    Err(e) => return Err(e),
+                };
+                let previous = match key.get_raw_value(&name) {
+                    Ok(previous) => previous,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok("already absent".to_string()),
+                    Err(e) => return Err(e),
+                };
+                key.delete_value(&name)?;
+                undo.push(Undo::RestoreValue(hive_handle(hive), path.to_string(), name, Some(previous)));
+                Ok("deleted".to_string())
+            },
+            _ => {
+                // delete_key (validated beforehand); cannot be undone
+                match root.delete_subkey_all(path) {
+                    Ok(()) => Ok("deleted".to_string()),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok("already absent".to_string()),
+                    Err(e) => Err(e),
+                }
+            },
+        }
+    }
+
+    if let Err(e) = validate_registry_operations(operations) {
+        return Err(vec![format!("Validation failed, nothing applied: {}", e)]);
+    }
+
+    let mut results = Vec::new();
+    let mut undo = Vec::new();
+
+    for (i, op) in operations.iter().enumerate() {
+        let label = format!("{} {} {}", op.action, op.key, op.name.as_deref().unwrap_or("")).trim_end().to_string();
+        match apply(op, &mut undo) {
+            Ok(outcome) => {
+                info!("Registry operation {}: {} ({})", i + 1, label, outcome);
+                results.push(format!("[ok] {}: {}", label, outcome));
+            },
+            Err(e) => {
+                error!("Registry operation {} failed: {} ({})", i + 1, label, e);
+                results.push(format!("[failed] {}: {}", label, e));
+
+                // Roll back what we changed so far, newest first
+                for step in undo.into_iter().rev() {
+                    let rolled_back = match step {
+                        Undo::RestoreValue(hive, path, name, previous) => {
+                            RegKey::predef(hive).open_subkey_with_flags(&path, KEY_ALL_ACCESS).and_then(|key| match previous {
+                                Some(value) => key.set_raw_value(&name, &value),
+                                None => key.delete_value(&name),
+                            })
+                        },
+                        Undo::DeleteKey(hive, path) => RegKey::predef(hive).delete_subkey_all(&path),
+                    };
+                    if let Err(e) = rolled_back {
+                        warn!("Registry rollback step failed: {}", e);
+                    }
+                }
+                results.push(format!("Rolled back {} earlier operation(s) where possible", i));
+                return Err(results);
+            },
+        }
+    }
+
+    Ok(results)
+}
+
+#[cfg(not(target_os = "windows"))]
+fn apply_registry_operations(operations: &[RegistryOperation]) -> Result<Vec<String>, Vec<String>> {
+    if let Err(e) = validate_registry_operations(operations) {
+        return Err(vec![format!("Validation failed, nothing applied: {}", e)]);
+    }
+    Err(vec!["Registry operations are only supported on Windows".to_string()])
+}
+
+/// Hex-encoded SHA-256 of a file's contents.
+fn sha256_file(path: &std::path::Path) -> std::io::Result<String> {
+    use sha2::{Digest, Sha256};
+
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Name of a download in `cache_dir`: its expected SHA-256. Downloads without one aren't cached,
+/// what a URL serves may change at any time.
+fn cache_key(task: &Task) -> Option<String> {
+    task.sha256.as_deref().map(str::trim).filter(|sha256| !sha256.is_empty()).map(str::to_lowercase)
+}
+
+/// The cached copy of a task's download, if there is one and it still matches its checksum.
+fn cached_download(task: &Task, config: &AgentConfig) -> Option<std::path::PathBuf> {
+    let path = std::path::Path::new(config.cache_dir.as_deref()?).join(cache_key(task)?);
+    if !path.is_file() {
+        return None;
+    }
+    let expected = task.sha256.as_deref()?;
+    if !sha256_file(&path).is_ok_and(|actual| actual.eq_ignore_ascii_case(expected.trim())) {
+        warn!("Cached download {:?} is corrupt. Removing it.", path);
+        let _ = std::fs::remove_file(&path);
+        return None;
+    }
+    // The modification time is the "last used" time for evicting
+    if let Ok(file) = File::options().write(true).open(&path) {
+        let _ = file.set_modified(std::time::SystemTime::now());
+    }
+    Some(path)
+}
+
+/// Copy a verified download into `cache_dir` and evict the least recently used files beyond `cache_max_mb`.
+fn store_in_cache(task: &Task, config: &AgentConfig, file_path: &std::path::Path) -> std::io::Result<()> {
+    let (Some(cache_dir), Some(key)) = (config.cache_dir.as_deref().map(std::path::Path::new), cache_key(task)) else {
+        return Ok(());
+    };
+    std::fs::create_dir_all(cache_dir)?;
+    // Written under a per-task name first, so concurrent tasks never see half a file
+    let tmp_path = cache_dir.join(format!("{}.{}.tmp", key, task.id));
+    std::fs::copy(file_path, &tmp_path)?;
+    std::fs::rename(&tmp_path, cache_dir.join(&key))?;
+    debug!("Cached download of task {} as {}", task.id, key);
+
+    let mut files: Vec<(std::time::SystemTime, u64, std::path::PathBuf)> = std::fs::read_dir(cache_dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok().filter(|metadata| metadata.is_file())?;
+            Some((metadata.modified().ok()?, metadata.len(), entry.path()))
+        })
+        .collect();
+    files.sort();
+    let max_bytes = config.cache_max_mb.saturating_mul(1024 * 1024);
+    let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+    for (_, len, path) in files {
+        if total <= max_bytes {
+            break;
+        }
+        if std::fs::remove_file(&path).is_ok() {
+            info!("Evicted {:?} from the download cache", path);
+            total -= len;
+        }
+    }
+    Ok(())
+}
+
+/// Make sure an "agent_update" task is acceptable before downloading anything.
+fn check_agent_update(task: &Task) -> Result<(), String> {
+    if task.sha256.is_none() {
+        return Err("agent_update requires a sha256 checksum".to_string());
+    }
+    let target = task.version.as_deref().ok_or("agent_update requires a version")?;
+    let current = env!("CARGO_PKG_VERSION");
+    if version_at_least(current, target) {
+        return Err(format!("Refusing to update from {} to {} (not newer)", current, target));
+    }
+    Ok(())
+}
+
+/// Parse "1.2.3" (optionally prefixed with "v") into comparable numeric components.
+fn parse_version(version: &str) -> Vec<u64> {
+    version.trim().trim_start_matches('v')
+        .split(['.', '-', '+'])
+        .map_while(|part| part.parse().ok())
+        .collect()
+}
+
+/// Compares numerically, treating missing components as 0 ("1.2" == "1.2.0").
+fn version_at_least(actual: &str, minimum: &str) -> bool {
+    let mut actual = parse_version(actual);
+    let mut minimum = parse_version(minimum);
+    let len = actual.len().max(minimum.len());
+    actual.resize(len, 0);
+    minimum.resize(len, 0);
+    actual >= minimum
+}
+
+/// Returns a description of what was found if every check in `spec` matches.
+fn detect_installed(spec: &DetectSpec) -> Option<String> {
+    let min_version = spec.min_version.as_deref();
+    let mut found = Vec::new();
+
+    if let Some(name) = &spec.display_name {
+        found.push(detect_registry_entry(name, min_version)?);
+    }
+    if let Some(path) = &spec.file_path {
+        found.push(detect_file(std::path::Path::new(path), min_version)?);
+    }
+    if let Some(command) = &spec.command {
+        found.push(detect_command(command)?);
+    }
+    if let Some(package) = &spec.package {
+        let version = installed_package_version(package)?;
+        if min_version.is_some_and(|min| !version_at_least(&version, min)) {
+            return None;
+        }
+        found.push(format!("package {} {}", package, version));
+    }
+
+    if found.is_empty() {
+        warn!("Detection rule has no checks. Installing anyway.");
+        return None;
+    }
+    Some(found.join(", "))
+}
+
+#[cfg(target_os = "windows")]
+fn detect_registry_entry(name: &str, min_version: Option<&str>) -> Option<String> {
+    uninstall_entries().into_iter().find_map(|UninstallEntry { key: subkey, .. }| {
+        let display_name: String = subkey.get_value("DisplayName").unwrap_or_default();
+        if !display_name.trim().eq_ignore_ascii_case(name.trim()) {
+            return None;
+        }
+        let version: String = subkey.get_value("DisplayVersion").unwrap_or_default();
+        if min_version.is_some_and(|min| !version_at_least(&version, min)) {
+            info!("Found {} {}, but it is older than required", display_name, version);
+            return None;
+        }
+        Some(format!("{} {}", display_name, version).trim().to_string())
+    })
+}
+
+#[cfg(not(target_os = "windows"))]
+fn detect_registry_entry(_name: &str, _min_version: Option<&str>) -> Option<String> {
+    warn!("Registry detection is only supported on Windows");
+    None
+}
+
+fn detect_file(path: &std::path::Path, min_version: Option<&str>) -> Option<String> {
+    if !path.exists() {
+        return None;
+    }
+    let Some(min) = min_version else {
+        return Some(format!("{} exists", path.display()));
+    };
+    let version = file_version(path)?;
+    version_at_least(&version, min).then(|| format!("{} {}", path.display(), version))
+}
+
+#[cfg(target_os = "windows")]
+fn file_version(path: &std::path::Path) -> Option<String> {
+    let literal = path.to_str()?.replace('\'', "''");
+    let output = Command::new("powershell")
+        .args(["-NoProfile", "-NonInteractive", "-Command"])
+        .arg(format!("(Get-Item -LiteralPath '{}').VersionInfo.ProductVersion", literal))
+        .output()
+        .ok()?;
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !version.is_empty()).then_some(version)
+}
+
+#[cfg(not(target_os = "windows"))]
+fn file_version(_path: &std::path::Path) -> Option<String> {
+    warn!("File version detection is only supported on Windows");
+    None
+}
+
+fn detect_command(command: &str) -> Option<String> {
+    #[cfg(target_os = "windows")]
+    let output = Command::new("where").arg(command).output().ok()?;
+    #[cfg(not(target_os = "windows"))]
+    let output = Command::new("sh").args(["-c", "command -v \"$1\"", "sh", command]).output().ok()?;
+
+    let location = String::from_utf8_lossy(&output.stdout).lines().next().unwrap_or_default().trim().to_string();
+    (output.status.success() && !location.is_empty()).then_some(location)
+}
+
+#[cfg(target_os = "linux")]
+fn installed_package_version(package: &str) -> Option<String> {
+    if let Ok(output) = Command::new("dpkg-query").args(["-W", "-f=${Status}|${Version}", package]).output() {
+        let text = String::from_utf8_lossy(&output.stdout).to_string();
+        if let Some((status, version)) = text.split_once('|') {
+            if output.status.success() && status.ends_with(" installed") {
+                return Some(version.trim().to_string());
+            }
+        }
+    }
+    let output = Command::new("rpm").args(["-q", "--qf", "%{VERSION}", package]).output().ok()?;
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !version.is_empty()).then_some(version)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn installed_package_version(_package: &str) -> Option<String> {
+    warn!("Package detection is only supported on Linux");
+    None
+}
+
+/// Swap the running executable for `new_binary`.
+/// Unix: rename over the current file (atomic, the running process keeps its inode).
+/// Windows: the running exe is locked, so it is moved aside to `.old` first and deleted on next start.
+fn replace_agent_binary(new_binary: &std::path::Path) -> Result<(), Box<dyn std::error::Error>> {
+    let current_exe = std::env::current_exe()?;
+    let staged = current_exe.with_extension("new");
+
+    // Stage next to the current binary so the final rename stays on one filesystem
+    std::fs::copy(new_binary, &staged)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o755))?;
+        std::fs::rename(&staged, &current_exe)?;
+    }
+
+    #[cfg(windows)]
+    {
+        let backup = current_exe.with_extension("old");
+        let _ = std::fs::remove_file(&backup);
+        std::fs::rename(&current_exe, &backup)?;
+        if let Err(e) = std::fs::rename(&staged, &current_exe) {
+            // Put the old binary back so the agent still starts
+            let _ = std::fs::rename(&backup, &current_exe);
+            return Err(e.into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Set before a restart; no new tasks are started from then on.
+static AGENT_RESTARTING: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Stop starting tasks and wait (up to `RESTART_DRAIN_SECS`) until only the calling task is running,
+/// so a restart doesn't cut off an install halfway.
+async fn drain_running_tasks() {
+    const RESTART_DRAIN_SECS: u64 = 600;
+
+    AGENT_RESTARTING.store(true, std::sync::atomic::Ordering::SeqCst);
+    let deadline = Instant::now() + Duration::from_secs(RESTART_DRAIN_SECS);
+    loop {
+        // The caller's own guard is one of them
+        let others = RUNNING_TASKS.load(std::sync::atomic::Ordering::SeqCst).saturating_sub(1);
+        if others == 0 {
+            return;
+        }
+        if Instant::now() >= deadline {
+            warn!("{} task(s) still running after {}s. Restarting anyway.", others, RESTART_DRAIN_SECS);
+            return;
+        }
+        info!("Waiting for {} running task(s) to finish before restarting...", others);
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+/// Replace the current process with the (updated) agent binary.
+fn restart_agent() -> Result<(), Box<dyn std::error::Error>> {
+    let current_exe = std::env::current_exe()?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        // exec only returns on failure
+        let e = Command::new(&current_exe).args(&args).exec();
+        Err(e.into())
+    }
+
+    #[cfg(not(unix))]
+    {
+        // The new process has to be able to take over the lock
+        INSTANCE_LOCK.lock().unwrap().take();
+        Command::new(&current_exe).args(&args).spawn()?;
+        std::process::exit(0);
+    }
+}
+
+/// Held for the agent's lifetime so only one instance runs per machine: a named mutex on
+/// Windows, a locked PID file in `state_dir` elsewhere. Both are released by the OS on exit.
+struct InstanceLock {
+    #[cfg(target_os = "windows")]
+    mutex: isize,
+    #[cfg(not(target_os = "windows"))]
+    _file: File,
+}
+
+#[cfg(target_os = "windows")]
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        unsafe { windows_sys::Win32::Foundation::CloseHandle(self.mutex as windows_sys::Win32::Foundation::HANDLE) };
+    }
+}
+
+static INSTANCE_LOCK: Mutex<Option<InstanceLock>> = Mutex::new(None);
+
+fn acquire_instance_lock(config: &AgentConfig) -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(target_os = "windows")]
+    let lock = {
+        use windows_sys::Win32::Foundation::{CloseHandle, GetLastError, ERROR_ALREADY_EXISTS};
+        use windows_sys::Win32::System::Threading::CreateMutexW;
+
+        let _ = config;
+        let name: Vec<u16> = "Global\\ZE-SilentSync-Agent".encode_utf16().chain(std::iter::once(0)).collect();
+        let mutex = unsafe { CreateMutexW(std::ptr::null(), 0, name.as_ptr()) };
+        if mutex.is_null() {
+            return Err(format!("Failed to create the single-instance mutex: {}", std::io::Error::last_os_error()).into());
+        }
+        if unsafe { GetLastError() } == ERROR_ALREADY_EXISTS {
+            unsafe { CloseHandle(mutex) };
+            return Err("Another agent instance is already running. Exiting.".into());
+        }
+        InstanceLock { mutex: mutex as isize }
+    };
+
+    #[cfg(not(target_os = "windows"))]
+    let lock = {
+        use fs2::FileExt;
+        use std::io::Read;
+
+        std::fs::create_dir_all(&config.state_dir)?;
+        let path = std::path::Path::new(&config.state_dir).join("agent.lock");
+        let mut file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+        if file.try_lock_exclusive().is_err() {
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            return Err(format!("Another agent instance (PID {}) is already running ({:?} is locked). Exiting.", pid.trim(), path).into());
+        }
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        InstanceLock { _file: file }
+    };
+
+    *INSTANCE_LOCK.lock().unwrap() = Some(lock);
+    Ok(())
+}
+
+/// Remove the backup left behind by a previous self-update on Windows.
+fn cleanup_old_agent_binary() {
+    if let Ok(current_exe) = std::env::current_exe() {
+        let backup = current_exe.with_extension("old");
+        if backup.exists() {
+            match std::fs::remove_file(&backup) {
+                Ok(()) => info!("Removed previous agent binary {:?}", backup),
+                Err(e) => warn!("Failed to remove previous agent binary {:?}: {}", backup, e),
+            }
+        }
+    }
+}
+
+/// Number of tasks currently executing (every type except reboot).
+static RUNNING_TASKS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+/// Marks a task as running for as long as the guard is alive.
+struct RunningTaskGuard;
+
+impl RunningTaskGuard {
+    fn new() -> Self {
+        RUNNING_TASKS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        RunningTaskGuard
+    }
+}
+
+impl Drop for RunningTaskGuard {
+    fn drop(&mut self) {
+        RUNNING_TASKS.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+/// Counters exposed on the metrics endpoint.
+struct Metrics {
+    heartbeats_total: std::sync::atomic::AtomicU64,
+    heartbeat_failures_total: std::sync::atomic::AtomicU64,
+    bytes_downloaded_total: std::sync::atomic::AtomicU64,
+    /// Final task results by ack status
+    tasks_processed_total: Mutex<BTreeMap<String, u64>>,
+    /// Task failures by `TaskError::kind`
+    task_errors_total: Mutex<BTreeMap<&'static str, u64>>,
+    last_heartbeat_success: Mutex<Option<Instant>>,
+}
+
+static METRICS: Metrics = Metrics {
+    heartbeats_total: std::sync::atomic::AtomicU64::new(0),
+    heartbeat_failures_total: std::sync::atomic::AtomicU64::new(0),
+    bytes_downloaded_total: std::sync::atomic::AtomicU64::new(0),
+    tasks_processed_total: Mutex::new(BTreeMap::new()),
+    task_errors_total: Mutex::new(BTreeMap::new()),
+    last_heartbeat_success: Mutex::new(None),
+};
+
+/// Render the metrics in the Prometheus text exposition format.
+fn render_metrics() -> String {
+    use std::sync::atomic::Ordering;
+
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+        out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+        for (labels, value) in samples {
+            out.push_str(&format!("{}{} {}\n", name, labels, value));
+        }
+    };
+
+    metric("heartbeats_total", "counter", "Heartbeats sent to the backend.",
+        vec![(String::new(), METRICS.heartbeats_total.load(Ordering::Relaxed).to_string())]);
+    metric("heartbeat_failures_total", "counter", "Heartbeats that failed or were rejected.",
+        vec![(String::new(), METRICS.heartbeat_failures_total.load(Ordering::Relaxed).to_string())]);
+    metric("tasks_processed_total", "counter", "Finished tasks by result status.",
+        METRICS.tasks_processed_total.lock().unwrap().iter()
+            .map(|(status, count)| (format!("{{status=\"{}\"}}", status.replace('\\', "\\\\").replace('"', "\\\"")), count.to_string()))
+            .collect());
+    metric("task_errors_total", "counter", "Failed tasks by the stage that failed.",
+        METRICS.task_errors_total.lock().unwrap().iter()
+            .map(|(kind, count)| (format!("{{kind=\"{}\"}}", kind), count.to_string()))
+            .collect());
+    metric("bytes_downloaded_total", "counter", "Bytes downloaded for tasks.",
+        vec![(String::new(), METRICS.bytes_downloaded_total.load(Ordering::Relaxed).to_string())]);
+    metric("tasks_running", "gauge", "Tasks currently downloading or installing.",
+        vec![(String::new(), RUNNING_TASKS.load(Ordering::SeqCst).to_string())]);
+    // No sample until the first successful heartbeat
+    let since_success = METRICS.last_heartbeat_success.lock().unwrap()
+        .map(|at| (String::new(), format!("{:.3}", at.elapsed().as_secs_f64())));
+    metric("seconds_since_last_successful_heartbeat", "gauge", "Seconds since the backend last accepted a heartbeat.",
+        since_success.into_iter().collect());
+    out
+}
+
+/// Minimal HTTP server answering `GET /metrics`. Runs until the agent exits.
+async fn serve_metrics(bind: String, port: u16) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Running out of file descriptors fails every accept until connections close
+    const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(500);
+
+    let listener = match tokio::net::TcpListener::bind((bind.as_str(), port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to start metrics endpoint on {}:{}: {}", bind, port, e);
+            return;
+        }
+    };
+    info!("Serving metrics on {}:{}", bind, port);
+
+    loop {
+        let (mut stream, _) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Failed to accept metrics connection: {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            // Only the request line matters; give slow or idle clients a few seconds at most
+            let mut buf = [0u8; 1024];
+            let read = match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(read)) => read,
+                _ => return,
+            };
+            let request = String::from_utf8_lossy(&buf[..read]);
+            let mut request_line = request.lines().next().unwrap_or_default().split_whitespace();
+            let (status, body) = match (request_line.next(), request_line.next()) {
+                (Some("GET"), Some("/metrics")) => ("200 OK", render_metrics()),
+                _ => ("404 Not Found", "Not Found\n".to_string()),
+            };
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status, body.len(), body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+            let _ = stream.shutdown().await;
+        });
+    }
+}
+
+/// Restart the machine. The ack is sent before the reboot is initiated.
+async fn process_reboot_task(task: &Task, config: &AgentConfig, client: &reqwest::Client, machine_token: &Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+    if !config.allow_reboot {
+        warn!("Reboot task {} refused: reboots are disabled on this machine.", task.id);
+        acknowledge(task.id, "failed", "Reboots are disabled on this machine".to_string(), None, config, client, machine_token).await;
+        return Ok(());
+    }
+
+    let running = RUNNING_TASKS.load(std::sync::atomic::Ordering::SeqCst);
+    if running > 0 {
+        warn!("Reboot task {} refused: {} other task(s) still running.", task.id, running);
+        acknowledge(task.id, "failed", format!("Refusing to reboot while {} other task(s) are running", running), None, config, client, machine_token).await;
+        return Ok(());
+    }
+
+    let delay_secs = task.reboot_delay_secs
+        .or_else(|| task.silent_args.trim().parse().ok())
+        .unwrap_or(60);
+
+    if task.dry_run || config.dry_run {
+        let message = format!("Would reboot in {}s", delay_secs);
+        info!("Dry run of task {}: {}", task.id, message);
+        acknowledge(task.id, "dry_run", message, None, config, client, machine_token).await;
+        return Ok(());
+    }
+
+    info!("Rebooting in {}s...", delay_secs);
+    acknowledge(task.id, "success", format!("Reboot scheduled in {}s", delay_secs), None, config, client, machine_token).await;
+
+    if let Err(e) = schedule_reboot(delay_secs).map_err(|e| e.to_string()) {
+        error!("Failed to schedule reboot: {}", e);
+        // Correct the earlier ack
+        acknowledge(task.id, "failed", format!("Failed to schedule reboot: {}", e), None, config, client, machine_token).await;
+    }
+
+    Ok(())
+}
+
+/// Start/stop/restart/enable/disable a service and wait until it reaches the requested state.
+async fn process_service_task(task: &Task, config: &AgentConfig, client: &reqwest::Client, machine_token: &Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+    let (name, action) = match (&task.service_name, &task.service_action) {
+        (Some(name), Some(action)) if !name.trim().is_empty() => (name.trim().to_string(), action.to_lowercase()),
+        _ => {
+            acknowledge(task.id, "failed", "service task requires service_name and service_action".to_string(), None, config, client, machine_token).await;
+            return Ok(());
+        }
+    };
+
+    if !["start", "stop", "restart", "enable", "disable"].contains(&action.as_str()) {
+        acknowledge(task.id, "failed", format!("Unknown service action '{}'", action), None, config, client, machine_token).await;
+        return Ok(());
+    }
+
+    // Don't let a task cut the branch we're sitting on
+    if action != "start" && action != "enable" {
+        if let Some(own) = agent_service_name(config) {
+            let normalize = |s: &str| s.trim_end_matches(".service").to_lowercase();
+            if normalize(&own) == normalize(&name) {
+                warn!("Refusing to {} the agent's own service '{}'", action, name);
+                acknowledge(task.id, "failed", format!("Refusing to {} the agent's own service", action), None, config, client, machine_token).await;
+                return Ok(());
+            }
+        }
+    }
+
+    info!("Service task: {} '{}'", action, name);
+    let timeout = Duration::from_secs(task.timeout_secs.unwrap_or(120));
+    let mut outputs = Vec::new();
+    let mut failure = None;
+
+    // sc.exe has no restart, so stop, wait for STOPPED, then start
+    let phases: Vec<&str> = if cfg!(target_os = "windows") && action == "restart" {
+        vec!["stop", "start"]
+    } else {
+        vec![action.as_str()]
+    };
+
+    if task.dry_run || config.dry_run {
+        let commands: Vec<String> = phases.iter().map(|phase| service_command(&name, phase).join(" ")).collect();
+        let message = format!("Would run: {}", commands.join(", then "));
+        info!("Dry run of task {}: {}", task.id, message);
+        acknowledge(task.id, "dry_run", message, None, config, client, machine_token).await;
+        return Ok(());
+    }
+
+    for phase in phases {
+        let args = service_command(&name, phase);
+        let (program, rest) = args.split_first().expect("service command is never empty");
+        let mut cmd = tokio::process::Command::new(program);
+        cmd.args(rest);
+        match run_command(cmd, timeout, config.output_tail_bytes, None).await {
+            Ok(outcome) => {
+                let output = outcome.combined_output();
+                if !output.is_empty() {
+                    outputs.push(output);
+                }
+                match outcome.exit_status {
+                    Some(status) if status.success() => {},
+                    // 1062 = ERROR_SERVICE_NOT_ACTIVE, fine when stopping as part of a restart
+                    Some(status) if action == "restart" && status.code() == Some(1062) => {},
+                    Some(status) => {
+                        failure = Some(format!("'{}' exited with {:?}", args.join(" "), status.code()));
+                        break;
+                    },
+                    None => {
+                        failure = Some(format!("'{}' timed out", args.join(" ")));
+                        break;
+                    },
+                }
+            },
+            Err(e) => {
+                failure = Some(format!("Failed to run '{}': {}", args.join(" "), e));
+                break;
+            },
+        }
+
+        if phase == "stop" && action == "restart" {
+            if let Err(state) = wait_for_service_state(&name, false, timeout).await {
+                failure = Some(format!("Service did not stop within {}s (state: {})", timeout.as_secs(), state));
+                break;
+            }
+        }
+    }
+
+    let output = Some(outputs.join("\n")).filter(|o| !o.is_empty());
+    if let Some(reason) = failure {
+        error!("Service task failed: {}", reason);
+        let state = service_state(&name).await.unwrap_or_else(|| "unknown".to_string());
+        acknowledge(task.id, "failed", format!("{} (state: {})", reason, state), output, config, client, machine_token).await;
+        return Ok(());
+    }
+
+    let (ack_status, message) = match action.as_str() {
+        "start" | "restart" | "stop" => {
+            let want_running = action != "stop";
+            match wait_for_service_state(&name, want_running, timeout).await {
+                Ok(state) => ("success", format!("Service '{}' {}: state {}", name, action, state)),
+                Err(state) => ("timeout", format!("Service '{}' did not reach the requested state within {}s (state: {})", name, timeout.as_secs(), state)),
+            }
+        },
+        _ => ("success", format!("Service '{}' {}d", name, action)),
+    };
+    info!("{}", message);
+    acknowledge(task.id, ack_status, message, output, config, client, machine_token).await;
+    Ok(())
+}
+
+/// The agent's own service name: from config, or the systemd unit we were started from.
+fn agent_service_name(config: &AgentConfig) -> Option<String> {
+    if let Some(name) = &config.agent_service_name {
+        return Some(name.clone());
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        // e.g. "0::/system.slice/ze-silentsync-agent.service"
+        let cgroup = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+        cgroup.lines()
+            .flat_map(|line| line.rsplit('/'))
+            .find(|segment| segment.ends_with(".service"))
+            .map(|segment| segment.to_string())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    None
+}
+
+/// Command implementing a single service action on this platform.
+fn service_command(name: &str, action: &str) -> Vec<String> {
+    let parts: Vec<&str> = if cfg!(target_os = "windows") {
+        match action {
+            "enable" => vec!["sc", "config", name, "start=", "auto"],
+            "disable" => vec!["sc", "config", name, "start=", "disabled"],
+            _ => vec!["sc", action, name],
+        }
+    } else {
+        vec!["systemctl", action, name]
+    };
+    parts.into_iter().map(|p| p.to_string()).collect()
+}
+
+/// Current state of a service ("RUNNING"/"STOPPED"/... on Windows, "active"/"inactive"/... on Linux).
+async fn service_state(name: &str) -> Option<String> {
+    if cfg!(target_os = "windows") {
+        let output = tokio::process::Command::new("sc").args(["query", name]).output().await.ok()?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        // "        STATE              : 4  RUNNING"
+        stdout.lines()
+            .find(|line| line.trim_start().starts_with("STATE"))
+            .and_then(|line| line.split_whitespace().last())
+            .map(|state| state.to_string())
+    } else {
+        let output = tokio::process::Command::new("systemctl").args(["is-active", name]).output().await.ok()?;
+        Some(String::from_utf8_lossy(&output.stdout).trim().to_string()).filter(|s| !s.is_empty())
+    }
+}
+
+/// Poll the service state until it is running (or stopped). Returns the last seen state either way.
+async fn wait_for_service_state(name: &str, want_running: bool, timeout: Duration) -> Result<String, String> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let state = service_state(name).await.unwrap_or_else(|| "unknown".to_string());
+        let reached = match state.as_str() {
+            "RUNNING" | "active" => want_running,
+            "STOPPED" | "inactive" | "failed" => !want_running,
+            _ => false,
+        };
+        if reached {
+            return Ok(state);
+        }
+        if Instant::now() >= deadline {
+            return Err(state);
+        }
+        tokio::time::sleep(Duration::from_secs(2)).await;
+    }
+}
+
+/// Install, upgrade or remove a package through winget, Chocolatey or a Linux package manager.
+async fn process_package_manager_task(task: &Task, config: &AgentConfig, client: &reqwest::Client, machine_token: &Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+    // Plain install/uninstall tasks use software_name as the package
+    let implicit = task.task_type != "package_manager";
+    let manager = match task.manager.as_deref() {
+        Some(manager) => manager.trim().to_lowercase(),
+        None => detected_package_manager().unwrap_or_default().to_string(),
+    };
+    if manager.is_empty() && cfg!(target_os = "linux") {
+        warn!("No supported package manager (apt, dnf, zypper) found for task {}.", task.id);
+        acknowledge(task.id, "manager_not_available", "No supported package manager (apt, dnf, zypper) found".to_string(), None, config, client, machine_token).await;
+        return Ok(());
+    }
+    if matches!(manager.as_str(), "flatpak" | "snap") && !config.allow_flatpak_snap {
+        warn!("Refusing task {}: {} is disabled by allow_flatpak_snap.", task.id, manager);
+        acknowledge(task.id, "manager_not_available", format!("{} is disabled on this machine", manager), None, config, client, machine_token).await;
+        return Ok(());
+    }
+    let action = match task.package_action.as_deref() {
+        Some(action) => action.trim().to_lowercase(),
+        None if implicit => task.task_type.clone(),
+        None => "install".to_string(),
+    };
+    let package_id = task.package_id.as_deref().or(implicit.then_some(task.software_name.as_str()));
+    let mut package_id = match package_id.map(str::trim).filter(|id| !id.is_empty()) {
+        Some(package_id) => package_id.to_string(),
+        None => {
+            acknowledge(task.id, "failed", "package_manager task requires package_id".to_string(), None, config, client, machine_token).await;
+            return Ok(());
+        }
+    };
+    // Uninstalls by display name ("Google Chrome") are resolved to the installed package's name;
+    // if nothing matches, the package manager gets to report it
+    if implicit && action == "uninstall" {
+        match find_installed_package(task, config) {
+            Ok(Some(package)) => package_id = package.name,
+            Ok(None) if task.display_name.is_some() => {
+                acknowledge(task.id, "not_installed", format!("No installed package is named '{}'{}", task.display_name.as_deref().unwrap_or_default(), version_suffix(task)), None, config, client, machine_token).await;
+                return Ok(());
+            },
+            Ok(None) => {},
+            Err(candidates) => {
+                warn!("Several installed packages match {} equally well. Not uninstalling.", task.software_name);
+                acknowledge(task.id, "ambiguous_match", candidates, None, config, client, machine_token).await;
+                return Ok(());
+            }
+        }
+    }
+    let package_id = package_id.as_str();
+
+    let args = match package_manager_args(&manager, &action, package_id, task.version.as_deref()) {
+        Ok(args) => args,
+        Err(reason) => {
+            acknowledge(task.id, "failed", reason, None, config, client, machine_token).await;
+            return Ok(());
+        }
+    };
+
+    // A missing manager would otherwise surface as an unhelpful spawn error
+    let program = match locate_package_manager(&manager) {
+        Some(program) => program,
+        None => {
+            warn!("Package manager {} is not available on this machine.", manager);
+            acknowledge(task.id, "manager_not_available", format!("{} is not installed or not on PATH", manager), None, config, client, machine_token).await;
+            return Ok(());
+        }
+    };
+
+    if !is_executable_allowed(config, &program) {
+        error!("Refusing to run {:?}: not in allowed_executables", program);
+        acknowledge(task.id, "failed", format!("Executable '{}' is not in the allowlist", program.display()), None, config, client, machine_token).await;
+        return Ok(());
+    }
+
+    let command_line = display_command_line(&program, &args, &[]);
+    if task.dry_run || config.dry_run {
+        info!("Dry run of task {}: would run {}", task.id, command_line);
+        acknowledge(task.id, "dry_run", format!("Would run: {}", command_line), None, config, client, machine_token).await;
+        return Ok(());
+    }
+
+    info!("Package task: {} {} via {}", action, package_id, manager);
+    let timeout = Duration::from_secs(task.timeout_secs.unwrap_or(config.task_timeout_secs));
+    let mut lock_retry_delay = Duration::from_secs(15);
+    let mut attempt = 1;
+    let outcome = loop {
+        let mut cmd = tokio::process::Command::new(&program);
+        cmd.args(&args);
+        if manager == "apt" {
+            cmd.env("DEBIAN_FRONTEND", "noninteractive");
+        }
+        // winget and choco mostly run msiexec underneath
+        let msi_lane = if cfg!(target_os = "windows") { Some(MSI_LANE.lock().await) } else { None };
+        let result = run_command(cmd, timeout, config.output_tail_bytes, cancellation(task.id)).await;
+        drop(msi_lane);
+        let outcome = match result {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                error!("Failed to run {}: {}", command_line, e);
+                acknowledge(task.id, "failed", format!("Failed to run {}: {}", manager, e), None, config, client, machine_token).await;
+                return Ok(());
+            }
+        };
+
+        // Another process (unattended-upgrades, PackageKit, ...) holding the package database is
+        // usually done within a few minutes
+        let exit_code = outcome.exit_status.and_then(|status| status.code());
+        if attempt >= PACKAGE_LOCK_ATTEMPTS || !package_database_locked(&manager, exit_code, &outcome.combined_output()) {
+            break outcome;
+        }
+        warn!("{} package database is locked (attempt {}/{}). Retrying in {}s.", manager, attempt, PACKAGE_LOCK_ATTEMPTS, lock_retry_delay.as_secs());
+        tokio::time::sleep(lock_retry_delay).await;
+        lock_retry_delay = (lock_retry_delay * 2).min(Duration::from_secs(120));
+        attempt += 1;
+    };
+    let combined_output = outcome.combined_output();
+    let output = Some(combined_output.clone()).filter(|output| !output.is_empty() && !task.redact_output);
+
+    let (ack_status, message) = match outcome.exit_status {
+        _ if outcome.cancelled => ("cancelled", format!("Cancelled by the backend, {} killed", manager)),
+        None => ("timeout", format!("{} was killed after {}s", manager, outcome.elapsed.as_secs())),
+        Some(status) => match status.code() {
+            Some(code) if package_manager_output_status(&manager, code, &combined_output).is_some() => {
+                let (ack_status, meaning) = package_manager_output_status(&manager, code, &combined_output).unwrap_or_default();
+                (ack_status, meaning.to_string())
+            },
+            Some(0) => ("success", format!("{} {} {} succeeded", manager, action, package_id)),
+            Some(code) => match package_manager_exit_status(&manager, code) {
+                Some((ack_status, meaning)) => (ack_status, format!("{} (Exit Code: {})", meaning, code)),
+                None => ("failed", format!("{} {} {} failed (Exit Code: {})", manager, action, package_id, code)),
+            },
+            None => ("failed", format!("{} was terminated by a signal", manager)),
+        },
+    };
+    info!("Package task {}: {} ({})", task.id, ack_status, message);
+    acknowledge(task.id, ack_status, message, output, config, client, machine_token).await;
+    Ok(())
+}
+
+/// An installed distribution package matching a task's software name.
+struct PackageCandidate {
+    name: String,
+    version: String,
+    /// Matching keywords; exact name matches rank above every keyword match
+    score: usize,
+}
+
+/// The installed package an uninstall task means, found like `find_uninstall_candidates` does on
+/// Windows but in the dpkg or rpm database. Ties are an error listing the candidates (for an
+/// "ambiguous_match" ack).
+fn find_installed_package(task: &Task, config: &AgentConfig) -> Result<Option<PackageCandidate>, String> {
+    let mut candidates = match task.display_name.as_deref() {
+        // The package name plays the part of the DisplayName
+        Some(display_name) => {
+            let wanted = normalize_display_name(display_name);
+            let mut exact = find_installed_packages(display_name, None, config);
+            exact.retain(|candidate| {
+                normalize_display_name(&candidate.name) == wanted
+                    && task.display_version.as_deref().is_none_or(|version| candidate.version.eq_ignore_ascii_case(version.trim()))
+            });
+            exact
+        },
+        None => Vec::new(),
+    };
+    if task.display_name.is_none() || (candidates.is_empty() && task.fallback_fuzzy) {
+        candidates = find_installed_packages(&task.software_name, task.match_hint.as_deref(), config);
+    }
+    let tied = candidates.iter().take_while(|candidate| candidate.score == candidates[0].score).count();
+    if tied > 1 {
+        let listed: Vec<String> = candidates.iter()
+            .map(|candidate| format!("{} {} (score {})", candidate.name, candidate.version, candidate.score))
+            .collect();
+        return Err(format!("{} entries match equally well, set match_hint to pick one: {}", tied, listed.join("; ")));
+    }
+    Ok(candidates.into_iter().next())
+}
+
+/// (name, version) of every package in the dpkg or rpm database. None if neither is available.
+fn installed_packages() -> Option<Vec<(String, String)>> {
+    if !cfg!(target_os = "linux") {
+        return None;
+    }
+    let query = |program: &str, args: &[&str]| -> Option<String> {
+        let output = Command::new(find_in_path(program)?).args(args).output().ok()?;
+        output.status.success().then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+    };
+    if let Some(list) = query("dpkg-query", &["-W", "-f=${db:Status-Abbrev}\t${Package}\t${Version}\n"]) {
+        let installed = list.lines()
+            .filter_map(|line| {
+                let mut fields = line.split('\t');
+                let status = fields.next()?;
+                // "ii " = installed; removed packages with leftover config files are "rc "
+                status.starts_with("ii").then_some(())?;
+                Some((fields.next()?.to_string(), fields.next()?.to_string()))
+            })
+            .collect();
+        return Some(installed);
+    }
+    let list = query("rpm", &["-qa", "--qf", "%{NAME}\t%{VERSION}-%{RELEASE}\n"])?;
+    Some(list.lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(name, version)| (name.to_string(), version.to_string()))
+        .collect())
+}
+
+/// Installed packages whose name matches `software_name` exactly or by enough keywords, best first.
+fn find_installed_packages(software_name: &str, match_hint: Option<&str>, config: &AgentConfig) -> Vec<PackageCandidate> {
+    if !cfg!(target_os = "linux") {
+        return Vec::new();
+    }
+    let Some(installed) = installed_packages() else {
+        warn!("Neither dpkg-query nor rpm is available. Cannot look up installed packages.");
+        return Vec::new();
+    };
+
+    let keywords = extract_keywords(software_name, &config.extra_stop_words);
+    let software_name_clean = software_name.trim().to_lowercase();
+    let hint = match_hint.map(|hint| hint.trim().to_lowercase()).filter(|hint| !hint.is_empty());
+    info!("Searching installed packages for '{}' (Keywords: {:?}, hint: {:?})", software_name, keywords, hint);
+    let exact_score = exact_match_score(&keywords);
+
+    let mut candidates: Vec<PackageCandidate> = Vec::new();
+    for (name, version) in installed {
+        let name_lower = name.to_lowercase();
+        if hint.as_ref().is_some_and(|hint| !name_lower.contains(hint.as_str()) && !version.to_lowercase().contains(hint.as_str())) {
+            continue;
+        }
+        // "Google Chrome" is also accepted as "google-chrome"
+        let score = if name_lower == software_name_clean || name_lower == software_name_clean.replace(' ', "-") {
+            Some(exact_score)
+        } else {
+            keyword_score(&keywords, &name)
+        };
+        // Multi-arch packages are listed once per architecture
+        let Some(score) = score.filter(|_| !candidates.iter().any(|candidate| candidate.name == name)) else {
+            continue;
+        };
+        info!("Found Candidate Package '{}' {} (score: {})", name, version, score);
+        candidates.push(PackageCandidate { name, version, score });
+    }
+    candidates.sort_by_key(|candidate| std::cmp::Reverse(candidate.score));
+    candidates
+}
+
+/// Command removing an installed package: through the detected package manager, or rpm/dpkg directly.
+fn package_remove_command(package: &str) -> Option<(std::path::PathBuf, Vec<String>)> {
+    if let Some(manager) = detected_package_manager() {
+        let args = package_manager_args(manager, "uninstall", package, None).ok()?;
+        return Some((locate_package_manager(manager)?, args));
+    }
+    if let Some(rpm) = find_in_path("rpm") {
+        return Some((rpm, vec!["-e".to_string(), package.to_string()]));
+    }
+    find_in_path("dpkg").map(|dpkg| (dpkg, vec!["-r".to_string(), package.to_string()]))
+}
+
+/// How often a package manager run is attempted while the package database is locked.
+const PACKAGE_LOCK_ATTEMPTS: u32 = 6;
+
+/// Non-interactive command line arguments (without the program) for a package manager action.
+fn package_manager_args(manager: &str, action: &str, package_id: &str, version: Option<&str>) -> Result<Vec<String>, String> {
+    if !["install", "upgrade", "uninstall"].contains(&action) {
+        return Err(format!("Unknown package action '{}'", action));
+    }
+    let version = version.map(str::trim).filter(|v| !v.is_empty());
+    // Linux managers pin versions in the package spec, e.g. "curl=7.81.0-1" (apt)
+    let pinned = |separator: &str| match version {
+        Some(version) if action != "uninstall" => format!("{}{}{}", package_id, separator, version),
+        _ => package_id.to_string(),
+    };
+
+    let mut args: Vec<String> = match manager {
+        "winget" => {
+            let mut args = vec![action, "--id", package_id, "--exact", "--silent", "--disable-interactivity", "--accept-source-agreements"];
+            if action != "uninstall" {
+                args.push("--accept-package-agreements");
+            }
+            args.into_iter().map(String::from).collect()
+        },
+        // Enhanced exit codes report "nothing to do" as 2 instead of 0 (see `package_manager_exit_status`)
+        "choco" => [action, package_id, "-y", "--no-progress", "--use-enhanced-exit-codes"].iter().map(|arg| arg.to_string()).collect(),
+        "apt" => {
+            let mut args = vec!["-y".to_string(), "-o".to_string(), "Dpkg::Options::=--force-confdef".to_string(), "-o".to_string(), "Dpkg::Options::=--force-confold".to_string()];
+            match action {
+                "install" => args.push("install".to_string()),
+                "upgrade" => args.extend(["install".to_string(), "--only-upgrade".to_string()]),
+                _ => args.push("remove".to_string()),
+            }
+            args.push(pinned("="));
+            return Ok(args);
+        },
+        "dnf" => {
+            let verb = match action { "install" => "install", "upgrade" => "upgrade", _ => "remove" };
+            return Ok(vec![verb.to_string(), "-y".to_string(), pinned("-")]);
+        },
+        "zypper" => {
+            let verb = match action { "install" => "install", "upgrade" => "update", _ => "remove" };
+            return Ok(vec!["--non-interactive".to_string(), verb.to_string(), pinned("=")]);
+        },
+        // Flatpak and Snap track the newest release, `version` isn't used
+        "flatpak" => {
+            let mut args = match action {
+                "install" => vec!["install", "-y", "--noninteractive", "--system", "flathub"],
+                "upgrade" => vec!["update", "-y", "--noninteractive", "--system"],
+                _ => vec!["uninstall", "-y", "--noninteractive", "--system"],
+            };
+            args.push(package_id);
+            return Ok(args.into_iter().map(String::from).collect());
+        },
+        "snap" => {
+            let verb = match action { "install" => "install", "upgrade" => "refresh", _ => "remove" };
+            return Ok(vec![verb.to_string(), package_id.to_string()]);
+        },
+        "" => return Err("package_manager task requires manager".to_string()),
+        other => return Err(format!("Unsupported package manager '{}'", other)),
+    };
+    if let Some(version) = version {
+        args.push("--version".to_string());
+        args.push(version.to_string());
+    }
+    Ok(args)
+}
+
+/// The Linux package manager available on this machine, probed once.
+fn detected_package_manager() -> Option<&'static str> {
+    static DETECTED: std::sync::OnceLock<Option<&'static str>> = std::sync::OnceLock::new();
+    *DETECTED.get_or_init(|| {
+        if !cfg!(target_os = "linux") {
+            return None;
+        }
+        ["apt", "dnf", "zypper"].into_iter().find(|manager| locate_package_manager(manager).is_some())
+    })
+}
+
+/// Whether a package manager run failed only because another process holds the package database.
+fn package_database_locked(manager: &str, exit_code: Option<i32>, output: &str) -> bool {
+    match manager {
+        "apt" => exit_code == Some(100) && (output.contains("Could not get lock") || output.contains("Unable to acquire the dpkg")),
+        "dnf" => exit_code == Some(1) && (output.contains("holding the yum lock") || output.contains("Waiting for process with pid")),
+        // ZYPPER_EXIT_ZYPP_LOCKED
+        "zypper" => exit_code == Some(7),
+        _ => false,
+    }
+}
+
+/// Find the package manager executable ("apt" is apt-get). winget lives in WindowsApps, which isn't on the
+/// SYSTEM account's PATH.
+fn locate_package_manager(manager: &str) -> Option<std::path::PathBuf> {
+    let program = if manager == "apt" { "apt-get" } else { manager };
+    if let Some(path) = find_in_path(program) {
+        return Some(path);
+    }
+
+    if cfg!(target_os = "windows") {
+        match manager {
+            "winget" => {
+                let program_files = std::env::var_os("ProgramFiles").unwrap_or_else(|| "C:\\Program Files".into());
+                let apps = std::path::Path::new(&program_files).join("WindowsApps");
+                let mut candidates: Vec<std::path::PathBuf> = std::fs::read_dir(apps).ok()?
+                    .filter_map(|entry| entry.ok())
+                    .filter(|entry| entry.file_name().to_string_lossy().starts_with("Microsoft.DesktopAppInstaller_"))
+                    .map(|entry| entry.path().join("winget.exe"))
+                    .filter(|path| path.is_file())
+                    .collect();
+                // Highest version last
+                candidates.sort();
+                return candidates.pop();
+            },
+            "choco" => {
+                let program_data = std::env::var_os("ProgramData").unwrap_or_else(|| "C:\\ProgramData".into());
+                let choco = std::path::Path::new(&program_data).join("chocolatey").join("bin").join("choco.exe");
+                return choco.is_file().then_some(choco);
+            },
+            _ => {},
+        }
+    }
+    None
+}
+
+/// Capabilities reported in the heartbeat: task types and installer formats this build supports,
+/// features enabled in the config, and tools found on this machine.
+fn agent_capabilities(config: &AgentConfig) -> Vec<String> {
+    let mut capabilities: Vec<&str> = vec![
+        "install", "uninstall", "rollback", "file_deploy", "agent_update", "reboot", "service",
+        "package_manager", "cancel", "progress", "depends_on", "dry_run", "recent_results",
+    ];
+    if cfg!(target_os = "windows") {
+        capabilities.extend(["msi", "msp", "msix", "registry", "user_session"]);
+    }
+    if cfg!(target_os = "macos") {
+        capabilities.extend(["pkg", "dmg"]);
+    }
+    if config.allow_scripts {
+        capabilities.push("script");
+    }
+    if config.inventory_enabled {
+        capabilities.push("inventory");
+        if config.inventory_delta {
+            capabilities.push("inventory_delta");
+        }
+    }
+    if config.long_poll {
+        capabilities.push("long_poll");
+    }
+    if config.request_compression != "off" {
+        capabilities.push("gzip_requests");
+    }
+    let managers: &[&str] = if cfg!(target_os = "windows") {
+        &["winget", "choco"]
+    } else if config.allow_flatpak_snap {
+        &["apt", "dnf", "zypper", "flatpak", "snap"]
+    } else {
+        &["apt", "dnf", "zypper"]
+    };
+    capabilities.extend(managers.iter().copied().filter(|manager| locate_package_manager(manager).is_some()));
+    // The same check sd_booted() does
+    if cfg!(target_os = "linux") && std::path::Path::new("/run/systemd/system").is_dir() {
+        capabilities.push("systemd");
+    }
+    capabilities.into_iter().map(String::from).collect()
+}
+
+/// Search PATH for an executable (trying PATHEXT extensions on Windows).
+fn find_in_path(name: &str) -> Option<std::path::PathBuf> {
+    let extensions: Vec<String> = if cfg!(target_os = "windows") {
+        std::env::var("PATHEXT").unwrap_or_else(|_| ".EXE;.CMD;.BAT".to_string())
+            .split(';').map(|ext| ext.to_lowercase()).collect()
+    } else {
+        vec![String::new()]
+    };
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .flat_map(|dir| extensions.iter().map(move |ext| dir.join(format!("{}{}", name, ext))))
+        .find(|path| path.is_file())
+}
+
+/// Documented non-zero exit codes that aren't plain failures, as ack status and meaning.
+fn package_manager_exit_status(manager: &str, code: i32) -> Option<(&'static str, &'static str)> {
+    match (manager, code) {
+        // APPINSTALLER_CLI_ERROR_* HRESULTs, reported as negative exit codes
+        ("winget", -1978335189) => Some(("already_installed", "No applicable update found")),
+        ("winget", -1978335135) => Some(("already_installed", "Package is already installed")),
+        ("winget", -1978335212) => Some(("package_not_found", "No package found matching the id")),
+        ("winget", -1978334967) => Some(("success_reboot_required", "Installed successfully, reboot required")),
+        ("winget", -1978334975) => Some(("failed", "The application is currently running")),
+        ("winget", -1978334974) => Some(("failed", "Another installation is already in progress")),
+        // Enhanced exit codes: nothing to do
+        ("choco", 2) => Some(("already_installed", "Nothing to do, package is already in the requested state")),
+        ("choco", 1641) | ("choco", 3010) => Some(("success_reboot_required", "Installed successfully, reboot required")),
+        ("zypper", 102) => Some(("success_reboot_required", "Installed successfully, reboot required")),
+        ("zypper", 104) => Some(("package_not_found", "No package found matching the name")),
+        _ => None,
+    }
+}
+
+/// Outcomes Flatpak and Snap only report as text, as ack status and meaning. Depending on the
+/// version, "already installed" comes with exit code 0 or 1.
+fn package_manager_output_status(manager: &str, code: i32, output: &str) -> Option<(&'static str, &'static str)> {
+    if !matches!(manager, "flatpak" | "snap") {
+        return None;
+    }
+    let output = output.to_lowercase();
+    if output.contains("already installed") {
+        Some(("already_installed", "Package is already installed"))
+    } else if output.contains("has no updates available") || (manager == "flatpak" && output.contains("nothing to do")) {
+        Some(("already_installed", "No applicable update found"))
+    } else if code != 0 && (output.contains("nothing matches") || output.contains("no remote refs found") || output.contains("not found")) {
+        Some(("package_not_found", "No package found matching the id"))
+    } else {
+        None
+    }
+}
+
+/// Serializes read-modify-write access to the ack queue and completed task files between concurrent tasks
+static STATE_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Persist an acknowledgement and try to deliver it (plus anything still queued).
+async fn acknowledge(task_id: i32, status: &str, message: String, output: Option<String>, config: &AgentConfig, client: &reqwest::Client, machine_token: &Option<String>) {
+    let ack = AckRequest::new(task_id, status, message, output, config.system_info.as_ref());
+    let interim = is_interim_status(status);
+    if !interim {
+        *METRICS.tasks_processed_total.lock().unwrap().entry(ack.status.clone()).or_insert(0) += 1;
+    }
+
+    // Persist first, so the result survives a failed ack or an agent restart
+    {
+        let _state = STATE_LOCK.lock().await;
+        if let Err(e) = enqueue_ack(config, &ack) {
+            error!("Failed to persist acknowledgement for task {}: {}", task_id, e);
+        }
+        // Interim acks report progress, the task still has to run
+        if !interim {
+            if let Err(e) = record_completed_task(config, &ack) {
+                error!("Failed to record completion of task {}: {}", task_id, e);
+            }
+            if let Err(e) = unschedule_task(config, task_id) {
+                error!("Failed to remove task {} from the schedule: {}", task_id, e);
+            }
+            TASK_REQUEST_IDS.lock().unwrap().remove(&task_id);
+        }
+    }
+    flush_ack_queue(config, client, machine_token).await;
+}
+
+/// Statuses that announce a later result instead of being one.
+fn is_interim_status(status: &str) -> bool {
+    // "agent_outdated" tasks run once the agent is updated and the backend sends them again
+    matches!(status, "scheduled" | "agent_outdated" | "waiting_for_window" | "deferred" | "pending_reboot" | "deferred_reboot_pending" | "waiting_on_dependency")
+}
+
+/// Why a task can't start yet, as the interim ack status and message: its `run_at` is in the
+/// future, it waits for the maintenance window, or it needs a reboot to happen first.
+fn hold_reason(task: &Task, config: &AgentConfig) -> Option<(&'static str, String)> {
+    if let Some(run_at) = task.run_at.filter(|run_at| *run_at > chrono::Utc::now()) {
+        return Some(("scheduled", format!("Scheduled for {}", run_at.to_rfc3339())));
+    }
+    // `maintenance_windows` defer tasks, the older single window only makes flagged ones wait
+    let (status, windows, bound) = if config.maintenance_windows.is_empty() {
+        ("waiting_for_window", config.maintenance_window.as_slice(), task.respect_maintenance_window)
+    } else {
+        ("deferred", config.maintenance_windows.as_slice(), task.respect_maintenance_window || !config.maintenance_windows_flagged_only)
+    };
+    let now = chrono::Local::now();
+    if bound && !windows.is_empty() && !windows.iter().any(|window| window.contains(now)) {
+        let message = match windows.iter().filter_map(|window| window.next_opening(now)).min() {
+            Some(opening) => format!("Waiting for the maintenance window opening at {}", opening.to_rfc3339()),
+            None => "Waiting for the maintenance window".to_string(),
+        };
+        return Some((status, message));
+    }
+    let reboot_first = match task.require_no_pending_reboot {
+        Some(required) => required.then_some(("pending_reboot", "Deferred until the pending reboot has happened")),
+        None => (config.defer_on_pending_reboot && matches!(task.task_type.as_str(), "install" | "rollback"))
+            .then_some(("deferred_reboot_pending", "Deferred until the pending reboot has happened (defer_on_pending_reboot)")),
+    };
+    if let Some((status, message)) = reboot_first.filter(|_| pending_reboot()) {
+        return Some((status, message.to_string()));
+    }
+    None
+}
+
+const SCHEDULED_TASKS_FILE: &str = "scheduled_tasks.json";
+
+/// Tasks waiting for their `run_at` or the maintenance window. They stay in the file until their final ack, so a
+/// restart re-runs anything that was due but hadn't finished.
+fn load_scheduled_tasks(config: &AgentConfig) -> Vec<Task> {
+    let path = std::path::Path::new(&config.state_dir).join(SCHEDULED_TASKS_FILE);
+    std::fs::read_to_string(&path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn save_scheduled_tasks(config: &AgentConfig, tasks: &[Task]) -> Result<(), Box<dyn std::error::Error>> {
+    std::fs::create_dir_all(&config.state_dir)?;
+    let path = std::path::Path::new(&config.state_dir).join(SCHEDULED_TASKS_FILE);
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_string(tasks)?)?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+/// Drop a task from the schedule. Returns whether it was scheduled.
+fn unschedule_task(config: &AgentConfig, task_id: i32) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut tasks = load_scheduled_tasks(config);
+    let before = tasks.len();
+    tasks.retain(|task| task.id != task_id);
+    if tasks.len() == before {
+        return Ok(false);
+    }
+    save_scheduled_tasks(config, &tasks)?;
+    Ok(true)
+}
+
+/// Keep a task until it is due and tell the backend why it hasn't run yet.
+async fn schedule_task(task: Task, status: &str, message: String, config: &AgentConfig, client: &reqwest::Client, machine_token: &Option<String>) {
+    let task_id = task.id;
+    let saved = {
+        let _state = STATE_LOCK.lock().await;
+        let mut tasks = load_scheduled_tasks(config);
+        tasks.push(task);
+        save_scheduled_tasks(config, &tasks).map_err(|e| e.to_string())
+    };
+    match saved {
+        Ok(()) => {
+            info!("Task {} held back: {}", task_id, message);
+            acknowledge(task_id, status, message, None, config, client, machine_token).await;
+        },
+        Err(e) => {
+            error!("Failed to persist scheduled task {}: {}", task_id, e);
+            acknowledge(task_id, "failed", format!("Could not schedule task: {}", e), None, config, client, machine_token).await;
+        },
+    }
+}
+
+/// Start scheduled tasks once they are due, independent of the heartbeat.
+async fn run_scheduled_tasks(config: Arc<AgentConfig>, client_rx: tokio::sync::watch::Receiver<reqwest::Client>, machine_token: tokio::sync::watch::Receiver<Option<String>>, semaphore: Arc<tokio::sync::Semaphore>) {
+    // Re-check at least once a minute so clock changes and window openings are picked up
+    const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+    loop {
+        let (due, waiting): (Vec<Task>, Vec<Task>) = {
+            let _state = STATE_LOCK.lock().await;
+            load_scheduled_tasks(&config).into_iter()
+                .filter(|task| !is_in_flight(task.id))
+                .partition(|task| hold_reason(task, &config).is_none())
+        };
+
+        if !due.is_empty() {
+            info!("{} scheduled task(s) due", due.len());
+            let batch_ids: Vec<i32> = due.iter().map(|task| task.id).collect();
+            for task in &due {
+                set_in_flight(task.id, true);
+            }
+            let token = machine_token.borrow().clone();
+            let client = client_rx.borrow().clone();
+            let (due, cyclic) = order_by_dependencies(due);
+            if !cyclic.is_empty() {
+                let ids: Vec<i32> = cyclic.iter().map(|task| task.id).collect();
+                error!("Dependency cycle between scheduled tasks {:?}", ids);
+                for task in &cyclic {
+                    acknowledge(task.id, "failed", format!("Dependency cycle between tasks {:?}", ids), None, &config, &client, &token).await;
+                    set_in_flight(task.id, false);
+                }
+            }
+            tokio::spawn(run_task_batch(due, batch_ids, config.clone(), client.clone(), token, semaphore.clone()));
+        }
+
+        let next = waiting.iter()
+            .filter_map(|task| task.run_at)
+            .filter(|run_at| *run_at > chrono::Utc::now())
+            .min()
+            .and_then(|run_at| (run_at - chrono::Utc::now()).to_std().ok())
+            .unwrap_or(MAX_SLEEP);
+        tokio::time::sleep(next.min(MAX_SLEEP)).await;
+    }
+}
+
+const ROLLBACKS_FILE: &str = "rollbacks.json";
+/// How many tasks' rollback commands are remembered (oldest are forgotten first).
+const ROLLBACKS_LIMIT: usize = 200;
+
+/// How to undo a task, kept beyond `completed_task_ttl_secs` since a bad rollout may only be
+/// noticed much later.
+#[derive(Serialize, Deserialize, Debug)]
+struct RollbackRecord {
+    software_name: String,
+    #[serde(default)]
+    version: Option<String>,
+    rollback_url: String,
+    #[serde(default)]
+    rollback_args: Option<String>,
+    /// Unix timestamp (seconds)
+    recorded_at: u64,
+}
+
+fn load_rollbacks(config: &AgentConfig) -> HashMap<i32, RollbackRecord> {
+    let path = std::path::Path::new(&config.state_dir).join(ROLLBACKS_FILE);
+    std::fs::read_to_string(&path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+/// Remember how to undo `task`. Callers hold `STATE_LOCK`.
+fn remember_rollback(config: &AgentConfig, task: &Task) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(rollback_url) = task.rollback_url.clone() else {
+        return Ok(());
+    };
+    let mut rollbacks = load_rollbacks(config);
+    rollbacks.insert(task.id, RollbackRecord {
+        software_name: task.software_name.clone(),
+        version: task.version.clone(),
+        rollback_url,
+        rollback_args: task.rollback_args.clone(),
+        recorded_at: unix_now(),
+    });
+    while rollbacks.len() > ROLLBACKS_LIMIT {
+        let Some(oldest) = rollbacks.iter().min_by_key(|(id, record)| (record.recorded_at, **id)).map(|(id, _)| *id) else {
+            break;
+        };
+        rollbacks.remove(&oldest);
+    }
+
+    std::fs::create_dir_all(&config.state_dir)?;
+    let path = std::path::Path::new(&config.state_dir).join(ROLLBACKS_FILE);
+    let tmp_path = path.with_extension("json.tmp");
+    std::fs::write(&tmp_path, serde_json::to_string(&rollbacks)?)?;
+    std::fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+/// The task to run for a "rollback" task: its own `rollback_url` / `rollback_args` if set, otherwise
+/// what was remembered for `rollback_of`. Callers hold `STATE_LOCK`.
+fn resolve_rollback_task(task: &Task, config: &AgentConfig) -> Result<Task, String> {
+    let record = match task.rollback_of {
+        Some(prior) => load_rollbacks(config).remove(&prior),
+        None => None,
+    };
+    let rollback_url = task.rollback_url.clone()
+        .or_else(|| record.as_ref().map(|record| record.rollback_url.clone()))
+        .or_else(|| Some(task.download_url.clone()).filter(|url| !url.trim().is_empty()));
+    let Some(rollback_url) = rollback_url else {
+        return Err(match task.rollback_of {
+            Some(prior) => format!("No rollback command known for task {}", prior),
+            None => "rollback task requires rollback_of or rollback_url".to_string(),
+        });
+    };
+    let silent_args = task.rollback_args.clone()
+        .or_else(|| record.as_ref().and_then(|record| record.rollback_args.clone()))
+        .unwrap_or_else(|| task.silent_args.clone());
+
+    if let Some(record) = &record {
+        info!("Rolling back task {} ({} {})", task.rollback_of.unwrap_or_default(), record.software_name, record.version.as_deref().unwrap_or("unknown version"));
+    }
+    let software_name = match &record {
+        Some(record) if task.software_name.trim().is_empty() => record.software_name.clone(),
+        _ => task.software_name.clone(),
+    };
+    Ok(Task {
+        download_url: rollback_url,
+        silent_args,
+        software_name,
+        ..task.clone()
+    })
+}
+
+const COMPLETED_TASKS_FILE: &str = "completed_tasks.json";
+
+/// A task the agent has finished, with the result it reported.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CompletedTask {
+    /// Unix timestamp (seconds)
+    completed_at: u64,
+    ack: AckRequest,
+    /// The backend confirmed the result via `acknowledged_ids`
+    #[serde(default)]
+    confirmed: bool,
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Load the completed-task set, dropping entries older than `completed_task_ttl_secs`.
+fn load_completed_tasks(config: &AgentConfig) -> HashMap<i32, CompletedTask> {
+    let path = std::path::Path::new(&config.state_dir).join(COMPLETED_TASKS_FILE);
+    let mut completed: HashMap<i32, CompletedTask> = std::fs::read_to_string(&path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+
+    let cutoff = unix_now().saturating_sub(config.completed_task_ttl_secs);
+    completed.retain(|_, task| task.completed_at >= cutoff);
+    completed
+}
+
+fn save_completed_tasks(config: &AgentConfig, completed: &HashMap<i32, CompletedTask>) -> Result<(), Box<dyn std::error::Error>> {
+    std::fs::create_dir_all(&config.state_dir)?;
+    let path = std::path::Path::new(&config.state_dir).join(COMPLETED_TASKS_FILE);
+    let tmp_path = path.with_extension("json.tmp");
+    std::fs::write(&tmp_path, serde_json::to_string(completed)?)?;
+    std::fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+fn record_completed_task(config: &AgentConfig, ack: &AckRequest) -> Result<(), Box<dyn std::error::Error>> {
+    let mut completed = load_completed_tasks(config);
+    completed.insert(ack.task_id, CompletedTask {
+        completed_at: unix_now(),
+        ack: ack.clone(),
+        confirmed: false,
+    });
+    save_completed_tasks(config, &completed)
+}
+
+/// The newest results the backend hasn't confirmed, newest first, for the heartbeat. Results older
+/// than `recent_results_max_age_secs` are no longer reported.
+fn unconfirmed_results(config: &AgentConfig) -> Vec<TaskResultSummary> {
+    let cutoff = unix_now().saturating_sub(config.recent_results_max_age_secs);
+    let mut results: Vec<TaskResultSummary> = load_completed_tasks(config).into_values()
+        .filter(|done| !done.confirmed && done.completed_at >= cutoff)
+        .map(|done| TaskResultSummary {
+            task_id: done.ack.task_id,
+            status: done.ack.status,
+            finished_at: done.completed_at,
+        })
+        .collect();
+    results.sort_by_key(|result| std::cmp::Reverse((result.finished_at, result.task_id)));
+    results.truncate(RECENT_RESULTS_LIMIT);
+    results
+}
+
+fn confirm_results(config: &AgentConfig, task_ids: &[i32]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut completed = load_completed_tasks(config);
+    let mut changed = false;
+    for task_id in task_ids {
+        if let Some(done) = completed.get_mut(task_id).filter(|done| !done.confirmed) {
+            done.confirmed = true;
+            changed = true;
+        }
+    }
+    if changed {
+        save_completed_tasks(config, &completed)?;
+    }
+    Ok(())
+}
+
+const ACK_QUEUE_FILE: &str = "pending_acks.jsonl";
+
+fn ack_queue_path(config: &AgentConfig) -> std::path::PathBuf {
+    std::path::Path::new(&config.state_dir).join(ACK_QUEUE_FILE)
+}
+
+/// Append an acknowledgement to the on-disk queue (one JSON object per line).
+fn enqueue_ack(config: &AgentConfig, ack: &AckRequest) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+
+    std::fs::create_dir_all(&config.state_dir)?;
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(ack_queue_path(config))?;
+    writeln!(file, "{}", serde_json::to_string(ack)?)?;
+    Ok(())
+}
+
+fn load_pending_acks(config: &AgentConfig) -> Vec<AckRequest> {
+    let content = match std::fs::read_to_string(ack_queue_path(config)) {
+        Ok(content) => content,
+        Err(_) => return Vec::new(),
+    };
+
+    content.lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(ack) => Some(ack),
+            Err(e) => {
+                warn!("Dropping corrupt entry in ack queue: {}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Rewrite the ack queue atomically (write to a temp file, then rename).
+fn save_pending_acks(config: &AgentConfig, acks: &[AckRequest]) -> Result<(), Box<dyn std::error::Error>> {
+    let path = ack_queue_path(config);
+    if acks.is_empty() {
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+        }
+        return Ok(());
+    }
+
+    let mut content = String::new();
+    for ack in acks {
+        content.push_str(&serde_json::to_string(ack)?);
+        content.push('\n');
+    }
+    let tmp_path = path.with_extension("jsonl.tmp");
+    std::fs::write(&tmp_path, content)?;
+    std::fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+/// HTTP client for the backend and downloads, presenting `client_cert_path` if set.
+fn build_client(config: &AgentConfig) -> Result<reqwest::Client, String> {
+    // Enabling the decoders also makes reqwest send a matching Accept-Encoding header
+    let mut builder = reqwest::Client::builder()
+        .user_agent(config.user_agent.clone().unwrap_or_else(default_user_agent))
+        .gzip(config.enable_compression)
+        .brotli(config.enable_compression)
+        .deflate(config.enable_compression);
+    if let Some(cert_path) = &config.client_cert_path {
+        builder = builder.identity(load_client_identity(cert_path, config)?);
+    }
+    builder.redirect(redirect_policy(config)).build().map_err(|e| format!("Failed to create the HTTP client: {}", e))
+}
+
+/// Paths below a backend URL that are API endpoints rather than files served for download.
+const BACKEND_API_PATHS: [&str; 4] = ["heartbeat", "tasks/wait", "progress", "ack"];
+
+/// Every redirect hop of a download has to pass `check_download_url`, so a 302 can't lead it to
+/// http:// or outside `allowed_download_hosts`. That includes files served by the backend. Requests
+/// to the backend API (see `is_backend_api_url`) may be redirected anywhere but not downgraded to
+/// http:// (unless `allow_insecure_backend`).
+fn redirect_policy(config: &AgentConfig) -> reqwest::redirect::Policy {
+    const MAX_REDIRECTS: usize = 10;
+
+    let backend_urls = config.backend_urls.clone();
+    let allow_insecure_backend = config.allow_insecure_backend;
+    let allow_insecure_downloads = config.allow_insecure_downloads;
+    let allowed_download_hosts = config.allowed_download_hosts.clone();
+    reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() > MAX_REDIRECTS {
+            return attempt.error(format!("more than {} redirects", MAX_REDIRECTS));
+        }
+        let to_backend_api = attempt.previous().first().is_some_and(|origin| is_backend_api_url(origin, &backend_urls));
+        let verdict = if to_backend_api {
+            match attempt.url().scheme() {
+                "http" if !allow_insecure_backend => Err(format!("redirect to '{}' is not https", attempt.url())),
+                _ => Ok(()),
+            }
+        } else {
+            check_url_allowed(attempt.url().as_str(), allow_insecure_downloads, &allowed_download_hosts)
+        };
+        match verdict {
+            Ok(()) => attempt.follow(),
+            Err(reason) => attempt.error(format!("Redirect refused: {}", reason)),
+        }
+    })
+}
+
+/// Whether `url` is one of the `BACKEND_API_PATHS` of one of `backend_urls`: same scheme, host and
+/// port, and exactly that path below the backend's own.
+fn is_backend_api_url(url: &reqwest::Url, backend_urls: &[String]) -> bool {
+    backend_urls.iter().filter_map(|backend_url| reqwest::Url::parse(backend_url).ok()).any(|base| {
+        base.scheme() == url.scheme()
+            && base.host_str() == url.host_str()
+            && base.port_or_known_default() == url.port_or_known_default()
+            && url.path().strip_prefix(base.path().trim_end_matches('/'))
+                .and_then(|rest| rest.strip_prefix('/'))
+                .is_some_and(|endpoint| BACKEND_API_PATHS.contains(&endpoint))
+    })
+}
+
+/// Warn at startup and on reload when the client certificate expires within this time.
+const CLIENT_CERT_EXPIRY_WARNING: Duration = Duration::from_secs(14 * 24 * 3600);
+
+/// Load the client certificate chain and key, refusing ones that can't work: unreadable files,
+/// a wrong PKCS#12 password, a missing key, or a certificate outside its validity period.
+fn load_client_identity(cert_path: &str, config: &AgentConfig) -> Result<reqwest::Identity, String> {
+    let read = |path: &str, what: &str| std::fs::read(path).map_err(|e| format!("Cannot read {} '{}': {}", what, path, e));
+    let cert_file = read(cert_path, "client_cert_path")?;
+
+    let lower = cert_path.to_lowercase();
+    // Leaf certificate (DER) and everything reqwest needs as PEM: the chain followed by the key
+    let (leaf, pem) = if lower.ends_with(".p12") || lower.ends_with(".pfx") {
+        let password = config.client_cert_password.as_deref().unwrap_or_default();
+        let store = p12_keystore::KeyStore::from_pkcs12(&cert_file, password)
+            .map_err(|e| format!("Cannot open PKCS#12 client certificate '{}' (wrong client_cert_password?): {}", cert_path, e))?;
+        let (_, key_chain) = store.private_key_chain()
+            .ok_or_else(|| format!("PKCS#12 client certificate '{}' contains no private key", cert_path))?;
+        let leaf = key_chain.chain().first()
+            .ok_or_else(|| format!("PKCS#12 client certificate '{}' contains no certificate", cert_path))?
+            .as_der().to_vec();
+        let mut pem: String = key_chain.chain().iter().map(|cert| pem_block("CERTIFICATE", cert.as_der())).collect();
+        pem.push_str(&pem_block("PRIVATE KEY", key_chain.key()));
+        (leaf, pem.into_bytes())
+    } else {
+        if config.client_cert_password.is_some() {
+            warn!("client_cert_password only applies to PKCS#12 (.p12/.pfx) certificates. Ignoring it.");
+        }
+        let leaf = x509_parser::pem::Pem::iter_from_buffer(&cert_file)
+            .filter_map(|block| block.ok())
+            .find(|block| block.label == "CERTIFICATE")
+            .ok_or_else(|| format!("client_cert_path '{}' contains no PEM certificate", cert_path))?
+            .contents;
+        let mut pem = cert_file.clone();
+        match &config.client_key_path {
+            Some(key_path) => {
+                pem.push(b'\n');
+                pem.extend(read(key_path, "client_key_path")?);
+            },
+            None if !String::from_utf8_lossy(&cert_file).contains("PRIVATE KEY-----") => {
+                return Err(format!("client_cert_path '{}' has no private key. Set client_key_path.", cert_path));
+            },
+            None => {},
+        }
+        (leaf, pem)
+    };
+
+    let (_, cert) = x509_parser::parse_x509_certificate(&leaf)
+        .map_err(|e| format!("Cannot parse client certificate '{}': {}", cert_path, e))?;
+    let validity = cert.validity();
+    let now = chrono::Utc::now().timestamp();
+    if now > validity.not_after.timestamp() {
+        return Err(format!("Client certificate '{}' expired on {}. Install a renewed certificate.", cert_path, validity.not_after));
+    }
+    if now < validity.not_before.timestamp() {
+        return Err(format!("Client certificate '{}' is not valid before {}. Check the system clock.", cert_path, validity.not_before));
+    }
+    let remaining = validity.not_after.timestamp() - now;
+    if remaining < CLIENT_CERT_EXPIRY_WARNING.as_secs() as i64 {
+        warn!("Client certificate '{}' expires in {} day(s), on {}.", cert_path, remaining / 86400, validity.not_after);
+    }
+    info!("Using client certificate {} (expires {})", cert.subject(), validity.not_after);
+
+    reqwest::Identity::from_pem(&pem)
+        .map_err(|e| format!("Client certificate '{}' or its key is invalid: {}", cert_path, e))
+}
+
+/// Encode DER as a PEM block.
+fn pem_block(label: &str, der: &[u8]) -> String {
+    use base64::Engine;
+
+    let encoded = base64::engine::general_purpose::STANDARD.encode(der);
+    let mut block = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(64) {
+        block.push_str(&String::from_utf8_lossy(line));
+        block.push('\n');
+    }
+    block.push_str(&format!("-----END {}-----\n", label));
+    block
+}
+
+/// Modification times of the client certificate files, to reload them after a renewal.
+struct ClientCertWatch {
+    paths: Vec<std::path::PathBuf>,
+    modified: Vec<Option<std::time::SystemTime>>,
+}
+
+impl ClientCertWatch {
+    fn new(config: &AgentConfig) -> Self {
+        let paths: Vec<std::path::PathBuf> = config.client_cert_path.iter().chain(&config.client_key_path)
+            .map(std::path::PathBuf::from)
+            .collect();
+        let modified = Self::modification_times(&paths);
+        ClientCertWatch { paths, modified }
+    }
+
+    fn modification_times(paths: &[std::path::PathBuf]) -> Vec<Option<std::time::SystemTime>> {
+        paths.iter().map(|path| std::fs::metadata(path).and_then(|meta| meta.modified()).ok()).collect()
+    }
+
+    /// The new modification times if any of the files changed since the last successful reload.
+    fn changed(&self) -> Option<Vec<Option<std::time::SystemTime>>> {
+        let modified = Self::modification_times(&self.paths);
+        (modified != self.modified).then_some(modified)
+    }
+
+    /// Record the files as loaded, as of the times `changed` returned. Until then a failed reload
+    /// (e.g. the certificate written before its key) is retried.
+    fn reloaded(&mut self, modified: Vec<Option<std::time::SystemTime>>) {
+        self.modified = modified;
+    }
+}
+
+/// "ZE-SilentSync/0.1.0 (windows; x86_64)"
+fn default_user_agent() -> String {
+    format!("ZE-SilentSync/{} ({}; {})", env!("CARGO_PKG_VERSION"), std::env::consts::OS, std::env::consts::ARCH)
+}
+
+/// Random (version 4) UUID identifying one heartbeat in agent and backend logs.
+fn new_request_id() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = hex::encode(bytes);
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+/// X-Request-Id of the heartbeat each running or held task arrived with, until its final ack.
+static TASK_REQUEST_IDS: Mutex<BTreeMap<i32, String>> = Mutex::new(BTreeMap::new());
+
+fn task_request_id(task_id: i32) -> Option<String> {
+    TASK_REQUEST_IDS.lock().unwrap().get(&task_id).cloned()
+}
+
+/// Set from heartbeat responses while `request_compression` is "auto".
+static BACKEND_ACCEPTS_GZIP: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// A 415 means the backend can't read gzip bodies after all (e.g. a proxy in front of it strips
+/// support). Stop compressing until a heartbeat response offers gzip again.
+fn check_unsupported_encoding(resp: &reqwest::Response) {
+    if resp.status() == reqwest::StatusCode::UNSUPPORTED_MEDIA_TYPE && BACKEND_ACCEPTS_GZIP.swap(false, std::sync::atomic::Ordering::Relaxed) {
+        warn!("Backend rejected a request with status 415. Sending uncompressed bodies.");
+    }
+}
+
+/// Bodies smaller than this are sent uncompressed; gzip would barely shrink them.
+const COMPRESS_MIN_BYTES: usize = 1024;
+
+/// Gzip `body` if `request_compression` (and, for "auto", the backend) allows it.
+fn compress_body(body: &[u8], config: &AgentConfig) -> Option<Vec<u8>> {
+    use flate2::write::GzEncoder;
+
+    let enabled = match config.request_compression.as_str() {
+        "gzip" => true,
+        "auto" => BACKEND_ACCEPTS_GZIP.load(std::sync::atomic::Ordering::Relaxed),
+        _ => false,
+    };
+    if !enabled || body.len() < COMPRESS_MIN_BYTES {
+        return None;
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(body).ok()?;
+    encoder.finish().ok()
+}
+
+/// Attach `body` as JSON. With `sign_requests`, also send X-Timestamp (local unix seconds) and
+/// X-Signature = hex(HMAC-SHA256(key, timestamp + "\n" + method + "\n" + path + "\n" + body)), so the
+/// backend can reject forged, replayed (timestamp outside its window) or redirected requests even if
+/// `auth_token` leaks. The key is the machine's own signing key once the backend has issued one,
+/// until then the fleet-wide `signing_secret`. The signature covers the uncompressed JSON when the
+/// body is gzipped. Every request to the backend API goes through here.
+fn signed_json<T: Serialize>(req: reqwest::RequestBuilder, body: &T, config: &AgentConfig) -> reqwest::RequestBuilder {
+    let body = match serde_json::to_vec(body) {
+        Ok(body) => body,
+        // Let reqwest report the serialization error when the request is sent
+        Err(_) => return req.json(body),
+    };
+    // Method and path as the request will be sent (the body isn't set yet, so it can be cloned)
+    let target = req.try_clone().and_then(|clone| clone.build().ok())
+        .map(|built| (built.method().to_string(), built.url().path().to_string()));
+    let mut req = req.header(reqwest::header::CONTENT_TYPE, "application/json");
+    if let (true, Some(secret), Some((method, path))) = (config.sign_requests, &config.signing_secret, target) {
+        let key = MACHINE_SIGNING_KEY.lock().unwrap().clone().unwrap_or_else(|| secret.clone());
+        let timestamp = unix_now().to_string();
+        let signature = request_signature(&key, &timestamp, &method, &path, &body);
+        req = req.header("X-Timestamp", timestamp).header("X-Signature", signature);
+    }
+    match compress_body(&body, config) {
+        Some(compressed) => req.header(reqwest::header::CONTENT_ENCODING, "gzip").body(compressed),
+        None => req.body(body),
+    }
+}
+
+/// The X-Signature of `signed_json`, which the backend recomputes.
+fn request_signature(key: &str, timestamp: &str, method: &str, path: &str, body: &[u8]) -> String {
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any length");
+    for part in [timestamp, method, path] {
+        mac.update(part.as_bytes());
+        mac.update(b"\n");
+    }
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+const SIGNING_KEY_FILE: &str = "signing_key";
+
+/// The key the backend issued this machine for `sign_requests` (`HeartbeatResponse::signing_key`).
+static MACHINE_SIGNING_KEY: Mutex<Option<String>> = Mutex::new(None);
+
+/// Pick up the signing key issued before the last restart.
+fn load_signing_key(config: &AgentConfig) {
+    let path = std::path::Path::new(&config.state_dir).join(SIGNING_KEY_FILE);
+    if let Some(key) = std::fs::read_to_string(path).ok().map(|key| key.trim().to_string()).filter(|key| !key.is_empty()) {
+        *MACHINE_SIGNING_KEY.lock().unwrap() = Some(key);
+    }
+}
+
+/// Sign with `key` from now on and keep it for later runs, readable only by the agent's user.
+fn store_signing_key(config: &AgentConfig, key: String) {
+    if MACHINE_SIGNING_KEY.lock().unwrap().as_deref() == Some(key.as_str()) {
+        return;
+    }
+    let write = || -> std::io::Result<()> {
+        std::fs::create_dir_all(&config.state_dir)?;
+        let path = std::path::Path::new(&config.state_dir).join(SIGNING_KEY_FILE);
+        let tmp = path.with_extension("tmp");
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(&tmp)?.write_all(key.as_bytes())?;
+        std::fs::rename(&tmp, &path)
+    };
+    if let Err(e) = write() {
+        error!("Failed to store the signing key: {}. It is lost when the agent restarts.", e);
+    }
+    info!("Received this machine's signing key.");
+    *MACHINE_SIGNING_KEY.lock().unwrap() = Some(key);
+}
+
+async fn send_ack(ack: &AckRequest, config: &AgentConfig, client: &reqwest::Client, machine_token: &Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut req = client.post(format!("{}/ack", active_backend(config)))
+        .header("X-Agent-Token", &config.auth_token);
+    if let Some(request_id) = &ack.request_id {
+        req = req.header("X-Request-Id", request_id);
+    }
+    req = signed_json(req, ack, config);
+
+    if let Some(token) = machine_token {
+        req = req.header("X-Machine-Token", token);
+    }
+
+    let resp = req.send().await?;
+    check_unsupported_encoding(&resp);
+    if !resp.status().is_success() {
+        return Err(format!("Ack rejected with status: {}", resp.status()).into());
+    }
+    Ok(())
+}
+
+/// Try to deliver all queued acknowledgements. Entries are only removed after a 2xx response.
+async fn flush_ack_queue(config: &AgentConfig, client: &reqwest::Client, machine_token: &Option<String>) {
+    // One flush at a time, so no ack is sent twice. STATE_LOCK is only held around the file
+    // access, tasks can queue acks while this one waits for the backend.
+    static FLUSH_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+    let _flush = FLUSH_LOCK.lock().await;
+
+    let pending = {
+        let _state = STATE_LOCK.lock().await;
+        load_pending_acks(config)
+    };
+    if pending.is_empty() {
+        return;
+    }
+
+    info!("Sending {} pending acknowledgement(s)...", pending.len());
+    let mut delivered = Vec::new();
+    for ack in pending {
+        match send_ack(&ack, config, client, machine_token).await {
+            Ok(()) => {
+                info!("Acknowledged task {} ({})", ack.task_id, ack.status);
+                delivered.extend(serde_json::to_string(&ack).ok());
+            },
+            Err(e) => warn!("Failed to acknowledge task {}: {}. Will retry later.", ack.task_id, e),
+        }
+    }
+    if delivered.is_empty() {
+        return;
+    }
+
+    // Acks queued in the meantime stay
+    let _state = STATE_LOCK.lock().await;
+    let remaining: Vec<AckRequest> = load_pending_acks(config).into_iter()
+        .filter(|ack| {
+            let json = serde_json::to_string(ack).unwrap_or_default();
+            match delivered.iter().position(|sent| *sent == json) {
+                Some(index) => {
+                    delivered.swap_remove(index);
+                    false
+                },
+                None => true,
+            }
+        })
+        .collect();
+    if let Err(e) = save_pending_acks(config, &remaining) {
+        error!("Failed to update ack queue: {}", e);
+    }
+}
+
+/// Schedule a system reboot after `delay_secs` seconds.
+fn schedule_reboot(delay_secs: u64) -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(target_os = "windows")]
+    let status = Command::new("shutdown")
+        .args(["/r", "/t", &delay_secs.to_string(), "/c", "ZE-SilentSync: Restart scheduled by administrator"])
+        .status()?;
+
+    // shutdown(8) only has minute granularity
+    #[cfg(not(target_os = "windows"))]
+    let status = if delay_secs == 0 {
+        Command::new("systemctl").arg("reboot").status()
+            .or_else(|_| Command::new("shutdown").args(["-r", "now"]).status())?
+    } else {
+        Command::new("shutdown")
+            .args(["-r", &format!("+{}", delay_secs.div_ceil(60))])
+            .status()?
+    };
+
+    if !status.success() {
+        return Err(format!("shutdown exited with {:?}", status.code()).into());
+    }
+    info!("Reboot scheduled.");
+    Ok(())
+}
+
+/// Validate the task's environment variables and expand the agent-provided placeholders.
+fn build_task_env(task: &Task, staging_dir: &std::path::Path, installer: &std::path::Path) -> Result<HashMap<String, String>, String> {
+    let mut env = HashMap::new();
+    for (name, value) in &task.env {
+        if name.is_empty() || name.contains('=') || name.contains('\0') {
+            return Err(format!("Invalid environment variable name '{}'", name.replace('\0', "\\0")));
+        }
+        if value.contains('\0') {
+            return Err(format!("Environment variable '{}' contains a NUL character", name));
+        }
+        let value = value
+            .replace("%STAGING_DIR%", &staging_dir.to_string_lossy())
+            .replace("%INSTALLER_PATH%", &installer.to_string_lossy())
+            .replace("%TASK_ID%", &task.id.to_string());
+        env.insert(name.clone(), value);
+    }
+    Ok(env)
+}
+
+/// Pick the installer's current directory: the task's `working_dir` (relative to the staging
+/// directory if not absolute), else the configured `work_dir`, else the staging directory itself.
+fn resolve_working_dir(task: &Task, config: &AgentConfig, staging_dir: &std::path::Path) -> Result<std::path::PathBuf, String> {
+    let dir = match (&task.working_dir, &config.work_dir) {
+        (Some(dir), _) if !dir.trim().is_empty() => {
+            let dir = std::path::Path::new(dir.trim());
+            if dir.is_absolute() {
+                dir.to_path_buf()
+            } else {
+                staging_dir.join(dir)
+            }
+        },
+        (_, Some(dir)) => std::path::PathBuf::from(dir),
+        _ => staging_dir.to_path_buf(),
+    };
+
+    if !dir.is_dir() {
+        return Err(format!("Working directory {} does not exist", dir.display()));
+    }
+    Ok(dir)
+}
+
+/// Check `command_path` against `allowed_executables` (an empty list allows everything).
+/// Entries are compared by file name only, case-insensitively, with or without ".exe".
+fn is_executable_allowed(config: &AgentConfig, command_path: &std::path::Path) -> bool {
+    if config.allowed_executables.is_empty() {
+        return true;
+    }
+
+    let normalize = |name: &str| {
+        let name = name.trim().to_lowercase();
+        name.strip_suffix(".exe").map(|s| s.to_string()).unwrap_or(name)
+    };
+    let file_name = match command_path.file_name() {
+        Some(name) => normalize(&name.to_string_lossy()),
+        None => return false,
+    };
+
+    config.allowed_executables.iter().any(|allowed| {
+        let allowed_name = std::path::Path::new(allowed.trim())
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        normalize(&allowed_name) == file_name
+    })
+}
+
+/// Lines of an msiexec verbose log leading up to the first failed action ("Return value 3"),
+/// limited to MSI_LOG_EXCERPT_BYTES.
+fn msi_log_excerpt(log: &std::path::Path) -> Option<String> {
+    let bytes = std::fs::read(log).ok()?;
+    // Verbose logs are usually UTF-16LE with a BOM
+    let text = if bytes.starts_with(&[0xFF, 0xFE]) {
+        let units: Vec<u16> = bytes[2..].chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+        String::from_utf16_lossy(&units)
+    } else {
+        String::from_utf8_lossy(&bytes).to_string()
+    };
+
+    let lines: Vec<&str> = text.lines().collect();
+    let failed = lines.iter().position(|line| line.contains("Return value 3"))?;
+    let start = failed.saturating_sub(40);
+    let end = (failed + 5).min(lines.len());
+
+    // Keep the lines closest to the failure if the excerpt is too long
+    let mut excerpt = String::new();
+    for line in lines[start..end].iter().rev() {
+        if excerpt.len() + line.len() + 1 > MSI_LOG_EXCERPT_BYTES {
+            break;
+        }
+        excerpt.insert_str(0, &format!("{}\n", line));
+    }
+    Some(excerpt.trim_end().to_string())
+}
+
+fn is_appx_package(file_name: &str) -> bool {
+    let file_name = file_name.to_lowercase();
+    [".msix", ".appx", ".msixbundle", ".appxbundle"].iter().any(|ext| file_name.ends_with(ext))
+}
+
+/// Arguments for running `script` with Windows PowerShell, without profile or prompts.
+fn powershell_args(script: &str) -> Vec<String> {
+    ["-NoProfile", "-NonInteractive", "-ExecutionPolicy", "Bypass", "-Command", script]
+        .iter().map(|arg| arg.to_string()).collect()
+}
+
+/// Single-quoted PowerShell string literal.
+fn powershell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Wrap `body` so any cmdlet error ends the script with its message on stderr and exit code 1.
+fn powershell_try(body: &str) -> String {
+    format!("$ErrorActionPreference = 'Stop'; try {{ {} }} catch {{ [Console]::Error.WriteLine($_.Exception.Message); exit 1 }}", body)
+}
+
+/// Provision an MSIX/Appx package for all users, or install it for the current user only.
+fn appx_install_script(package: &std::path::Path, per_user: bool) -> String {
+    let package = powershell_quote(&package.to_string_lossy());
+    if per_user {
+        powershell_try(&format!("Add-AppxPackage -Path {}", package))
+    } else {
+        powershell_try(&format!("Add-AppxProvisionedPackage -Online -PackagePath {} -SkipLicense | Out-Null", package))
+    }
+}
+
+/// Remove the package identified by the package's own manifest (name + publisher, i.e. its
+/// package family), deprovisioning it too unless `per_user` is set.
+fn appx_uninstall_script(package: &std::path::Path, per_user: bool) -> String {
+    let read_identity = format!(
+        "Add-Type -AssemblyName System.IO.Compression.FileSystem; \
+         $zip = [System.IO.Compression.ZipFile]::OpenRead({}); \
+         try {{ \
+             $entry = $zip.GetEntry('AppxManifest.xml'); \
+             if (-not $entry) {{ $entry = $zip.GetEntry('AppxMetadata/AppxBundleManifest.xml') }}; \
+             if (-not $entry) {{ throw 'Package contains no manifest' }}; \
+             $reader = New-Object System.IO.StreamReader($entry.Open()); \
+             [xml]$manifest = $reader.ReadToEnd(); \
+             $reader.Close() \
+         }} finally {{ $zip.Dispose() }}; \
+         $name = $manifest.DocumentElement.Identity.Name; \
+         $publisher = $manifest.DocumentElement.Identity.Publisher",
+        powershell_quote(&package.to_string_lossy())
+    );
+    let remove = if per_user {
+        "Get-AppxPackage -Name $name | Where-Object { $_.Publisher -eq $publisher } | Remove-AppxPackage"
+    } else {
+        "Get-AppxProvisionedPackage -Online | Where-Object { $_.DisplayName -eq $name } | Remove-AppxProvisionedPackage -Online | Out-Null; \
+         Get-AppxPackage -AllUsers -Name $name | Where-Object { $_.Publisher -eq $publisher } | Remove-AppxPackage -AllUsers"
+    };
+    powershell_try(&format!("{}; {}; Write-Output ('Removed ' + $name)", read_identity, remove))
+}
+
+/// Header of OLE compound files, which .msi and .msp files are.
+const COMPOUND_FILE_MAGIC: [u8; 8] = [0xD0, 0xCF, 0x11, 0xE0, 0xA1, 0xB1, 0x1A, 0xE1];
+
+/// Make sure a downloaded file starts like what it is about to be run as, so an HTML error or
+/// captive-portal login page saved under the installer's name (served with 200) is never executed.
+/// Types without a known signature are only checked for looking like a web page.
+fn check_executable(path: &std::path::Path, file_name: &str, task_type: &str) -> Result<(), String> {
+    use std::io::Read;
+
+    let mut header = Vec::with_capacity(512);
+    File::open(path).and_then(|file| file.take(512).read_to_end(&mut header))
+        .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    let lower = file_name.to_lowercase();
+    let extension = lower.rsplit_once('.').map(|(_, ext)| ext).unwrap_or_default();
+
+    // What the platform runs directly; scripts only for installers, never as the agent binary
+    let mut native: Vec<(&str, &[u8])> = if cfg!(target_os = "windows") {
+        vec![("PE", b"MZ")]
+    } else if cfg!(target_os = "macos") {
+        vec![("Mach-O", &[0xCF, 0xFA, 0xED, 0xFE]), ("universal Mach-O", &[0xCA, 0xFE, 0xBA, 0xBE])]
+    } else {
+        vec![("ELF", b"\x7fELF")]
+    };
+    if task_type != "agent_update" && !cfg!(target_os = "windows") {
+        native.push(("script", b"#!"));
+    }
+    let expected: Vec<(&str, &[u8])> = match extension {
+        "exe" => vec![("PE", b"MZ")],
+        "msi" | "msp" => vec![("Windows Installer", &COMPOUND_FILE_MAGIC)],
+        "msix" | "appx" | "msixbundle" | "appxbundle" => vec![("MSIX/Appx (zip)", b"PK\x03\x04")],
+        "pkg" => vec![("macOS package (xar)", b"xar!")],
+        // Disk images carry their signature at the end
+        "dmg" => Vec::new(),
+        _ if task_type == "agent_update" => native,
+        // Windows runs .bat, .cmd and the like by extension; elsewhere anything else is run directly
+        _ if cfg!(target_os = "windows") => Vec::new(),
+        _ => native,
+    };
+
+    let text = String::from_utf8_lossy(&header).trim_start_matches('\u{feff}').trim_start().to_lowercase();
+    let markup = ["<!doctype", "<html", "<head", "<?xml", "<body"].iter().any(|tag| text.starts_with(tag));
+    if expected.is_empty() {
+        return if markup { Err("it looks like an HTML page".to_string()) } else { Ok(()) };
+    }
+    if expected.iter().any(|(_, magic)| header.starts_with(magic)) {
+        return Ok(());
+    }
+    let names: Vec<&str> = expected.iter().map(|(name, _)| *name).collect();
+    let found = if markup {
+        "what looks like an HTML page".to_string()
+    } else if header.is_empty() {
+        "an empty file".to_string()
+    } else {
+        format!("data starting with {}", hex::encode(&header[..header.len().min(8)]))
+    };
+    Err(format!("expected a {} file, got {}", names.join(" or "), found))
+}
+
+/// Whether `path` is a Windows Installer patch, judged by the root storage CLSID of the
+/// compound file ({000C1086-0000-0000-C000-000000000046}), for patches without an .msp extension.
+fn is_msi_patch(path: &std::path::Path) -> bool {
+    const PATCH_CLSID: [u8; 16] = [0x86, 0x10, 0x0C, 0x00, 0x00, 0x00, 0x00, 0x00, 0xC0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x46];
+
+    let read = || -> std::io::Result<bool> {
+        use std::io::{Read, Seek, SeekFrom};
+        let mut file = File::open(path)?;
+        let mut header = [0u8; 512];
+        file.read_exact(&mut header)?;
+        if header[..8] != COMPOUND_FILE_MAGIC {
+            return Ok(false);
+        }
+        let sector_size = 1u64 << u16::from_le_bytes([header[0x1E], header[0x1F]]).min(16);
+        let first_dir_sector = u32::from_le_bytes([header[0x30], header[0x31], header[0x32], header[0x33]]) as u64;
+        // The root storage is the first directory entry; its CLSID sits at offset 0x50
+        file.seek(SeekFrom::Start((first_dir_sector + 1) * sector_size + 0x50))?;
+        let mut clsid = [0u8; 16];
+        file.read_exact(&mut clsid)?;
+        Ok(clsid == PATCH_CLSID)
+    };
+    read().unwrap_or(false)
+}
+
+/// Whether `command_path` refers to Windows Installer (msiexec / msiexec.exe, any case or directory).
+fn is_msiexec(command_path: &std::path::Path) -> bool {
+    command_path.file_stem()
+        .map(|stem| stem.to_string_lossy().eq_ignore_ascii_case("msiexec"))
+        .unwrap_or(false)
+}
+
+/// Result of running an external command.
+struct ExecOutcome {
+    /// `None` if the process was killed because it exceeded its timeout or was cancelled.
+    exit_status: Option<std::process::ExitStatus>,
+    /// The process was killed because the backend cancelled the task.
+    cancelled: bool,
+    elapsed: Duration,
+    /// Last bytes written to stdout (lossy UTF-8)
+    stdout: String,
+    /// Last bytes written to stderr (lossy UTF-8)
+    stderr: String,
+}
+
+impl ExecOutcome {
+    /// stdout and stderr tails in a single block, suitable for an ack.
+    fn combined_output(&self) -> String {
+        let mut parts = Vec::new();
+        if !self.stdout.trim().is_empty() {
+            parts.push(format!("[stdout]\n{}", self.stdout.trim_end()));
+        }
+        if !self.stderr.trim().is_empty() {
+            parts.push(format!("[stderr]\n{}", self.stderr.trim_end()));
+        }
+        parts.join("\n")
+    }
+}
+
+/// Spawn `cmd` and wait for it to exit, killing the whole process tree if it runs longer than `timeout`
+/// or `cancel` becomes true. Only the last `output_limit` bytes of stdout and stderr are kept.
+async fn run_command(mut cmd: tokio::process::Command, timeout: Duration, output_limit: usize, cancel: Option<tokio::sync::watch::Receiver<bool>>) -> std::io::Result<ExecOutcome> {
+    cmd.kill_on_drop(true)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped());
+    // Own process group, so the installer and everything it spawned can be killed together
+    #[cfg(unix)]
+    cmd.process_group(0);
+
+    let started = Instant::now();
+    let mut child = cmd.spawn()?;
+
+    let stdout_reader = child.stdout.take().map(|r| tokio::spawn(read_tail(r, output_limit)));
+    let stderr_reader = child.stderr.take().map(|r| tokio::spawn(read_tail(r, output_limit)));
+
+    let cancelled = async move {
+        if let Some(mut cancel) = cancel {
+            if cancel.wait_for(|cancelled| *cancelled).await.is_ok() {
+                return;
+            }
+        }
+        std::future::pending::<()>().await
+    };
+
+    let (exit_status, cancelled) = tokio::select! {
+        result = tokio::time::timeout(timeout, child.wait()) => match result {
+            Ok(status) => (Some(status?), false),
+            Err(_) => {
+                warn!("Process exceeded timeout of {}s. Killing process tree...", timeout.as_secs());
+                kill_process_tree(&mut child).await;
+                (None, false)
+            }
+        },
+        _ = cancelled => {
+            warn!("Task was cancelled. Killing process tree...");
+            kill_process_tree(&mut child).await;
+            (None, true)
+        },
+    };
+    let elapsed = started.elapsed();
+
+    Ok(ExecOutcome {
+        exit_status,
+        cancelled,
+        elapsed,
+        stdout: collect_tail(stdout_reader).await,
+        stderr: collect_tail(stderr_reader).await,
+    })
+}
+
+/// Result of trying to run a command in the interactive user's session.
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+enum UserSessionLaunch {
+    Ran(ExecOutcome),
+    NoUserSession,
+    /// Not possible here (platform, privileges); the reason is logged.
+    Unavailable(String),
+}
+
+async fn launch_in_user_session(command_path: &std::path::Path, args: &[String], raw_args: &[String], work_dir: &std::path::Path, timeout: Duration) -> std::io::Result<UserSessionLaunch> {
+    #[cfg(target_os = "windows")]
+    {
+        let command_path = command_path.to_path_buf();
+        let args = args.to_vec();
+        let raw_args = raw_args.to_vec();
+        let work_dir = work_dir.to_path_buf();
+        // Raw handles aren't Send; keep all of them on one blocking thread
+        tokio::task::spawn_blocking(move || run_in_user_session(&command_path, &args, &raw_args, &work_dir, timeout))
+            .await
+            .map_err(std::io::Error::other)?
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    {
+        let _ = (command_path, args, raw_args, work_dir, timeout);
+        Ok(UserSessionLaunch::Unavailable("only supported on Windows".to_string()))
+    }
+}
+
+/// Run a command in the active console session with the logged-on user's token
+/// (WTSQueryUserToken + CreateProcessAsUser). Requires SeTcbPrivilege, i.e. running as LocalSystem.
+/// Output of processes in another session is not captured.
+#[cfg(target_os = "windows")]
+fn run_in_user_session(command_path: &std::path::Path, args: &[String], raw_args: &[String], work_dir: &std::path::Path, timeout: Duration) -> std::io::Result<UserSessionLaunch> {
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::process::ExitStatusExt;
+    use std::ptr::{null, null_mut};
+    use windows_sys::Win32::Foundation::{CloseHandle, ERROR_NO_TOKEN, HANDLE, LUID, WAIT_TIMEOUT};
+    use windows_sys::Win32::Security::{DuplicateTokenEx, GetTokenInformation, LookupPrivilegeValueW, SecurityImpersonation, TokenPrimary, TokenPrivileges, SE_TCB_NAME, TOKEN_ALL_ACCESS, TOKEN_PRIVILEGES, TOKEN_QUERY};
+    use windows_sys::Win32::System::Environment::{CreateEnvironmentBlock, DestroyEnvironmentBlock};
+    use windows_sys::Win32::System::RemoteDesktop::{WTSGetActiveConsoleSessionId, WTSQueryUserToken};
+    use windows_sys::Win32::System::Threading::{CreateProcessAsUserW, GetCurrentProcess, GetExitCodeProcess, OpenProcessToken, TerminateProcess, WaitForSingleObject, CREATE_UNICODE_ENVIRONMENT, PROCESS_INFORMATION, STARTUPINFOW};
+
+    /// Closes the wrapped handle when dropped.
+    struct OwnedHandle(HANDLE);
+
+    impl Drop for OwnedHandle {
+        fn drop(&mut self) {
+            if !self.0.is_null() {
+                unsafe { CloseHandle(self.0) };
+            }
+        }
+    }
+
+    fn has_tcb_privilege() -> bool {
+        unsafe {
+            let mut luid: LUID = std::mem::zeroed();
+            if LookupPrivilegeValueW(null(), SE_TCB_NAME, &mut luid) == 0 {
+                return false;
+            }
+            let mut token: HANDLE = null_mut();
+            if OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) == 0 {
+                return false;
+            }
+            let token = OwnedHandle(token);
+
+            let mut len = 0u32;
+            GetTokenInformation(token.0, TokenPrivileges, null_mut(), 0, &mut len);
+            if len == 0 {
+                return false;
+            }
+            // u32 buffer for correct alignment of TOKEN_PRIVILEGES
+            let mut buf = vec![0u32; (len as usize).div_ceil(4)];
+            if GetTokenInformation(token.0, TokenPrivileges, buf.as_mut_ptr().cast(), len, &mut len) == 0 {
+                return false;
             }
+            let privileges = &*(buf.as_ptr() as *const TOKEN_PRIVILEGES);
+            let entries = std::slice::from_raw_parts(privileges.Privileges.as_ptr(), privileges.PrivilegeCount as usize);
+            entries.iter().any(|p| p.Luid.LowPart == luid.LowPart && p.Luid.HighPart == luid.HighPart)
         }
-    } else {
-        // INSTALL
-        info!("Executing installer with args: {}", task.silent_args);
+    }
 
-        if file_name.to_lowercase().ends_with(".msi") {
-             info!("Detected MSI installer. Using msiexec.");
-             command_path = std::path::PathBuf::from("msiexec");
-             // msiexec /i <file> <args>
-             let mut new_args = vec!["/i".to_string(), file_path.to_str().unwrap().to_string()];
-             new_args.extend(args);
-             args = new_args;
+    fn wide(s: &std::ffi::OsStr) -> Vec<u16> {
+        s.encode_wide().chain(std::iter::once(0)).collect()
+    }
+
+    if !has_tcb_privilege() {
+        return Ok(UserSessionLaunch::Unavailable("agent lacks SeTcbPrivilege (not running as LocalSystem)".to_string()));
+    }
+
+    let session_id = unsafe { WTSGetActiveConsoleSessionId() };
+    if session_id == u32::MAX {
+        return Ok(UserSessionLaunch::NoUserSession);
+    }
+
+    let mut user_token: HANDLE = null_mut();
+    if unsafe { WTSQueryUserToken(session_id, &mut user_token) } == 0 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() == Some(ERROR_NO_TOKEN as i32) {
+            return Ok(UserSessionLaunch::NoUserSession);
+        }
+        return Err(err);
+    }
+    let user_token = OwnedHandle(user_token);
+
+    let mut primary_token: HANDLE = null_mut();
+    if unsafe { DuplicateTokenEx(user_token.0, TOKEN_ALL_ACCESS, null(), SecurityImpersonation, TokenPrimary, &mut primary_token) } == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let primary_token = OwnedHandle(primary_token);
+
+    let mut environment: *mut std::ffi::c_void = null_mut();
+    if unsafe { CreateEnvironmentBlock(&mut environment, primary_token.0, 0) } == 0 {
+        warn!("Failed to create the user's environment block. Using the default environment.");
+        environment = null_mut();
+    }
+
+    let mut command_line: Vec<u16> = windows_command_line(command_path, args, raw_args)
+        .encode_utf16()
+        .chain(std::iter::once(0))
+        .collect();
+    let current_dir = wide(work_dir.as_os_str());
+    let mut desktop = wide(std::ffi::OsStr::new("winsta0\\default"));
+
+    let mut startup_info: STARTUPINFOW = unsafe { std::mem::zeroed() };
+    startup_info.cb = std::mem::size_of::<STARTUPINFOW>() as u32;
+    startup_info.lpDesktop = desktop.as_mut_ptr();
+    let mut process_info: PROCESS_INFORMATION = unsafe { std::mem::zeroed() };
+
+    info!("Launching {:?} in user session {}", command_path, session_id);
+    let started = Instant::now();
+    let created = unsafe {
+        CreateProcessAsUserW(
+            primary_token.0,
+            null(),
+            command_line.as_mut_ptr(),
+            null(),
+            null(),
+            0,
+            CREATE_UNICODE_ENVIRONMENT,
+            environment,
+            current_dir.as_ptr(),
+            &startup_info,
+            &mut process_info,
+        )
+    };
+    let create_error = std::io::Error::last_os_error();
+    if !environment.is_null() {
+        unsafe { DestroyEnvironmentBlock(environment) };
+    }
+    if created == 0 {
+        return Err(create_error);
+    }
+    let process = OwnedHandle(process_info.hProcess);
+    let _thread = OwnedHandle(process_info.hThread);
+
+    let timeout_ms = timeout.as_millis().min(u32::MAX as u128 - 1) as u32;
+    if unsafe { WaitForSingleObject(process.0, timeout_ms) } == WAIT_TIMEOUT {
+        warn!("Process exceeded timeout of {}s. Killing process tree...", timeout.as_secs());
+        let _ = Command::new("taskkill")
+            .args(["/T", "/F", "/PID", &process_info.dwProcessId.to_string()])
+            .status();
+        unsafe {
+            TerminateProcess(process.0, 1);
+            WaitForSingleObject(process.0, 5000);
         }
+        return Ok(UserSessionLaunch::Ran(ExecOutcome {
+            exit_status: None,
+            cancelled: false,
+            elapsed: started.elapsed(),
+            stdout: String::new(),
+            stderr: String::new(),
+        }));
     }
 
-    let status = Command::new(&command_path)
-        .args(&args)
-        .status();
+    let mut exit_code: u32 = 0;
+    if unsafe { GetExitCodeProcess(process.0, &mut exit_code) } == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(UserSessionLaunch::Ran(ExecOutcome {
+        exit_status: Some(std::process::ExitStatus::from_raw(exit_code)),
+        cancelled: false,
+        elapsed: started.elapsed(),
+        stdout: String::new(),
+        stderr: String::new(),
+    }))
+}
+
+/// The command line as it would be executed, for logs and dry-run acks.
+fn display_command_line(program: &std::path::Path, args: &[String], raw_args: &[String]) -> String {
+    #[cfg(target_os = "windows")]
+    {
+        windows_command_line(program, args, raw_args)
+    }
 
-    let (ack_status, message) = match status {
-        Ok(exit_status) => {
-            if exit_status.success() {
-                info!("Task Complete: {} (Success)", task.software_name);
-                ("success", "Installed successfully".to_string())
+    #[cfg(not(target_os = "windows"))]
+    {
+        let quote = |arg: &str| {
+            if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || "'\"\\$`".contains(c)) {
+                arg.to_string()
             } else {
-                error!("Task Failed: {} (Exit Code: {:?})", task.software_name, exit_status.code());
-                ("failed", format!("Exit Code: {:?}", exit_status.code()))
+                format!("'{}'", arg.replace('\'', "'\\''"))
             }
-        },
-        Err(e) => {
-            if cfg!(target_os = "linux") && file_name.to_lowercase().ends_with(".exe") {
-                 warn!("Cannot run .exe on Linux. Simulating success for verification.");
-                 ("success", "Simulated success on Linux".to_string())
-            } else {
-                 return Err(Box::new(e));
+        };
+        let mut parts = vec![quote(&program.to_string_lossy())];
+        parts.extend(args.iter().map(|arg| quote(arg)));
+        parts.extend(raw_args.iter().cloned());
+        parts.join(" ")
+    }
+}
+
+/// Build a command line that CommandLineToArgvW splits back into exactly `program` + `args`,
+/// followed by `raw_args` as-is.
+#[cfg(target_os = "windows")]
+fn windows_command_line(program: &std::path::Path, args: &[String], raw_args: &[String]) -> String {
+    let mut parts = vec![quote_windows_arg(&program.to_string_lossy())];
+    parts.extend(args.iter().map(|arg| quote_windows_arg(arg)));
+    parts.extend(raw_args.iter().cloned());
+    parts.join(" ")
+}
+
+/// Quote a single argument following the CommandLineToArgvW / MSVC CRT rules:
+/// backslashes are only special directly before a double quote.
+#[cfg(target_os = "windows")]
+fn quote_windows_arg(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '\n', '\x0b', '"']) {
+        return arg.to_string();
+    }
+
+    let mut quoted = String::from('"');
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                // Escape the pending backslashes and the quote itself
+                quoted.push_str(&"\\".repeat(backslashes * 2 + 1));
+                quoted.push('"');
+                backslashes = 0;
+            },
+            _ => {
+                quoted.push_str(&"\\".repeat(backslashes));
+                quoted.push(c);
+                backslashes = 0;
+            },
+        }
+    }
+    // Backslashes before the closing quote must be doubled
+    quoted.push_str(&"\\".repeat(backslashes * 2));
+    quoted.push('"');
+    quoted
+}
+
+/// Read a stream to the end, keeping only the last `limit` bytes.
+async fn read_tail<R: tokio::io::AsyncRead + Unpin>(mut reader: R, limit: usize) -> Vec<u8> {
+    use tokio::io::AsyncReadExt;
+
+    let mut tail = Vec::new();
+    let mut buf = [0u8; 8192];
+    loop {
+        match reader.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                tail.extend_from_slice(&buf[..n]);
+                if tail.len() > limit {
+                    let excess = tail.len() - limit;
+                    tail.drain(..excess);
+                }
             }
         }
-    };
+    }
+    tail
+}
 
-    // 3. Acknowledge
-    let sys_info = get_system_info();
-    let ack = AckRequest {
-        task_id: task.id,
-        status: ack_status.to_string(),
-        message: message,
-        mac_address: sys_info.mac_address,
+async fn collect_tail(reader: Option<tokio::task::JoinHandle<Vec<u8>>>) -> String {
+    let Some(handle) = reader else {
+        return String::new();
     };
-
-    info!("Sending Acknowledgement...");
-    let mut req = client.post(format!("{}/ack", config.backend_url))
-        .header("X-Agent-Token", &config.auth_token)
-        .json(&ack);
-        
-    if let Some(token) = machine_token {
-        req = req.header("X-Machine-Token", token);
+    // A detached grandchild may keep the pipe open; don't wait for it forever.
+    match tokio::time::timeout(Duration::from_secs(5), handle).await {
+        Ok(Ok(bytes)) => String::from_utf8_lossy(&bytes).into_owned(),
+        _ => String::new(),
     }
-        
-    let _ = req.send().await;
+}
 
-    Ok(())
+/// Kill a child process including all of its descendants, then reap it.
+async fn kill_process_tree(child: &mut tokio::process::Child) {
+    if let Some(pid) = child.id() {
+        #[cfg(target_os = "windows")]
+        let result = tokio::process::Command::new("taskkill")
+            .args(["/T", "/F", "/PID", &pid.to_string()])
+            .status()
+            .await;
+
+        #[cfg(not(target_os = "windows"))]
+        let result = tokio::process::Command::new("kill")
+            .args(["-KILL", "--", &format!("-{}", pid)])
+            .status()
+            .await;
+
+        if let Err(e) = result {
+            warn!("Failed to kill process tree of PID {}: {}", pid, e);
+        }
+    }
+    // Make sure the direct child is gone and reaped
+    if let Err(e) = child.kill().await {
+        warn!("Failed to kill child process: {}", e);
+    }
 }
 
+/// Split a command line into arguments the way CommandLineToArgvW does, so `C:\Program Files\`,
+/// `"say \"hi\""` and `a""b` come out as Windows programs would see them. The result is meant for
+/// `Command::args`, which quotes and escapes each argument again on Windows (the rules
+/// `quote_windows_arg` follows), so arguments must not be pre-quoted here.
 fn split_args(input: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current_arg = String::new();
+    // An argument can be empty ("") but still exists
+    let mut started = false;
+    let mut in_quote = false;
+    let mut backslashes = 0;
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                backslashes += 1;
+                started = true;
+            },
+            '"' => {
+                // 2n backslashes before a quote are n backslashes and the quote delimits;
+                // 2n+1 are n backslashes and a literal quote
+                current_arg.push_str(&"\\".repeat(backslashes / 2));
+                if backslashes % 2 == 1 {
+                    current_arg.push('"');
+                } else if in_quote && chars.peek() == Some(&'"') {
+                    // "" inside quotes is a literal quote
+                    chars.next();
+                    current_arg.push('"');
+                } else {
+                    in_quote = !in_quote;
+                }
+                backslashes = 0;
+                started = true;
+            },
+            c if c.is_whitespace() && !in_quote => {
+                current_arg.push_str(&"\\".repeat(backslashes));
+                backslashes = 0;
+                if started {
+                    args.push(std::mem::take(&mut current_arg));
+                    started = false;
+                }
+            },
+            c => {
+                // Backslashes not followed by a quote are literal
+                current_arg.push_str(&"\\".repeat(backslashes));
+                backslashes = 0;
+                current_arg.push(c);
+                started = true;
+            },
+        }
+    }
+    current_arg.push_str(&"\\".repeat(backslashes));
+    if started {
+        args.push(current_arg);
+    }
+    args
+}
+
+/// Turn the arguments of an msiexec UninstallString into a silent removal:
+/// "/I{GUID}" (which opens the maintenance UI) becomes "/X{GUID}", /qn is added unless a UI level
+/// is already given, and only msiexec-compatible entries of `silent_args` (PROPERTY=VALUE, /norestart, ...)
+/// are appended. Nothing is added twice.
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn normalize_msi_uninstall_args(registry_args: &[String], silent_args: &str) -> Vec<String> {
+    const MSIEXEC_SWITCHES: [&str; 5] = ["/norestart", "/forcerestart", "/promptrestart", "/quiet", "/passive"];
+
+    fn push_unique(args: &mut Vec<String>, arg: String) {
+        if !args.iter().any(|existing| existing.eq_ignore_ascii_case(&arg)) {
+            args.push(arg);
+        }
+    }
+
+    let mut args = Vec::new();
+    for arg in registry_args {
+        let arg = match arg.get(..2) {
+            Some("/I" | "/i" | "-I" | "-i") => format!("/X{}", &arg[2..]),
+            _ => arg.clone(),
+        };
+        push_unique(&mut args, arg);
+    }
+
+    for arg in split_args(silent_args) {
+        let lower = arg.to_lowercase();
+        if arg.contains('=') || lower.starts_with("/q") || lower.starts_with("/l") || MSIEXEC_SWITCHES.contains(&lower.as_str()) {
+            push_unique(&mut args, arg);
+        } else {
+            warn!("Ignoring silent argument '{}' for msiexec uninstall", arg);
+        }
+    }
+
+    let has_ui_level = args.iter()
+        .map(|arg| arg.to_lowercase())
+        .any(|arg| arg.starts_with("/q") || arg == "/passive");
+    if !has_ui_level {
+        args.push("/qn".to_string());
+    }
+    args
+}
+
+/// Split a registry UninstallString into the program and its arguments. The program may be quoted or
+/// an unquoted path with spaces ("C:\Program Files\App\uninst.exe /S"); the arguments keep their
+/// quotes (see `split_raw_args`).
+#[cfg(any(target_os = "windows", test))]
+fn parse_command_string(input: &str) -> (String, Vec<String>) {
+    let input = input.trim();
+    if let Some(quoted) = input.strip_prefix('"') {
+        if let Some(end_quote) = quoted.find('"') {
+            return (quoted[..end_quote].to_string(), split_raw_args(&quoted[end_quote + 1..]));
+        }
+    }
+
+    // No quotes: split by first space, unless that cuts a path ("C:\Program"), which then runs
+    // to the first ".exe" followed by a space
+    let first_space = input.find(char::is_whitespace);
+    let exe_end = first_space.filter(|&space| input[..space].contains('\\')).and_then(|_| {
+        input.to_ascii_lowercase().match_indices(".exe")
+            .map(|(start, _)| start + 4)
+            .find(|&end| input[end..].is_empty() || input[end..].starts_with(char::is_whitespace))
+    });
+    let end = exe_end.or(first_space).unwrap_or(input.len());
+    (input[..end].to_string(), split_raw_args(&input[end..]))
+}
+
+/// Split arguments at whitespace outside quotes, keeping every token exactly as written (quotes included).
+#[cfg(any(target_os = "windows", test))]
+fn split_raw_args(input: &str) -> Vec<String> {
     let mut args = Vec::new();
     let mut current_arg = String::new();
     let mut in_quote = false;
@@ -436,37 +7154,621 @@ fn split_args(input: &str) -> Vec<String> {
     for c in input.chars() {
         if c == '"' {
             in_quote = !in_quote;
-        } else if c.is_whitespace() && !in_quote {
+        }
+        if c.is_whitespace() && !in_quote {
             if !current_arg.is_empty() {
-                args.push(current_arg.clone());
-                current_arg.clear();
+                args.push(std::mem::take(&mut current_arg));
             }
         } else {
             current_arg.push(c);
         }
     }
     if !current_arg.is_empty() {
-       args.push(current_arg);
+        args.push(current_arg);
     }
     args
 }
 
-fn parse_command_string(input: &str) -> (String, String) {
-    let input = input.trim();
-    if input.starts_with('"') {
-        if let Some(end_quote) = input[1..].find('"') {
-            let real_end = end_quote + 1;
-            let exe = &input[1..real_end];
-            let rest = if real_end + 1 < input.len() { &input[real_end+1..] } else { "" };
-            return (exe.to_string(), rest.to_string());
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fixed identity, nothing read from the OS.
+    #[derive(Debug)]
+    struct MockSystemInfo {
+        hostname: &'static str,
+        mac_address: &'static str,
+    }
+
+    impl SystemInfoProvider for MockSystemInfo {
+        fn system_info(&self) -> SystemInfo {
+            serde_json::from_value(serde_json::json!({
+                "hostname": self.hostname,
+                "mac_address": self.mac_address,
+                "mac_addresses": [self.mac_address],
+                "os_info": "Test OS x86_64",
+                "pending_reboot": false,
+            })).unwrap()
+        }
 
+        fn mac_address(&self) -> String {
+            self.mac_address.to_string()
         }
     }
-    
-    // No quotes, split by first space
-    if let Some(space) = input.find(' ') {
-        return (input[..space].to_string(), input[space+1..].to_string());
+
+    #[test]
+    fn ack_reports_the_providers_mac_address() {
+        let provider: Arc<dyn SystemInfoProvider> = Arc::new(MockSystemInfo { hostname: "test-host", mac_address: "02:00:00:00:00:01" });
+        let ack = AckRequest::new(42, "success", "Installed".to_string(), None, provider.as_ref());
+        assert_eq!(ack.mac_address, "02:00:00:00:00:01");
+
+        let body = serde_json::to_value(&ack).unwrap();
+        assert_eq!(body["mac_address"], "02:00:00:00:00:01");
+        assert_eq!(body["task_id"], 42);
+        assert_eq!(provider.system_info().hostname, "test-host");
+    }
+
+    /// A task from the fields a test cares about, the required ones filled in.
+    fn task(fields: serde_json::Value) -> Task {
+        let mut json = serde_json::json!({ "id": 7, "type": "install", "software_name": "Test", "silent_args": "" });
+        json.as_object_mut().unwrap().extend(fields.as_object().unwrap().clone());
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn task_env_expands_placeholders() {
+        let task = task(serde_json::json!({ "env": { "LICENSE_DIR": "%STAGING_DIR%", "SETUP": "%INSTALLER_PATH%", "RUN": "task-%TASK_ID%" } }));
+        let staging = std::path::Path::new("staging");
+        let env = build_task_env(&task, staging, &staging.join("setup.exe")).unwrap();
+        assert_eq!(env["LICENSE_DIR"], "staging");
+        assert_eq!(env["SETUP"], staging.join("setup.exe").to_string_lossy());
+        assert_eq!(env["RUN"], "task-7");
+    }
+
+    #[test]
+    fn task_env_rejects_invalid_names_and_values() {
+        let staging = std::path::Path::new("staging");
+        for env in [serde_json::json!({ "A=B": "x" }), serde_json::json!({ "": "x" }), serde_json::json!({ "A\u{0}": "x" }), serde_json::json!({ "A": "x\u{0}" })] {
+            let task = task(serde_json::json!({ "env": env }));
+            assert!(build_task_env(&task, staging, &staging.join("setup.exe")).is_err(), "{:?} was accepted", task.env);
+        }
+    }
+
+    #[test]
+    fn task_env_is_added_to_the_inherited_environment() {
+        let task = task(serde_json::json!({ "env": { "ZE_SILENTSYNC_TEST": "%TASK_ID%" } }));
+        let staging = std::env::temp_dir();
+        let env = build_task_env(&task, &staging, &staging.join("setup.exe")).unwrap();
+
+        let mut cmd = if cfg!(windows) {
+            let mut cmd = Command::new("cmd");
+            cmd.args(["/C", "set"]);
+            cmd
+        } else {
+            Command::new("env")
+        };
+        let output = cmd.envs(&env).output().unwrap();
+        let vars: Vec<String> = String::from_utf8_lossy(&output.stdout).lines().map(str::to_string).collect();
+        assert!(vars.iter().any(|var| var == "ZE_SILENTSYNC_TEST=7"));
+        // Windows spells it "Path"
+        assert!(vars.iter().any(|var| var.to_uppercase().starts_with("PATH=")), "inherited PATH is missing");
+    }
+
+    #[test]
+    fn msiexec_uninstall_string_becomes_silent_removal() {
+        let (exe, args) = parse_command_string("MsiExec.exe /I{AC76BA86-7AD7-1033-7B44-AC0F074E4100}");
+        assert!(is_msiexec(std::path::Path::new(&exe)));
+        let args = normalize_msi_uninstall_args(&args, "/S /norestart REBOOT=ReallySuppress");
+        // /S is not an msiexec switch and would break the command
+        assert_eq!(args, ["/X{AC76BA86-7AD7-1033-7B44-AC0F074E4100}", "/norestart", "REBOOT=ReallySuppress", "/qn"]);
+    }
+
+    #[test]
+    fn msiexec_uninstall_keeps_its_ui_level_and_adds_nothing_twice() {
+        let (_, args) = parse_command_string("MsiExec.exe /X{AC76BA86-7AD7-1033-7B44-AC0F074E4100} /qb");
+        let args = normalize_msi_uninstall_args(&args, "/qb /norestart /NORESTART");
+        assert_eq!(args, ["/X{AC76BA86-7AD7-1033-7B44-AC0F074E4100}", "/qb", "/norestart"]);
+    }
+
+    #[test]
+    fn quoted_exe_uninstall_string_is_not_msiexec() {
+        let (exe, args) = parse_command_string(r#""C:\Program Files\App\uninst.exe" /S"#);
+        assert_eq!(exe, r"C:\Program Files\App\uninst.exe");
+        assert_eq!(args, ["/S"]);
+        assert!(!is_msiexec(std::path::Path::new(&exe)));
+    }
+
+    /// A config from the settings a test cares about, the required ones filled in.
+    fn config(fields: serde_json::Value) -> AgentConfig {
+        let mut json = serde_json::json!({ "backend_url": "https://backend.example", "heartbeat_interval": 60, "auth_token": "token" });
+        json.as_object_mut().unwrap().extend(fields.as_object().unwrap().clone());
+        let mut config: AgentConfig = serde_json::from_value(json).unwrap();
+        if config.backend_urls.is_empty() {
+            config.backend_urls.push(config.backend_url.clone());
+        }
+        config
+    }
+
+    #[test]
+    fn http_backend_is_refused_without_allow_insecure_backend() {
+        assert!(check_backend_transport(&config(serde_json::json!({ "backend_url": "http://backend.example" }))).is_err());
+        assert!(check_backend_transport(&config(serde_json::json!({ "backend_urls": ["https://a.example", "http://b.example"] }))).is_err());
+        assert!(check_backend_transport(&config(serde_json::json!({ "backend_url": "http://backend.example", "allow_insecure_backend": true }))).is_ok());
+        assert!(check_backend_transport(&config(serde_json::json!({}))).is_ok());
+    }
+
+    #[test]
+    fn backend_api_urls_match_scheme_host_port_and_path() {
+        let backends = ["https://backend.example.com".to_string(), "https://api.example.org/agent/".to_string()];
+        let is_api = |url: &str| is_backend_api_url(&reqwest::Url::parse(url).unwrap(), &backends);
+        assert!(is_api("https://backend.example.com/heartbeat"));
+        assert!(is_api("https://BACKEND.example.com:443/tasks/wait"));
+        assert!(is_api("https://api.example.org/agent/ack"));
+        assert!(!is_api("https://backend.example.com/files/app.msi"));
+        assert!(!is_api("https://backend.example.com/heartbeat/app.msi"));
+        assert!(!is_api("https://backend.example.com.evil.net/heartbeat"));
+        assert!(!is_api("https://backend.example.com:8443/heartbeat"));
+        assert!(!is_api("http://backend.example.com/heartbeat"));
+        assert!(!is_api("https://api.example.org/ack"));
+        assert!(!is_api("https://api.example.org/agentx/ack"));
+    }
+
+    /// Answers every request on 127.0.0.1 with the status and Location `route` returns for its path.
+    fn serve_redirects(route: fn(&str) -> (u16, Option<String>)) -> String {
+        use std::io::{BufRead, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let mut reader = std::io::BufReader::new(stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut header = String::new();
+                while reader.read_line(&mut header).is_ok_and(|n| n > 2) {
+                    header.clear();
+                }
+                let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+                let (status, location) = route(path);
+                let location = location.map(|location| format!("Location: {}\r\n", location)).unwrap_or_default();
+                let response = format!("HTTP/1.1 {} X\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n", status, location);
+                let _ = reader.get_mut().write_all(response.as_bytes());
+            }
+        });
+        base
+    }
+
+    #[tokio::test]
+    async fn redirects_of_backend_hosted_downloads_are_checked() {
+        let base = serve_redirects(|path| match path {
+            "/files/app.msi" => (302, Some("https://evil.example.net/app.msi".to_string())),
+            "/heartbeat" => (307, Some("/heartbeat-moved".to_string())),
+            _ => (200, None),
+        });
+        let config = config(serde_json::json!({
+            "backend_url": base,
+            "allow_insecure_backend": true,
+            "allowed_download_hosts": ["downloads.example.com"],
+        }));
+        let client = build_client(&config).unwrap();
+
+        let error = client.get(format!("{}/files/app.msi", base)).send().await.unwrap_err();
+        assert!(error.is_redirect(), "{}", error);
+        assert!(format!("{:?}", error).contains("allowed_download_hosts"), "{:?}", error);
+
+        // The API itself may move
+        let response = client.post(format!("{}/heartbeat", base)).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(response.url().path().ends_with("/heartbeat-moved"));
+    }
+
+    #[test]
+    fn keywords_and_registry_matches_of_awkward_names() {
+        use Keyword::{Short, Version, Word};
+
+        // DisplayNames as found under the Uninstall keys
+        const INSTALLED: [&str; 9] = [
+            "7-Zip 23.01 (x64)",
+            "WinZip 28.0",
+            "Notepad++ (64-bit x64)",
+            "VLC media player",
+            "Windows Media Player Legacy",
+            "K-Lite Codec Pack 17.9.0 Basic",
+            "Python 3.11.4 (64-bit)",
+            "Python 3.12.1 (64-bit)",
+            "Zoom Workplace",
+        ];
+        let word = |word: &str| Word(word.to_string());
+        let cases = [
+            ("7-Zip", vec![word("7zip")], "7-Zip 23.01 (x64)"),
+            ("7zip x64", vec![word("7zip")], "7-Zip 23.01 (x64)"),
+            ("Notepad++", vec![word("notepad")], "Notepad++ (64-bit x64)"),
+            ("VLC media player", vec![word("vlc"), word("media"), word("player")], "VLC media player"),
+            ("K-Lite Codec Pack", vec![Short("k".to_string()), word("lite"), word("codec"), word("pack")], "K-Lite Codec Pack 17.9.0 Basic"),
+            ("Python 3.12", vec![word("python"), Version("3".to_string()), Version("12".to_string())], "Python 3.12.1 (64-bit)"),
+        ];
+
+        for (name, expected_keywords, expected_match) in cases {
+            let keywords = extract_keywords(name, &[]);
+            assert_eq!(keywords, expected_keywords, "keywords of '{}'", name);
+            let best = INSTALLED.iter()
+                .filter_map(|installed| keyword_score(&keywords, installed).map(|score| (score, *installed)))
+                .max_by_key(|(score, _)| *score)
+                .map(|(_, installed)| installed);
+            assert_eq!(best, Some(expected_match), "registry match of '{}'", name);
+        }
+    }
+
+    #[test]
+    fn extra_stop_words_are_ignored() {
+        let keywords = extract_keywords("Contoso VLC Media Player", &["contoso".to_string()]);
+        assert_eq!(keywords, [Keyword::Word("vlc".to_string()), Keyword::Word("media".to_string()), Keyword::Word("player".to_string())]);
+        // Two of the three words are enough, but score below a full match
+        assert_eq!(keyword_score(&keywords, "Windows Media Player Legacy"), Some(2 * KEYWORD_WEIGHT));
+        assert_eq!(keyword_score(&keywords, "Zoom Workplace"), None);
+    }
+
+    #[test]
+    fn uninstall_string_corpus() {
+        // UninstallString values from real machines: (value, program, arguments as passed on)
+        let corpus: [(&str, &str, &[&str]); 8] = [
+            ("MsiExec.exe /I{AC76BA86-7AD7-1033-7B44-AC0F074E4100}", "MsiExec.exe", &["/I{AC76BA86-7AD7-1033-7B44-AC0F074E4100}"]),
+            ("MsiExec.exe /X{23170F69-40C1-2702-2301-000001000000}", "MsiExec.exe", &["/X{23170F69-40C1-2702-2301-000001000000}"]),
+            (r#""C:\Program Files\App\unins000.exe" /SILENT /LOG="C:\temp dir\u.log""#, r"C:\Program Files\App\unins000.exe", &["/SILENT", r#"/LOG="C:\temp dir\u.log""#]),
+            (r"C:\Program Files\Mozilla Firefox\uninstall\helper.exe", r"C:\Program Files\Mozilla Firefox\uninstall\helper.exe", &[]),
+            (r"C:\Program Files (x86)\Notepad++\uninstall.exe /S", r"C:\Program Files (x86)\Notepad++\uninstall.exe", &["/S"]),
+            (
+                r#"RunDll32 C:\PROGRA~1\COMMON~1\INSTAL~1\PROFES~1\RunTime\10\01\Intel32\Ctor.dll,LaunchSetup "C:\Program Files\InstallShield Installation Information\{9B2F3A4C}\setup.exe" -l0x9 -removeonly"#,
+                "RunDll32",
+                &[r"C:\PROGRA~1\COMMON~1\INSTAL~1\PROFES~1\RunTime\10\01\Intel32\Ctor.dll,LaunchSetup", r#""C:\Program Files\InstallShield Installation Information\{9B2F3A4C}\setup.exe""#, "-l0x9", "-removeonly"],
+            ),
+            (r#"cmd /c "C:\Program Files\App\remove.bat" /quiet"#, "cmd", &["/c", r#""C:\Program Files\App\remove.bat""#, "/quiet"]),
+            (r#"  "C:\Program Files\App\uninstall.exe"  "#, r"C:\Program Files\App\uninstall.exe", &[]),
+        ];
+
+        for (value, program, args) in corpus {
+            let (parsed_program, parsed_args) = parse_command_string(value);
+            assert_eq!(parsed_program, program, "program of {}", value);
+            assert_eq!(parsed_args, args, "arguments of {}", value);
+        }
+    }
+
+    #[test]
+    fn raw_args_keep_their_quotes() {
+        assert_eq!(split_raw_args(r#" /a "b c"  d"e f"g "#), ["/a", r#""b c""#, r#"d"e f"g"#]);
+        assert_eq!(split_raw_args(""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn network_interface_serialization() {
+        let interface = NetworkInterface {
+            name: "eth0".to_string(),
+            mac_address: "00:1a:2b:3c:4d:5e".to_string(),
+            ipv4_addresses: vec!["192.168.1.20".to_string()],
+            ipv6_addresses: vec!["fe80::21a:2bff:fe3c:4d5e".to_string()],
+            is_up: true,
+            is_virtual: false,
+        };
+        let json = serde_json::json!({
+            "name": "eth0",
+            "mac_address": "00:1a:2b:3c:4d:5e",
+            "ipv4_addresses": ["192.168.1.20"],
+            "ipv6_addresses": ["fe80::21a:2bff:fe3c:4d5e"],
+            "is_up": true,
+            "is_virtual": false,
+        });
+        assert_eq!(serde_json::to_value(&interface).unwrap(), json);
+
+        // Addresses may be left out
+        let parsed: NetworkInterface = serde_json::from_value(serde_json::json!({ "name": "wg0", "mac_address": "", "is_up": false, "is_virtual": true })).unwrap();
+        assert!(parsed.ipv4_addresses.is_empty() && parsed.ipv6_addresses.is_empty());
+
+        let mut info = MockSystemInfo { hostname: "test-host", mac_address: "00:1a:2b:3c:4d:5e" }.system_info();
+        info.interfaces = vec![interface];
+        let heartbeat = serde_json::to_value(&info).unwrap();
+        assert_eq!(heartbeat["interfaces"], serde_json::json!([json]));
+        assert_eq!(heartbeat["mac_address"], "00:1a:2b:3c:4d:5e");
+    }
+
+    #[test]
+    fn split_args_follows_command_line_to_argv_rules() {
+        let cases: [(&str, &[&str]); 9] = [
+            (r#"/S /D="C:\Program Files\App""#, &["/S", r"/D=C:\Program Files\App"]),
+            (r#""C:\Program Files\App\" /quiet"#, &[r#"C:\Program Files\App" /quiet"#]),
+            (r#""C:\Program Files\App\\" /quiet"#, &[r"C:\Program Files\App\", "/quiet"]),
+            (r"C:\temp\ /quiet", &[r"C:\temp\", "/quiet"]),
+            (r#"/msg="say \"hi\"""#, &[r#"/msg=say "hi""#]),
+            (r#""a""b" c"#, &[r#"a"b"#, "c"]),
+            (r#"a\\\"b"#, &[r#"a\"b"#]),
+            (r#""" /x"#, &["", "/x"]),
+            ("  /a\t/b  ", &["/a", "/b"]),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(split_args(input), expected, "split of {}", input);
+        }
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn quoted_windows_args_split_back_unchanged() {
+        let args = [r"C:\Program Files\App\", r#"say "hi""#, "", r"trailing\\", r#"\"already\""#, "plain"];
+        let command_line = args.iter().map(|arg| quote_windows_arg(arg)).collect::<Vec<_>>().join(" ");
+        assert_eq!(split_args(&command_line), args);
+    }
+
+    #[test]
+    fn gzipped_request_bodies_round_trip() {
+        use std::io::Read;
+
+        let config = config(serde_json::json!({ "request_compression": "gzip" }));
+        let mut info = MockSystemInfo { hostname: "test-host", mac_address: "02:00:00:00:00:01" }.system_info();
+        // Large enough to be compressed
+        info.software_inventory = Some((0..100).map(|i| InstalledSoftware {
+            name: format!("Product {}", i),
+            version: Some(format!("1.{}", i)),
+            publisher: Some("Contoso".to_string()),
+            size_kb: Some(i * 10),
+        }).collect());
+
+        let request = signed_json(reqwest::Client::new().post("https://backend.example/heartbeat"), &info, &config).build().unwrap();
+        assert_eq!(request.headers()[reqwest::header::CONTENT_ENCODING], "gzip");
+        let compressed = request.body().and_then(reqwest::Body::as_bytes).unwrap();
+        assert!(compressed.len() < serde_json::to_vec(&info).unwrap().len());
+
+        let mut json = String::new();
+        flate2::read::GzDecoder::new(compressed).read_to_string(&mut json).unwrap();
+        let decoded: SystemInfo = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_value(&decoded).unwrap(), serde_json::to_value(&info).unwrap());
+        assert_eq!(decoded.software_inventory, info.software_inventory);
+
+        // Small bodies aren't worth it
+        let ack = AckRequest::new(1, "success", "Installed".to_string(), None, &MockSystemInfo { hostname: "test-host", mac_address: "02:00:00:00:00:01" });
+        let request = signed_json(reqwest::Client::new().post("https://backend.example/ack"), &ack, &config).build().unwrap();
+        assert!(request.headers().get(reqwest::header::CONTENT_ENCODING).is_none());
+    }
+
+    fn local(y: i32, m: u32, d: u32, h: u32, min: u32) -> chrono::DateTime<chrono::Local> {
+        use chrono::TimeZone;
+        chrono::Local.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    fn window(fields: serde_json::Value) -> MaintenanceWindow {
+        serde_json::from_value(fields).unwrap()
+    }
+
+    #[test]
+    fn overnight_window_covers_evening_and_morning() {
+        let window = window(serde_json::json!({ "times": ["22:00-05:00"] }));
+        // 2026-07-03 is a Friday
+        assert!(window.contains(local(2026, 7, 3, 22, 0)));
+        assert!(window.contains(local(2026, 7, 3, 23, 30)));
+        assert!(window.contains(local(2026, 7, 4, 4, 59)));
+        assert!(!window.contains(local(2026, 7, 4, 5, 0)));
+        assert!(!window.contains(local(2026, 7, 4, 12, 0)));
+        assert!(!window.contains(local(2026, 7, 3, 21, 59)));
+    }
+
+    #[test]
+    fn overnight_window_days_name_the_evening_it_opens() {
+        let window = window(serde_json::json!({ "days": ["Fri"], "times": ["22:00-05:00"] }));
+        assert!(window.contains(local(2026, 7, 3, 23, 0)), "Fri 23:00");
+        assert!(window.contains(local(2026, 7, 4, 4, 0)), "Sat 04:00");
+        assert!(!window.contains(local(2026, 7, 4, 22, 0)), "Sat 22:00");
+        assert!(!window.contains(local(2026, 7, 3, 4, 0)), "Fri 04:00 belongs to Thursday's window");
+    }
+
+    #[test]
+    fn next_opening_wraps_around_the_week() {
+        let monday_night = window(serde_json::json!({ "days": ["Mon"], "times": ["22:00-05:00"] }));
+        // Saturday to the coming Monday
+        assert_eq!(monday_night.next_opening(local(2026, 7, 4, 12, 0)), Some(local(2026, 7, 6, 22, 0)));
+        // Inside Monday's window: the next one is a week later
+        assert_eq!(monday_night.next_opening(local(2026, 7, 6, 23, 0)), Some(local(2026, 7, 13, 22, 0)));
+
+        let daily = window(serde_json::json!({ "times": ["08:00-09:00"] }));
+        assert_eq!(daily.next_opening(local(2026, 7, 5, 23, 0)), Some(local(2026, 7, 6, 8, 0)));
+        let invalid = window(serde_json::json!({ "times": ["nonsense"] }));
+        assert_eq!(invalid.next_opening(local(2026, 7, 5, 23, 0)), None);
+    }
+
+    fn ids(tasks: &[Task]) -> Vec<i32> {
+        tasks.iter().map(|task| task.id).collect()
+    }
+
+    #[test]
+    fn dependencies_run_first_otherwise_order_is_kept() {
+        let tasks = vec![
+            task(serde_json::json!({ "id": 1, "depends_on": [3] })),
+            task(serde_json::json!({ "id": 2 })),
+            task(serde_json::json!({ "id": 3, "depends_on": [4] })),
+            task(serde_json::json!({ "id": 4 })),
+            // Not in this batch, left to unmet_dependency
+            task(serde_json::json!({ "id": 5, "depends_on": [99] })),
+        ];
+        let (ordered, cyclic) = order_by_dependencies(tasks);
+        assert_eq!(ids(&ordered), [2, 4, 3, 1, 5]);
+        assert!(cyclic.is_empty());
+    }
+
+    #[test]
+    fn every_task_in_or_behind_a_cycle_is_returned_as_cyclic() {
+        let tasks = vec![
+            task(serde_json::json!({ "id": 1, "depends_on": [2] })),
+            task(serde_json::json!({ "id": 2, "depends_on": [1] })),
+            task(serde_json::json!({ "id": 3 })),
+            task(serde_json::json!({ "id": 4, "depends_on": [1] })),
+            task(serde_json::json!({ "id": 5, "depends_on": [5] })),
+        ];
+        let (ordered, cyclic) = order_by_dependencies(tasks);
+        assert_eq!(ids(&ordered), [3]);
+        assert_eq!(ids(&cyclic), [1, 2, 4, 5]);
+    }
+
+    fn completed(entries: &[(i32, &str)]) -> HashMap<i32, CompletedTask> {
+        let system_info = MockSystemInfo { hostname: "test-host", mac_address: "02:00:00:00:00:01" };
+        entries.iter().map(|&(id, status)| {
+            (id, CompletedTask { completed_at: 0, ack: AckRequest::new(id, status, String::new(), None, &system_info), confirmed: false })
+        }).collect()
+    }
+
+    #[test]
+    fn dependencies_must_have_succeeded_and_be_known() {
+        let completed = completed(&[(801, "success"), (802, "already_installed"), (803, "success_reboot_required"), (804, "failed"), (805, "cancelled")]);
+
+        let satisfied = task(serde_json::json!({ "depends_on": [801, 802, 803] }));
+        assert!(unmet_dependency(&satisfied, &completed, &[]).is_none());
+
+        for (depends_on, expected) in [(804, "(failed)"), (805, "(cancelled)"), (899, "unknown")] {
+            let blocked = task(serde_json::json!({ "depends_on": [801, depends_on] }));
+            match unmet_dependency(&blocked, &completed, &[]) {
+                Some(DependencyBlock::Failed(reason)) => assert!(reason.contains(&depends_on.to_string()) && reason.contains(expected), "{}", reason),
+                _ => panic!("dependency {} should fail the task", depends_on),
+            }
+        }
+    }
+
+    #[test]
+    fn dependencies_without_a_result_yet_are_pending() {
+        let completed = completed(&[(811, "success")]);
+        let waiting = task(serde_json::json!({ "depends_on": [811, 812] }));
+        // Later in this batch or scheduled
+        assert!(matches!(unmet_dependency(&waiting, &completed, &[812]), Some(DependencyBlock::Pending(812))));
+
+        // Still running from an earlier heartbeat
+        set_in_flight(812, true);
+        let block = unmet_dependency(&waiting, &completed, &[]);
+        set_in_flight(812, false);
+        assert!(matches!(block, Some(DependencyBlock::Pending(812))));
+
+        assert!(matches!(unmet_dependency(&waiting, &completed, &[]), Some(DependencyBlock::Failed(_))));
+        assert!(is_interim_status("waiting_on_dependency"));
+    }
+
+    #[test]
+    fn request_signature_known_answer() {
+        assert_eq!(
+            request_signature("test-secret", "1700000000", "POST", "/api/ack", br#"{"task_id":1}"#),
+            "7aca7d341f050fab06b58a2d89ae0f433f950fdf038f045fbb1c278cd2c38bc7"
+        );
+    }
+
+    #[test]
+    fn requests_are_signed_with_the_machine_key_once_issued() {
+        let config = config(serde_json::json!({ "sign_requests": true, "signing_secret": "fleet-secret", "request_compression": "gzip" }));
+        let body = serde_json::json!({ "task_id": 1, "padding": "x".repeat(4096) });
+        let signature_with = |key: &str| {
+            let request = signed_json(reqwest::Client::new().post("https://backend.example/api/ack?x=1"), &body, &config).build().unwrap();
+            // Over the uncompressed body, and the path without the query
+            assert_eq!(request.headers()[reqwest::header::CONTENT_ENCODING], "gzip");
+            let timestamp = request.headers()["X-Timestamp"].to_str().unwrap();
+            let expected = request_signature(key, timestamp, "POST", "/api/ack", &serde_json::to_vec(&body).unwrap());
+            request.headers()["X-Signature"] == expected.as_str()
+        };
+
+        assert!(signature_with("fleet-secret"));
+        *MACHINE_SIGNING_KEY.lock().unwrap() = Some("machine-key".to_string());
+        let (machine, fleet) = (signature_with("machine-key"), signature_with("fleet-secret"));
+        *MACHINE_SIGNING_KEY.lock().unwrap() = None;
+        assert!(machine && !fleet);
+    }
+
+    #[test]
+    fn versions_compare_numerically() {
+        assert_eq!(parse_version("v1.10.0"), [1, 10, 0]);
+        assert_eq!(parse_version(" 2.0.1-rc.1+build5 "), [2, 0, 1]);
+        assert_eq!(parse_version("garbage"), Vec::<u64>::new());
+        assert_eq!(parse_version("1.x.3"), [1]);
+
+        assert!(version_at_least("1.10.0", "1.9.0"));
+        assert!(!version_at_least("1.9.0", "1.10.0"));
+        assert!(version_at_least("v1.2", "1.2.0"));
+        assert!(version_at_least("1.2.0", "v1.2"));
+        // Pre-release and build suffixes are ignored
+        assert!(version_at_least("1.2.0-beta", "1.2.0"));
+        assert!(version_at_least("1.2.0", "1.2.0-beta"));
+        // Unparseable versions count as 0
+        assert!(!version_at_least("garbage", "0.0.1"));
+        assert!(version_at_least("0.0.1", "garbage"));
+    }
+
+    #[test]
+    fn agent_updates_must_be_newer() {
+        let update = |version: &str| task(serde_json::json!({ "type": "agent_update", "version": version, "sha256": "00" }));
+        let current = parse_version(env!("CARGO_PKG_VERSION"));
+        let next = format!("v{}.{}.{}", current[0], current[1], current[2] + 1);
+        assert!(check_agent_update(&update(&next)).is_ok());
+        assert!(check_agent_update(&update(&format!("{}-rc.1", &next[1..]))).is_ok());
+        assert!(check_agent_update(&update(env!("CARGO_PKG_VERSION"))).is_err());
+        assert!(check_agent_update(&update(&format!("{}.0", env!("CARGO_PKG_VERSION")))).is_err());
+        assert!(check_agent_update(&update("0.0.1")).is_err());
+        assert!(check_agent_update(&update("garbage")).is_err());
+        assert!(check_agent_update(&task(serde_json::json!({ "type": "agent_update", "version": next }))).is_err());
+    }
+
+    #[test]
+    fn download_url_rules() {
+        let hosts = ["*.example.com".to_string(), "Downloads.Contoso.com".to_string()];
+        assert!(check_url_allowed("https://cdn.example.com/app.msi", false, &hosts).is_ok());
+        assert!(check_url_allowed("https://a.b.example.com/app.msi", false, &hosts).is_ok());
+        assert!(check_url_allowed("https://example.com/app.msi", false, &hosts).is_err());
+        assert!(check_url_allowed("https://badexample.com/app.msi", false, &hosts).is_err());
+        assert!(check_url_allowed("https://example.com.evil.net/app.msi", false, &hosts).is_err());
+        // Hosts compare case-insensitively
+        assert!(check_url_allowed("https://CDN.Example.COM/app.msi", false, &hosts).is_ok());
+        assert!(check_url_allowed("https://downloads.contoso.com/app.msi", false, &hosts).is_ok());
+
+        assert!(check_url_allowed("http://cdn.example.com/app.msi", false, &hosts).is_err());
+        assert!(check_url_allowed("http://cdn.example.com/app.msi", true, &hosts).is_ok());
+        assert!(check_url_allowed("http://anywhere.net/app.msi", true, &[]).is_ok());
+        assert!(check_url_allowed("https://anywhere.net/app.msi", false, &[]).is_ok());
+
+        for url in ["file:///etc/passwd", "ftp://cdn.example.com/app.msi", "not a url"] {
+            assert!(check_url_allowed(url, true, &[]).is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn exit_codes_map_to_ack_statuses() {
+        let install = task(serde_json::json!({ "success_exit_codes": [2] }));
+        let status = |task: &Task, code: Option<i32>, msiexec: bool| exit_code_status(task, code, msiexec).map(|(status, _)| status);
+
+        assert_eq!(status(&install, Some(0), false), Some("success"));
+        assert_eq!(status(&install, Some(0), true), Some("success"));
+        assert_eq!(status(&install, Some(2), false), Some("success"));
+        assert_eq!(status(&install, Some(3010), true), Some("success_reboot_required"));
+        assert_eq!(status(&install, Some(1641), true), Some("success_reboot_required"));
+        assert_eq!(status(&install, Some(1642), true), Some("patch_not_applicable"));
+        assert_eq!(status(&install, Some(1), true), None);
+        assert_eq!(status(&install, Some(1603), true), None);
+        // Killed by a signal
+        assert_eq!(status(&install, None, false), None);
+
+        // Listed reboot codes still ask for the reboot
+        let exe = task(serde_json::json!({ "success_exit_codes": [3010] }));
+        assert_eq!(status(&exe, Some(3010), false), Some("success_reboot_required"));
+
+        let script = task(serde_json::json!({ "type": "script" }));
+        assert!(exit_code_status(&script, Some(0), false).unwrap().1.starts_with("Script completed"));
+        let rollback = task(serde_json::json!({ "type": "rollback", "rollback_of": 3 }));
+        assert_eq!(exit_code_status(&rollback, Some(0), false).unwrap().1, "Rolled back task 3 successfully");
+    }
+
+    #[test]
+    fn fatal_exit_codes_stop_retries() {
+        let task = task(serde_json::json!({ "fatal_exit_codes": [1603] }));
+        assert!(is_retryable_result(&task, "failed", Some(1)));
+        assert!(is_retryable_result(&task, "failed", None));
+        assert!(!is_retryable_result(&task, "failed", Some(1603)));
+        assert!(!is_retryable_result(&task, "success", Some(0)));
+        assert!(!is_retryable_result(&task, "cancelled", None));
+    }
+
+    #[test]
+    fn msiexec_reboot_codes_fail_other_programs() {
+        let exe = task(serde_json::json!({}));
+        for code in [3010, 1641, 1642] {
+            assert_eq!(exit_code_status(&exe, Some(code), false), None, "exit {}", code);
+            assert!(exit_code_status(&exe, Some(code), true).is_some(), "msiexec exit {}", code);
+        }
     }
-    
-    (input.to_string(), "".to_string())
 }